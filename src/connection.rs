@@ -0,0 +1,316 @@
+extern crate redis;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::OhmerError;
+
+/// Something ohmers can send commands to Redis through.
+///
+/// It is implemented for `redis::Client`, which opens a new connection for
+/// every command, for `redis::Connection`, which reuses a single connection,
+/// and for any `redis::ConnectionLike` trait object, which can be used to
+/// plug in a fake server in tests.
+///
+/// The database is the one in the URL, like `redis://127.0.0.1/3`. The
+/// `redis` crate sends `SELECT` whenever it opens a connection, so every
+/// command, pipeline and script runs in that database, even through a
+/// `Client` opening a new connection each time or a `Pool`. Scripts are
+/// cached by the server for all databases, so they are loaded only once.
+pub trait OhmerConnection {
+    /// The connection where commands are sent.
+    fn conn(&self) -> &redis::ConnectionLike;
+
+    /// How to retry operations that could not reach the server. By default
+    /// they are not retried. See `Retry`.
+    fn retry_policy(&self) -> RetryPolicy { RetryPolicy::none() }
+
+    /// Whether writes must be rejected. See `ReadOnly`.
+    fn read_only(&self) -> bool { false }
+
+    /// Whether operations must be timed and reported to `instrument`. When
+    /// it is false, the default, nothing is timed. See `Instrument`.
+    fn instrumented(&self) -> bool { false }
+
+    /// Receives the name and the duration of every timed operation.
+    fn instrument(&self, _operation: &str, _elapsed: Duration) {}
+}
+
+impl OhmerConnection for redis::Client {
+    fn conn(&self) -> &redis::ConnectionLike { self }
+}
+
+impl OhmerConnection for redis::Connection {
+    fn conn(&self) -> &redis::ConnectionLike { self }
+}
+
+impl<'a> OhmerConnection for redis::ConnectionLike + 'a {
+    fn conn(&self) -> &redis::ConnectionLike { self }
+}
+
+impl<'a, C: OhmerConnection + ?Sized> OhmerConnection for &'a C {
+    fn conn(&self) -> &redis::ConnectionLike { (**self).conn() }
+    fn retry_policy(&self) -> RetryPolicy { (**self).retry_policy() }
+    fn read_only(&self) -> bool { (**self).read_only() }
+    fn instrumented(&self) -> bool { (**self).instrumented() }
+    fn instrument(&self, operation: &str, elapsed: Duration) { (**self).instrument(operation, elapsed) }
+}
+
+/// How many times to try an operation that failed to reach the server, and
+/// how long to wait between attempts. The wait doubles after every attempt.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: usize,
+    /// Wait before the second attempt.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Tries operations up to `max_attempts` times in total, waiting
+    /// `backoff` before the second attempt and twice as long before every
+    /// following one. A `max_attempts` of 0 or 1 does not retry.
+    pub fn new(max_attempts: usize, backoff: Duration) -> Self {
+        RetryPolicy { max_attempts: max_attempts, backoff: backoff }
+    }
+
+    /// Operations are tried only once.
+    pub fn none() -> Self {
+        RetryPolicy::new(1, Duration::from_millis(0))
+    }
+
+    /// Wait after the failed attempt number `attempt`, starting at 1. It
+    /// stays at the longest `Duration` instead of overflowing.
+    fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32 << ::std::cmp::min(attempt - 1, 16);
+        self.backoff.checked_mul(factor).unwrap_or(Duration::new(u64::max_value(), 999_999_999))
+    }
+}
+
+/// A connection that retries operations failing with a connection error
+/// (see `OhmerError::is_connection_error`).
+///
+/// Only operations that are safe to repeat are retried: updating an object
+/// that was already saved, deleting, and running queries. Creating a new
+/// object is never retried, since the first attempt may have succeeded
+/// before the connection was lost. Other commands, like the ones on `List`,
+/// `Set` or `Counter`, are not retried either.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// use std::time::Duration;
+/// use ohmers::{Retry, RetryPolicy};
+///
+/// model!(
+///     Sensor {
+///         reading:u32 = 0;
+///     });
+///
+/// # fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let conn = Retry::new(client, RetryPolicy::new(3, Duration::from_millis(50)));
+/// let mut sensor = create!(Sensor { reading: 7, }, conn).unwrap();
+/// sensor.reading = 8;
+/// sensor.save(&conn).unwrap();
+/// assert_eq!(ohmers::get::<Sensor>(sensor.id, &conn).unwrap().reading, 8);
+///
+/// let nowhere = Retry::new(redis::Client::open("redis://127.0.0.1:1/").unwrap(),
+///         RetryPolicy::new(2, Duration::from_millis(1)));
+/// assert!(sensor.save(&nowhere).unwrap_err().is_connection_error());
+/// # }
+/// ```
+pub struct Retry<C: OhmerConnection> {
+    conn: C,
+    policy: RetryPolicy,
+}
+
+impl<C: OhmerConnection> Retry<C> {
+    /// Wraps a connection to retry with `policy`.
+    pub fn new(conn: C, policy: RetryPolicy) -> Self {
+        Retry { conn: conn, policy: policy }
+    }
+}
+
+impl<C: OhmerConnection> OhmerConnection for Retry<C> {
+    fn conn(&self) -> &redis::ConnectionLike { self.conn.conn() }
+    fn retry_policy(&self) -> RetryPolicy { self.policy }
+    fn read_only(&self) -> bool { self.conn.read_only() }
+    fn instrumented(&self) -> bool { self.conn.instrumented() }
+    fn instrument(&self, operation: &str, elapsed: Duration) { self.conn.instrument(operation, elapsed) }
+}
+
+/// A connection that rejects writes: saving, deleting, incrementing
+/// counters and changing lists and sets fail with `OhmerError::ReadOnly`
+/// without sending anything to the server.
+///
+/// Queries combining several sets store their intermediate results in
+/// temporary keys, so a read-only replica will still refuse them.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// use ohmers::{OhmerError, ReadOnly};
+///
+/// model!(
+///     Report {
+///         title:String = "".to_string();
+///     });
+///
+/// # fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut report = create!(Report { title: "Q1".to_string(), }, &client).unwrap();
+///
+/// let replica = ReadOnly::new(client);
+/// assert_eq!(&*ohmers::get::<Report>(report.id, &replica).unwrap().title, "Q1");
+/// report.title = "Q2".to_string();
+/// assert_eq!(report.save(&replica), Err(OhmerError::ReadOnly));
+/// # }
+/// ```
+pub struct ReadOnly<C: OhmerConnection> {
+    conn: C,
+}
+
+impl<C: OhmerConnection> ReadOnly<C> {
+    /// Wraps a connection to reject writes.
+    pub fn new(conn: C) -> Self {
+        ReadOnly { conn: conn }
+    }
+}
+
+impl<C: OhmerConnection> OhmerConnection for ReadOnly<C> {
+    fn conn(&self) -> &redis::ConnectionLike { self.conn.conn() }
+    fn retry_policy(&self) -> RetryPolicy { self.conn.retry_policy() }
+    fn read_only(&self) -> bool { true }
+    fn instrumented(&self) -> bool { self.conn.instrumented() }
+    fn instrument(&self, operation: &str, elapsed: Duration) { self.conn.instrument(operation, elapsed) }
+}
+
+/// A connection that reports how long operations take to a callback, with
+/// the name of the operation: `save`, `load`, `delete` or `query`. The
+/// time covers the calls to Redis, including retries, but not encoding
+/// or decoding objects. Connections without it do not time anything.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// use std::sync::Mutex;
+/// use std::time::Duration;
+/// use ohmers::Instrument;
+///
+/// model!(
+///     Probe {
+///         depth:u32 = 0;
+///     });
+///
+/// # fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let seen = Mutex::new(vec![]);
+/// let conn = Instrument::new(&client, |operation: &str, elapsed: Duration| {
+///     seen.lock().unwrap().push((operation.to_string(), elapsed));
+/// });
+/// let probe = create!(Probe { depth: 3, }, conn).unwrap();
+/// ohmers::get::<Probe>(probe.id, &conn).unwrap();
+///
+/// let operations = seen.lock().unwrap().iter().map(|s| s.0.clone()).collect::<Vec<_>>();
+/// assert_eq!(operations, vec!["save".to_string(), "load".to_string()]);
+/// # }
+/// ```
+pub struct Instrument<C: OhmerConnection, F: Fn(&str, Duration)> {
+    conn: C,
+    f: F,
+}
+
+impl<C: OhmerConnection, F: Fn(&str, Duration)> Instrument<C, F> {
+    /// Wraps a connection to call `f` after every timed operation.
+    pub fn new(conn: C, f: F) -> Self {
+        Instrument { conn: conn, f: f }
+    }
+}
+
+impl<C: OhmerConnection, F: Fn(&str, Duration)> OhmerConnection for Instrument<C, F> {
+    fn conn(&self) -> &redis::ConnectionLike { self.conn.conn() }
+    fn retry_policy(&self) -> RetryPolicy { self.conn.retry_policy() }
+    fn read_only(&self) -> bool { self.conn.read_only() }
+    fn instrumented(&self) -> bool { true }
+    fn instrument(&self, operation: &str, elapsed: Duration) { (self.f)(operation, elapsed) }
+}
+
+/// Fails with `OhmerError::ReadOnly` if `r` rejects writes.
+pub fn check_writable(r: &OhmerConnection) -> Result<(), OhmerError> {
+    if r.read_only() {
+        Err(OhmerError::ReadOnly)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends `PING` through `r`, following its retry policy like any other
+/// read. Meant for health checks, which then go through the same
+/// connection as the rest of the operations.
+///
+/// What happens after the server restarts depends on the connection. A
+/// `redis::Client` opens a new connection for every command, so it works
+/// again as soon as the server is back. A `redis::Connection` stays
+/// broken: when `ping` fails with `is_connection_error`, open a new one.
+/// A `Pool` checks its connections when they are taken and replaces the
+/// broken ones, unless `test_on_check_out` was turned off.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate ohmers;
+/// # extern crate redis;
+/// # fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// ohmers::ping(&client).unwrap();
+///
+/// let nowhere = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+/// assert!(ohmers::ping(&nowhere).unwrap_err().is_connection_error());
+/// # }
+/// ```
+pub fn ping(r: &OhmerConnection) -> Result<(), OhmerError> {
+    with_retry(r, || {
+        let _:String = try!(redis::cmd("PING").query(r.conn()));
+        Ok(())
+    })
+}
+
+/// Runs `f`, reporting how long it took to `r` if it is instrumented.
+pub fn timed<T, F: FnOnce() -> T>(r: &OhmerConnection, operation: &str, f: F) -> T {
+    if !r.instrumented() {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    r.instrument(operation, start.elapsed());
+    result
+}
+
+/// Runs `f` until it succeeds, fails with something other than a
+/// connection error, or the retry policy of `r` runs out of attempts.
+pub fn with_retry<T, F: FnMut() -> Result<T, OhmerError>>(r: &OhmerConnection, mut f: F) -> Result<T, OhmerError> {
+    let policy = r.retry_policy();
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref e) if e.is_connection_error() && attempt < policy.max_attempts => {
+                thread::sleep(policy.delay(attempt));
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
@@ -5,12 +5,15 @@ extern crate rustc_serialize;
 use std::ascii::AsciiExt;
 use std::collections::HashMap;
 
+use self::rustc_serialize::json::Json;
+
 
 #[derive(Debug, Clone, PartialEq)]
 enum DecoderStatus {
     Unnamed,
     Normal,
     Reference,
+    PolyReference,
 }
 
 #[derive(Debug)]
@@ -111,7 +114,19 @@ impl rustc_serialize::Decoder for Decoder {
     }
 
     fn read_char(&mut self) -> DecodeResult<char> {
-        Err(DecoderError::NotImplementedYet)
+        match self.stack.pop() {
+            Some(opt_s) => match opt_s {
+                Some(s) => {
+                    let mut chars = s.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(c),
+                        _ => Err(DecoderError::ExpectedError("Char".to_string(), s)),
+                    }
+                },
+                None => Err(DecoderError::ExpectedError("Char".to_string(), "None".to_string()))
+            },
+            None => Err(DecoderError::ExpectedError("Char".to_string(), "Not found".to_string()))
+        }
     }
 
     fn read_str(&mut self) -> DecodeResult<String> {
@@ -173,18 +188,27 @@ impl rustc_serialize::Decoder for Decoder {
                                -> DecodeResult<T> where
         F: FnOnce(&mut Decoder) -> DecodeResult<T>,
     {
-        if self.status != DecoderStatus::Reference {
+        if self.status != DecoderStatus::Reference && self.status != DecoderStatus::PolyReference {
             match self.properties.remove(name) {
                 Some(v) => self.stack.push(Some(v)),
                 None => {
-                    match self.properties.remove(&*format!("{}_id", name).to_ascii_lowercase()) {
-                        Some(id) => {
-                            self.status = DecoderStatus::Reference;
-                            self.stack.push(Some(id));
+                    let id = self.properties.remove(&*format!("{}_id", name).to_ascii_lowercase());
+                    match self.properties.remove(&*format!("{}_type", name).to_ascii_lowercase()) {
+                        // The class is read first, then the id.
+                        Some(class) => {
+                            self.status = DecoderStatus::PolyReference;
+                            self.stack.push(id);
+                            self.stack.push(Some(class));
+                        },
+                        None => match id {
+                            Some(id) => {
+                                self.status = DecoderStatus::Reference;
+                                self.stack.push(Some(id));
+                            },
+                            None => {
+                                self.stack.push(None);
+                            }
                         },
-                        None => {
-                            self.stack.push(None);
-                        }
                     }
                 }
             }
@@ -233,10 +257,33 @@ impl rustc_serialize::Decoder for Decoder {
         f(self, opt)
     }
 
-    fn read_seq<T, F>(&mut self, _f: F) -> DecodeResult<T> where
+    // A `Vec` is stored in a single field, as a JSON array. Its elements
+    // are pushed to the stack as strings, like fields, to be read in order.
+    fn read_seq<T, F>(&mut self, f: F) -> DecodeResult<T> where
         F: FnOnce(&mut Decoder, usize) -> DecodeResult<T>,
     {
-        Err(DecoderError::NotImplementedYet)
+        let s = match self.stack.pop() {
+            Some(Some(s)) => s,
+            Some(None) => return Err(DecoderError::ExpectedError("Array".to_string(), "None".to_string())),
+            None => return Err(DecoderError::ExpectedError("Array".to_string(), "Not found".to_string())),
+        };
+        let items = match Json::from_str(&*s) {
+            Ok(Json::Array(items)) => items,
+            _ => return Err(DecoderError::ExpectedError("Array".to_string(), s)),
+        };
+        let len = items.len();
+        for item in items.into_iter().rev() {
+            self.stack.push(match item {
+                Json::String(v) => Some(v),
+                Json::I64(v) => Some(format!("{}", v)),
+                Json::U64(v) => Some(format!("{}", v)),
+                Json::F64(v) => Some(format!("{}", v)),
+                Json::Boolean(v) => Some((if v { "1" } else { "0" }).to_string()),
+                Json::Null => None,
+                _ => return Err(DecoderError::ExpectedError("Array of values".to_string(), s.clone())),
+            });
+        }
+        f(self, len)
     }
 
     fn read_seq_elt<T, F>(&mut self, _idx: usize, f: F) -> DecodeResult<T> where
@@ -5,11 +5,14 @@ extern crate rustc_serialize;
 use std::ascii::AsciiExt;
 use std::collections::{HashMap, HashSet};
 
+use self::rustc_serialize::json::Json;
+
 #[derive(Debug, Clone, PartialEq)]
 enum EncoderStatus {
     Normal,
     Id,
     Reference(String),
+    PolyReference(String),
 }
 
 #[derive(Debug, Clone)]
@@ -18,10 +21,23 @@ pub struct Encoder {
     pub id_field: String,
     pub features: HashMap<String, String>,
     pub attributes: Vec<String>,
+    pub nil_attributes: Vec<String>,
     pub sets: HashSet<String>,
     pub lists: HashSet<String>,
+    pub maps: HashSet<String>,
     pub counters: HashSet<String>,
+    pub hll_counters: HashSet<String>,
+    pub references: HashSet<String>,
+    pub poly_references: HashSet<String>,
+    pub collections: HashSet<String>,
     status: EncoderStatus,
+    /// Elements of the `Vec` being encoded, written as a JSON array.
+    seq: Option<Vec<Json>>,
+    /// Whether structs unknown to ohmers are accepted. They are left out of
+    /// the attributes, only the JSON document of the object holds them.
+    pub embed: bool,
+    /// How deep the encoder is inside such a struct.
+    embedded: usize,
 }
 
 impl Encoder {
@@ -31,10 +47,19 @@ impl Encoder {
             id_field: "".to_string(),
             features: HashMap::new(),
             attributes: vec![],
+            nil_attributes: vec![],
             counters: HashSet::new(),
+            hll_counters: HashSet::new(),
             sets: HashSet::new(),
             lists: HashSet::new(),
+            maps: HashSet::new(),
+            references: HashSet::new(),
+            poly_references: HashSet::new(),
+            collections: HashSet::new(),
             status: EncoderStatus::Normal,
+            seq: None,
+            embed: false,
+            embedded: 0,
         }
     }
 }
@@ -46,6 +71,7 @@ pub enum EncoderError {
     UnknownStruct(String),
     RedisError(redis::RedisError),
     MsgPackError,
+    JsonError,
 }
 
 impl From<redis::RedisError> for EncoderError {
@@ -63,8 +89,14 @@ impl From<msgpack::encode::serialize::Error> for EncoderError {
 pub type EncodeResult<T> = Result<T, EncoderError>;
 
 macro_rules! emit_fmt {
-    ($enc: ident, $e: expr) => {{
-        $enc.attributes.push(format!("{}", $e));
+    ($enc: ident, $e: expr, $json: expr) => {{
+        if $enc.embedded > 0 {
+            return Ok(());
+        }
+        match $enc.seq {
+            Some(ref mut seq) => seq.push($json),
+            None => $enc.attributes.push(format!("{}", $e)),
+        }
         Ok(())
     }}
 }
@@ -73,53 +105,76 @@ impl rustc_serialize::Encoder for Encoder {
     type Error = EncoderError;
 
     fn emit_nil(&mut self) -> EncodeResult<()> {
-        self.attributes.pop();
+        if self.embedded > 0 {
+            return Ok(());
+        }
+        if let Some(ref mut seq) = self.seq {
+            seq.push(Json::Null);
+            return Ok(());
+        }
+        if let Some(name) = self.attributes.pop() {
+            self.nil_attributes.push(name);
+        }
         Ok(())
     }
 
     fn emit_usize(&mut self, v: usize) -> EncodeResult<()> {
+        if self.embedded > 0 {
+            return Ok(());
+        }
         let s = format!("{}", v);
         match self.status {
-            EncoderStatus::Normal => self.attributes.push(s),
+            EncoderStatus::Normal => match self.seq {
+                Some(ref mut seq) => seq.push(Json::U64(v as u64)),
+                None => self.attributes.push(s),
+            },
             EncoderStatus::Id => {
+                // The scripts always read the id from `id`, whatever the
+                // name of the field.
                 if s != "0" {
-                    self.features.insert(self.id_field.clone(), s);
+                    self.features.insert("id".to_string(), s);
                 }
-                self.attributes.pop();
             }
             EncoderStatus::Reference(ref field) => {
+                let name = format!("{}_id", &*field.to_ascii_lowercase());
                 self.attributes.pop();
-                self.attributes.push(format!("{}_id", &*field.to_ascii_lowercase()));
+                self.references.insert(name.clone());
+                self.attributes.push(name);
                 self.attributes.push(s);
             }
+            // The class was written already, the id is the last field.
+            EncoderStatus::PolyReference(_) => self.attributes.push(s),
         }
         self.status = EncoderStatus::Normal;
         Ok(())
     }
 
-    fn emit_u64(&mut self, v: u64) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_u32(&mut self, v: u32) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_u16(&mut self, v: u16) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_u8(&mut self, v: u8) -> EncodeResult<()> { emit_fmt!(self, v) }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult<()> { emit_fmt!(self, v, Json::U64(v)) }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult<()> { emit_fmt!(self, v, Json::U64(v as u64)) }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult<()> { emit_fmt!(self, v, Json::U64(v as u64)) }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult<()> { emit_fmt!(self, v, Json::U64(v as u64)) }
 
-    fn emit_isize(&mut self, v: isize) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_i64(&mut self, v: i64) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_i32(&mut self, v: i32) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_i16(&mut self, v: i16) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_i8(&mut self, v: i8) -> EncodeResult<()> { emit_fmt!(self, v) }
+    fn emit_isize(&mut self, v: isize) -> EncodeResult<()> { emit_fmt!(self, v, Json::I64(v as i64)) }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult<()> { emit_fmt!(self, v, Json::I64(v)) }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult<()> { emit_fmt!(self, v, Json::I64(v as i64)) }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult<()> { emit_fmt!(self, v, Json::I64(v as i64)) }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult<()> { emit_fmt!(self, v, Json::I64(v as i64)) }
 
-    fn emit_bool(&mut self, v: bool) -> EncodeResult<()> { emit_fmt!(self, if v { 1 } else { 0 }) }
+    fn emit_bool(&mut self, v: bool) -> EncodeResult<()> { emit_fmt!(self, if v { 1 } else { 0 }, Json::Boolean(v)) }
 
-    fn emit_f64(&mut self, v: f64) -> EncodeResult<()> { emit_fmt!(self, v) }
-    fn emit_f32(&mut self, v: f32) -> EncodeResult<()> { emit_fmt!(self, v) }
+    fn emit_f64(&mut self, v: f64) -> EncodeResult<()> { emit_fmt!(self, v, Json::F64(v)) }
+    fn emit_f32(&mut self, v: f32) -> EncodeResult<()> { emit_fmt!(self, v, Json::F64(v as f64)) }
 
-    fn emit_char(&mut self, v: char) -> EncodeResult<()> { emit_fmt!(self, v) }
+    fn emit_char(&mut self, v: char) -> EncodeResult<()> { emit_fmt!(self, v, Json::String(v.to_string())) }
 
-    fn emit_str(&mut self, v: &str) -> EncodeResult<()> { emit_fmt!(self, v) }
+    fn emit_str(&mut self, v: &str) -> EncodeResult<()> { emit_fmt!(self, v, Json::String(v.to_string())) }
 
-    fn emit_enum<F>(&mut self, _: &str, _: F) -> EncodeResult<()> where
+    fn emit_enum<F>(&mut self, _: &str, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
@@ -127,16 +182,22 @@ impl rustc_serialize::Encoder for Encoder {
         _: &str,
         _: usize,
         _: usize,
-        _: F)
+        f: F)
         -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
-    fn emit_enum_variant_arg<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_enum_variant_arg<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
@@ -144,18 +205,24 @@ impl rustc_serialize::Encoder for Encoder {
                                    _: &str,
                                    _: usize,
                                    _: usize,
-                                   _: F) -> EncodeResult<()> where
+                                   f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
     fn emit_enum_struct_variant_field<F>(&mut self,
                                          _: &str,
                                          _: usize,
-                                         _: F) -> EncodeResult<()> where
+                                         f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
@@ -163,13 +230,33 @@ impl rustc_serialize::Encoder for Encoder {
     fn emit_struct<F>(&mut self, name: &str, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
+        if self.seq.is_some() {
+            return Err(EncoderError::NotImplementedYet);
+        }
         if self.features.contains_key("name") {
             match name {
                 "Reference" => self.status = EncoderStatus::Reference(try!(self.attributes.pop().ok_or(EncoderError::MissingField))),
+                "PolyReference" => {
+                    let field = try!(self.attributes.pop().ok_or(EncoderError::MissingField));
+                    self.poly_references.insert(field.clone());
+                    self.status = EncoderStatus::PolyReference(field);
+                },
                 "Counter" => { self.counters.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
+                "HllCounter" => { self.hll_counters.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
                 "Set" => { self.sets.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
                 "List" => { self.lists.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
-                "Collection" => { try!(self.attributes.pop().ok_or(EncoderError::MissingField)); },
+                "Map" => { self.maps.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
+                "Collection" => { self.collections.insert(try!(self.attributes.pop().ok_or(EncoderError::MissingField))); },
+                _ if self.embed => {
+                    try!(self.attributes.pop().ok_or(EncoderError::MissingField));
+                    self.embedded += 1;
+                    let result = f(self);
+                    self.embedded -= 1;
+                    return result;
+                },
                 _ => return Err(EncoderError::UnknownStruct(name.to_string())),
             }
             f(self)
@@ -182,34 +269,53 @@ impl rustc_serialize::Encoder for Encoder {
     fn emit_struct_field<F>(&mut self, name: &str, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         if self.status == EncoderStatus::Normal && name == self.id_field {
             self.status = EncoderStatus::Id;
+        } else if let EncoderStatus::PolyReference(ref field) = self.status {
+            // `<field>_type` and `<field>_id`
+            let suffix = if name == "class" { "type" } else { name };
+            self.attributes.push(format!("{}_{}", field.to_ascii_lowercase(), suffix));
         } else {
             self.attributes.push(name.to_string());
         }
         f(self)
     }
 
-    fn emit_tuple<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_tuple<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
-    fn emit_tuple_arg<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_tuple_arg<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
-    fn emit_tuple_struct<F>(&mut self, _: &str, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_tuple_struct<F>(&mut self, _: &str, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
-    fn emit_tuple_struct_arg<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_tuple_struct_arg<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
@@ -229,33 +335,54 @@ impl rustc_serialize::Encoder for Encoder {
         f(self)
     }
 
-    fn emit_seq<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    // A `Vec` of primitives is written in a single field, as a JSON array.
+    fn emit_seq<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        Err(EncoderError::NotImplementedYet)
+        if self.embedded > 0 {
+            return f(self);
+        }
+        if self.seq.is_some() {
+            return Err(EncoderError::NotImplementedYet);
+        }
+        self.seq = Some(vec![]);
+        let result = f(self);
+        let seq = self.seq.take().unwrap();
+        try!(result);
+        self.attributes.push(Json::Array(seq).to_string());
+        Ok(())
     }
 
-    fn emit_seq_elt<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        Err(EncoderError::NotImplementedYet)
+        f(self)
     }
 
-    fn emit_map<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_map<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
-    fn emit_map_elt_key<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_map_elt_key<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 
-    fn emit_map_elt_val<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
+    fn emit_map_elt_val<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if self.embedded > 0 {
+            return f(self);
+        }
         Err(EncoderError::NotImplementedYet)
     }
 }
@@ -265,3 +392,44 @@ pub fn msgpack_encode<T: rustc_serialize::Encodable>(t: &T) -> Result<Vec<u8>, E
     try!(t.encode(&mut msgpack::Encoder::new(&mut buf)));
     Ok(buf)
 }
+
+/// How the arguments of the save script are serialized.
+///
+/// The Rust side and the Lua side must agree: the script decodes every
+/// argument with `cmsgpack.unpack` for `MsgPack` and with `cjson.decode`
+/// for `Json`. `adapt` rewrites a script written for MessagePack, like the
+/// bundled one, to use the decoder of the encoding.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ScriptEncoding {
+    /// MessagePack, what Ohm scripts expect. The default.
+    MsgPack,
+    /// JSON, easier to read when debugging a script, for instance with
+    /// `MONITOR`.
+    Json,
+}
+
+impl ScriptEncoding {
+    /// Serializes a script argument.
+    pub fn encode<T: rustc_serialize::Encodable>(&self, t: &T) -> Result<Vec<u8>, EncoderError> {
+        match *self {
+            ScriptEncoding::MsgPack => msgpack_encode(t),
+            ScriptEncoding::Json => rustc_serialize::json::encode(t)
+                    .map(|s| s.into_bytes())
+                    .map_err(|_| EncoderError::JsonError),
+        }
+    }
+
+    /// The Lua function decoding the arguments.
+    pub fn decoder(&self) -> &'static str {
+        match *self {
+            ScriptEncoding::MsgPack => "cmsgpack.unpack",
+            ScriptEncoding::Json => "cjson.decode",
+        }
+    }
+
+    /// Rewrites a script decoding its arguments with `cmsgpack.unpack` to
+    /// use the decoder of this encoding.
+    pub fn adapt(&self, script: &str) -> String {
+        script.replace(ScriptEncoding::MsgPack.decoder(), self.decoder())
+    }
+}
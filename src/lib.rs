@@ -9,6 +9,11 @@
 //!
 //! Have a [redis server](https://github.com/antirez/redis/) running and a
 //! [redis-rs](https://github.com/mitsuhiko/redis-rs/) connection.
+//! Every operation takes an `OhmerConnection`, which can be a
+//! `redis::Client`, a long-lived `redis::Connection`, or with the `pool`
+//! feature an `r2d2` backed `Pool`. Any of them can be wrapped in a `Retry`
+//! to retry operations when the server is briefly unreachable, or in a
+//! `ReadOnly` to reject writes.
 //!
 //! # Getting started
 //!
@@ -62,31 +67,72 @@
 //! e2.save(&client).unwrap();
 //! # }
 //! ```
+//!
+//! # Redis Cluster
+//!
+//! The redis-rs version used by ohmers has no cluster client, so a cluster
+//! cannot be used directly. The key layout is cluster friendly though: every
+//! key of a class starts with its class name (`get_class_name`), and a class
+//! with a `key_tag` has it wrapped in a hash tag, so `{shop}Order:1`,
+//! `{shop}Order:all`, `{shop}Order:id`, `{shop}Order:indices:*` and
+//! `{shop}Order:uniques:*` land in the same slot, which is what the save and
+//! delete scripts require.
+//!
+//! ```rust,ignore
+//! model!(
+//!     #[key_tag = "shop"]
+//!     Order {
+//!         total:u32 = 0;
+//!     });
+//! ```
+//!
+//! Queries combining sets (`inter`, `union`, `diff`, `sort`) use temporary
+//! `stal:*` keys that are not tagged, and will be rejected by a cluster.
 extern crate rmp as msgpack;
 extern crate redis;
 extern crate rustc_serialize;
 extern crate regex;
 extern crate stal;
+#[doc(hidden)]
+pub extern crate paste;
 
 use std::ascii::AsciiExt;
+use std::cmp::Ordering;
 use std::collections::{HashSet, HashMap};
 use std::marker::PhantomData;
 use std::mem::replace;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
-use redis::Commands;
 use redis::ToRedisArgs;
 use regex::Regex;
+use rustc_serialize::base64::{self, FromBase64, ToBase64};
+use rustc_serialize::json;
 pub use stal::Set as StalSet;
 
+mod connection;
+pub use connection::{ping, Instrument, OhmerConnection, ReadOnly, Retry, RetryPolicy};
+use connection::{check_writable, timed, with_retry};
+
+#[cfg(feature = "pool")]
+mod pool;
+#[cfg(feature = "pool")]
+pub use pool::Pool;
+
+#[cfg(feature = "chrono")]
+mod timestamp;
+#[cfg(feature = "chrono")]
+pub use timestamp::Timestamp;
+
 mod encoder;
 use encoder::*;
+pub use encoder::ScriptEncoding;
 
 mod decoder;
 use decoder::*;
 
 mod lua;
-use lua::{DELETE, SAVE};
+use lua::{CREATE_MANY, DELETE, INCR_AND_GET_FIELD, INCR_SATURATING, LIST_TO_SET, RENAME_CLASS, SAVE, SAVE_MANY, SET_FIELD, UNLOCK};
 
 /// Declares a struct.
 /// Fields may be declared as a part of uniques, indices, or regular fields.
@@ -95,6 +141,41 @@ use lua::{DELETE, SAVE};
 /// More `derive`s can be specified.
 ///
 /// A property `id: usize = 0;` is automatically added to track the object.
+/// Another name can be given after the class name, like
+/// `MyStruct(uid) { ... }`, for structs shared with code that calls it
+/// differently; see `Ohmer::id_field`.
+///
+/// `PartialEq`, `Eq` and `Hash` are implemented comparing and hashing only
+/// the `id`: two objects with the same fields but different ids are
+/// different, and an object is still equal to itself after changing its
+/// fields. This lets objects be used as `HashMap` keys and `HashSet`
+/// members. Listing `Hash` or `Eq` in `derive` has no effect.
+///
+/// An index may be followed by `=> transform`, a function from `&str` to
+/// `String` applied to the value before it is indexed and before it is
+/// looked up, like `email:String = "".to_string() => str::to_lowercase;`
+/// for case-insensitive lookups. The stored field keeps its value. See
+/// `Ohmer::index_value`.
+///
+/// Fields may use raw identifiers, like `r#type`, to be stored with a
+/// keyword as name (`type`).
+///
+/// A `char` is stored as a one character string. A `Vec` or a fixed size
+/// array of numbers, strings, chars or booleans, optional or not, is
+/// stored in a single field as a JSON array, like
+/// `tags:Vec<String> = vec![];`; loading an array of another length
+/// fails. Redis
+/// sees the array as one opaque value: indexing the field indexes the whole
+/// array, not each element. Use a `Set` to find objects by element.
+///
+/// Use `field!` to get a field name checked by the compiler, like
+/// `field!(MyStruct.my_index)`, instead of a string literal.
+///
+/// A builder named after the class, like `MyStructBuilder`, is generated
+/// too. It starts from `Default`, has a chainable setter with the name of
+/// every field and a `maybe_<field>` one taking an `Option` that only sets
+/// it if there is a value, and ends with `build` or `create` to save it.
+/// The builder is private to the module of the model, like the struct.
 ///
 /// # Examples
 /// ```
@@ -108,13 +189,37 @@ use lua::{DELETE, SAVE};
 ///         other_field:String = "".to_string();
 ///     });
 /// # fn main() {
+/// let st = MyStructBuilder::default()
+///     .my_index(3)
+///     .other_field("hello".to_string())
+///     .maybe_my_unique_identifier(None)
+///     .build();
+/// assert_eq!(st.my_index, 3);
+/// assert_eq!(&*st.other_field, "hello");
+/// assert_eq!(st.my_unique_identifier, 0);
 /// # }
 /// ```
 #[macro_export]
 macro_rules! model {
-    ($class: ident { $($key: ident:$proptype: ty = $default: expr);*; } ) => {
+    // Removes `Hash` and `Eq` from the derives, since they are always
+    // implemented on the id.
+    (@derive [$($kept: ident),*] [Hash $(, $rest: ident)*] $($item: tt)*) => {
+        model!(@derive [$($kept),*] [$($rest),*] $($item)*);
+    };
+    (@derive [$($kept: ident),*] [Eq $(, $rest: ident)*] $($item: tt)*) => {
+        model!(@derive [$($kept),*] [$($rest),*] $($item)*);
+    };
+    (@derive [$($kept: ident),*] [$next: ident $(, $rest: ident)*] $($item: tt)*) => {
+        model!(@derive [$($kept,)* $next] [$($rest),*] $($item)*);
+    };
+    (@derive [$($kept: ident),*] [] $($item: tt)*) => {
+        #[derive(RustcEncodable, RustcDecodable, Debug, $($kept,)* )]
+        $($item)*
+    };
+    ($(#[key_tag = $tag: tt])? $class: ident $(($idkey: ident))* { $($key: ident:$proptype: ty = $default: expr);*; } ) => {
         model!(
-                $class {
+                $(#[key_tag = $tag])?
+                $class $(($idkey))* {
                     uniques { };
                     indices { };
                     $($key:$proptype = $default;)*
@@ -122,23 +227,26 @@ macro_rules! model {
                 );
     };
     (
+     $(#[key_tag = $tag: tt])?
      derive { $($derive: ident),* }
-     $class: ident { $($key: ident:$proptype: ty = $default: expr);*; } ) => {
+     $class: ident $(($idkey: ident))* { $($key: ident:$proptype: ty = $default: expr);*; } ) => {
         model!(
+                $(#[key_tag = $tag])?
                 derive { $($derive),* }
-                $class {
+                $class $(($idkey))* {
                     uniques { };
                     indices { };
                     $($key:$proptype = $default;)*
                 }
                 );
     };
-    ($class: ident {
+    ($(#[key_tag = $tag: tt])? $class: ident $(($idkey: ident))* {
      uniques { $($ukey: ident:$uproptype: ty = $udefault: expr;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
         model!(
-                $class {
+                $(#[key_tag = $tag])?
+                $class $(($idkey))* {
                     uniques {
                         $(
                             $ukey: $uproptype = $udefault;
@@ -150,14 +258,16 @@ macro_rules! model {
                 );
     };
     (
+     $(#[key_tag = $tag: tt])?
      derive { $($derive: ident),* }
-     $class: ident {
+     $class: ident $(($idkey: ident))* {
      uniques { $($ukey: ident:$uproptype: ty = $udefault: expr;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
         model!(
+                $(#[key_tag = $tag])?
                 derive { $($derive),* }
-                $class {
+                $class $(($idkey))* {
                     uniques {
                         $(
                             $ukey: $uproptype = $udefault;
@@ -168,16 +278,17 @@ macro_rules! model {
                 }
                 );
     };
-    ($class: ident {
-     indices { $($ikey: ident:$iproptype: ty = $idefault: expr;)* };
+    ($(#[key_tag = $tag: tt])? $class: ident $(($idkey: ident))* {
+     indices { $($ikey: ident:$iproptype: ty = $idefault: expr $(=> $itransform: expr)?;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
         model!(
-                $class {
+                $(#[key_tag = $tag])?
+                $class $(($idkey))* {
                     uniques { };
                     indices {
                         $(
-                            $ikey: $iproptype = $idefault;
+                            $ikey: $iproptype = $idefault $(=> $itransform)?;
                         )*
                     };
                     $($key:$proptype = $default;)*
@@ -185,18 +296,20 @@ macro_rules! model {
                 );
     };
     (
+     $(#[key_tag = $tag: tt])?
      derive { $($derive: ident),* }
-     $class: ident {
-     indices { $($ikey: ident:$iproptype: ty = $idefault: expr;)* };
+     $class: ident $(($idkey: ident))* {
+     indices { $($ikey: ident:$iproptype: ty = $idefault: expr $(=> $itransform: expr)?;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
         model!(
+                $(#[key_tag = $tag])?
                 derive { $($derive),* }
-                $class {
+                $class $(($idkey))* {
                     uniques { };
                     indices {
                         $(
-                            $ikey: $iproptype = $idefault;
+                            $ikey: $iproptype = $idefault $(=> $itransform)?;
                         )*
                     };
                     $($key:$proptype = $default;)*
@@ -204,13 +317,42 @@ macro_rules! model {
                 );
     };
     (
-     $class: ident {
+     $(#[key_tag = $tag: tt])?
+     $class: ident $(($idkey: ident))* {
      uniques { $($ukey: ident:$uproptype: ty = $udefault: expr;)* };
-     indices { $($ikey: ident:$iproptype: ty = $idefault: expr;)* };
+     indices { $($ikey: ident:$iproptype: ty = $idefault: expr $(=> $itransform: expr)?;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
         model!(
+                $(#[key_tag = $tag])?
                 derive { }
+                $class $(($idkey))* {
+                    uniques {
+                        $(
+                            $ukey: $uproptype = $udefault;
+                        )*
+                    };
+                    indices {
+                        $(
+                            $ikey: $iproptype = $idefault $(=> $itransform)?;
+                        )*
+                    };
+                    $($key:$proptype = $default;)*
+                }
+                );
+    };
+    (
+     $(#[key_tag = $tag: tt])?
+     derive { $($derive: ident),* }
+     $class: ident $(($idkey: ident))* {
+     uniques { $($ukey: ident:$uproptype: ty = $udefault: expr;)* };
+     indices { $($ikey: ident:$iproptype: ty = $idefault: expr $(=> $itransform: expr)?;)* };
+     $($key: ident:$proptype: ty = $default: expr;)* }
+     ) => {
+        model!(
+                @id [$($idkey)*]
+                $(#[key_tag = $tag])?
+                derive { $($derive),* }
                 $class {
                     uniques {
                         $(
@@ -219,23 +361,29 @@ macro_rules! model {
                     };
                     indices {
                         $(
-                            $ikey: $iproptype = $idefault;
+                            $ikey: $iproptype = $idefault $(=> $itransform)?;
                         )*
                     };
                     $($key:$proptype = $default;)*
                 }
                 );
     };
+    // Names the id field `id` unless the class sets another name.
+    (@id [] $($rest: tt)*) => {
+        model!(@id [id] $($rest)*);
+    };
     (
+     @id [$idkey: ident]
+     $(#[key_tag = $tag: tt])?
      derive { $($derive: ident),* }
      $class: ident {
      uniques { $($ukey: ident:$uproptype: ty = $udefault: expr;)* };
-     indices { $($ikey: ident:$iproptype: ty = $idefault: expr;)* };
+     indices { $($ikey: ident:$iproptype: ty = $idefault: expr $(=> $itransform: expr)?;)* };
      $($key: ident:$proptype: ty = $default: expr;)* }
      ) => {
-        #[derive(RustcEncodable, RustcDecodable, Debug, $($derive,)* )]
+        model!(@derive [] [$($derive),*]
         struct $class {
-            id: usize,
+            $idkey: usize,
             $(
                 $key: $proptype,
             )*
@@ -245,12 +393,12 @@ macro_rules! model {
             $(
                 $ikey: $iproptype,
             )*
-        }
+        });
 
         impl Default for $class {
             fn default() -> Self {
                 $class {
-                    id: 0,
+                    $idkey: 0,
                     $(
                         $key: $default,
                     )*
@@ -265,28 +413,42 @@ macro_rules! model {
         }
 
         impl ::ohmers::Ohmer for $class {
-            fn id(&self) -> usize { self.id }
-            fn set_id(&mut self, id: usize) { self.id = id; }
+            fn id(&self) -> usize { self.$idkey }
+            fn set_id(&mut self, id: usize) { self.$idkey = id; }
+
+            fn id_field(&self) -> String {
+                stringify!($idkey).to_owned()
+            }
+
+            $(
+                fn key_tag(&self) -> Option<&'static str> { Some($tag) }
+            )?
 
             // These functions are implemented in the trait, but this
             // reduces the runtime overhead
             fn get_class_name(&self) -> String {
-                stringify!($class).to_owned()
+                ::ohmers::tag_class_name(::ohmers::Ohmer::key_tag(self), stringify!($class))
             }
 
             fn key_for_unique(&self, field: &str, value: &str) -> String {
-                format!("{}:uniques:{}:{}", stringify!($class), field, value)
+                format!("{}:uniques:{}:{}", ::ohmers::Ohmer::get_class_name(self), field, value)
             }
 
             fn key_for_index(&self, field: &str, value: &str) -> String {
-                format!("{}:indices:{}:{}", stringify!($class), field, value)
+                format!("{}:indices:{}:{}", ::ohmers::Ohmer::get_class_name(self), field, value)
+            }
+
+            fn counters(&self) -> ::std::collections::HashSet<String> {
+                thread_local!(static COUNTERS: ::std::collections::HashSet<String> =
+                        ::ohmers::encoded_counters(&$class::default()));
+                COUNTERS.with(|counters| counters.clone())
             }
 
             fn unique_fields<'a>(&self) -> ::std::collections::HashSet<&'a str> {
                 #![allow(unused_mut)]
                 let mut hs = ::std::collections::HashSet::new();
                 $(
-                    hs.insert(stringify!($ukey));
+                    hs.insert(::ohmers::field_name(stringify!($ukey)));
                 )*
                 hs
             }
@@ -295,15 +457,92 @@ macro_rules! model {
                 #![allow(unused_mut)]
                 let mut hs = ::std::collections::HashSet::new();
                 $(
-                    hs.insert(stringify!($ikey));
+                    hs.insert(::ohmers::field_name(stringify!($ikey)));
                 )*
                 hs
             }
+
+            fn index_value(&self, field: &str, value: &str) -> String {
+                $($(
+                    if field == ::ohmers::field_name(stringify!($ikey)) {
+                        return ($itransform)(value);
+                    }
+                )?)*
+                let _ = field;
+                value.to_string()
+            }
+        }
+
+        ::ohmers::paste::paste! {
+            #[allow(dead_code)]
+            #[derive(Default)]
+            struct [<$class Builder>] {
+                obj: $class,
+            }
+
+            #[allow(dead_code)]
+            impl [<$class Builder>] {
+                $(
+                    pub fn $key(mut self, value: $proptype) -> Self {
+                        self.obj.$key = value;
+                        self
+                    }
+
+                    pub fn [<maybe_ $key>](self, value: Option<$proptype>) -> Self {
+                        match value {
+                            Some(value) => self.$key(value),
+                            None => self,
+                        }
+                    }
+                )*
+                $(
+                    pub fn $ukey(mut self, value: $uproptype) -> Self {
+                        self.obj.$ukey = value;
+                        self
+                    }
+
+                    pub fn [<maybe_ $ukey>](self, value: Option<$uproptype>) -> Self {
+                        match value {
+                            Some(value) => self.$ukey(value),
+                            None => self,
+                        }
+                    }
+                )*
+                $(
+                    pub fn $ikey(mut self, value: $iproptype) -> Self {
+                        self.obj.$ikey = value;
+                        self
+                    }
+
+                    pub fn [<maybe_ $ikey>](self, value: Option<$iproptype>) -> Self {
+                        match value {
+                            Some(value) => self.$ikey(value),
+                            None => self,
+                        }
+                    }
+                )*
+
+                pub fn build(self) -> $class {
+                    self.obj
+                }
+
+                pub fn create(self, r: &::ohmers::OhmerConnection) -> Result<$class, ::ohmers::OhmerError> {
+                    ::ohmers::Ohmer::create(self.obj, r)
+                }
+            }
         }
 
         impl PartialEq for $class {
             fn eq(&self, other: &$class) -> bool {
-                self.id == other.id
+                self.$idkey == other.$idkey
+            }
+        }
+
+        impl Eq for $class {}
+
+        impl ::std::hash::Hash for $class {
+            fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+                ::std::hash::Hash::hash(&self.$idkey, state);
             }
         }
     }
@@ -377,6 +616,7 @@ macro_rules! create {
 
 /// Returns a `Query` with all the `$class` objects  where `$key` is `$value`.
 /// All the `$key` must be declared as `indices` in the `model!` declaration.
+/// Fields that `$class` does not have fail to compile.
 ///
 /// # Examples
 ///
@@ -420,13 +660,15 @@ macro_rules! create {
 #[macro_export]
 macro_rules! find {
     ($class: ident $({ $($key:ident: $value: expr),*, })||*, $conn: expr) => {{
+        // fails to compile if a field does not exist
+        let _ = |obj: &$class| { $($(let _ = &obj.$key;)*)* };
         ::ohmers::Query::<$class>::new(
                 ::ohmers::StalSet::Union(vec![
                     $(
                     ::ohmers::StalSet::Inter(
                         vec![
                         $(
-                            ::ohmers::Query::<$class>::key(stringify!($key), &*format!("{}", $value)),
+                            ::ohmers::Query::<$class>::key(::ohmers::field_name(stringify!($key)), &*format!("{}", $value)),
                         )*
                         ]
                     ),
@@ -436,6 +678,57 @@ macro_rules! find {
     }}
 }
 
+/// The name of `$class.$key` as stored in Redis. It fails to compile if
+/// `$class` has no such field, so it can be used instead of a string
+/// literal anywhere a field name is taken.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, field)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Query};
+/// # use redis::Commands;
+/// model!(
+///     Dog {
+///         indices { color:String = "".to_string(); };
+///         name:String = "".to_string();
+///     });
+///
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Dog:indices:color:black").unwrap();
+/// create!(Dog { name: "Rex".to_string(), color: "black".to_string(), }, &client).unwrap();
+///
+/// assert_eq!(field!(Dog.color), "color");
+/// let dogs = Query::<Dog>::find(field!(Dog.color), "black", &client);
+/// assert_eq!(dogs.try_iter().unwrap().count(), 1);
+/// # }
+/// ```
+///
+/// A misspelled field is caught by the compiler:
+///
+/// ```compile_fail
+/// # #[macro_use(model, field)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// model!(
+///     Cat {
+///         color:String = "".to_string();
+///     });
+///
+/// # fn main() {
+/// field!(Cat.colour);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! field {
+    ($class: ident.$key: ident) => {{
+        let _ = |obj: &$class| { let _ = &obj.$key; };
+        ::ohmers::field_name(stringify!($key))
+    }}
+}
+
 /// Properties declared as `Collection` can use the collection macro to get a
 /// `Query` to iterate over all of its elements.
 /// A `Collection` is an accessor to objects that have a `Reference` to the
@@ -447,7 +740,7 @@ macro_rules! collection {
     }}
 }
 
-/// Number of elements in a List or Set property.
+/// Number of elements in a List, Set or Map property.
 #[macro_export]
 macro_rules! len {
     ($obj: ident. $prop: ident, $conn: expr) => {{
@@ -455,6 +748,15 @@ macro_rules! len {
     }}
 }
 
+/// Removes every element of `$obj.$prop`, keeping `$obj`. The property
+/// must be a List or Set.
+#[macro_export]
+macro_rules! clear {
+    ($obj: ident. $prop: ident, $conn: expr) => {{
+        $obj.$prop.clear(stringify!($prop), &$obj, &$conn)
+    }}
+}
+
 /// Insert `$el` in `$obj.$prop`. The property must be a Set.
 #[macro_export]
 macro_rules! insert {
@@ -463,6 +765,31 @@ macro_rules! insert {
     }}
 }
 
+/// Moves `$el` from `$obj.$prop` to `$dst.$dst_prop` atomically. Both
+/// properties must be Sets.
+#[macro_export]
+macro_rules! move_member {
+    ($obj: ident.$prop: ident => $dst: ident.$dst_prop: ident, $el: expr, $conn: expr) => {{
+        $obj.$prop.move_to(stringify!($prop), &$obj, &$el, &$dst.$dst_prop, stringify!($dst_prop), &$dst, &$conn)
+    }}
+}
+
+/// Sets `$key` to `$value` in `$obj.$prop`. The property must be a Map.
+#[macro_export]
+macro_rules! map_set {
+    ($obj: ident.$prop: ident, $key: expr, $value: expr, $conn: expr) => {{
+        $obj.$prop.set(stringify!($prop), &$obj, $key, $value, &$conn)
+    }}
+}
+
+/// Gets the value of `$key` in `$obj.$prop`. The property must be a Map.
+#[macro_export]
+macro_rules! map_get {
+    ($obj: ident.$prop: ident, $key: expr, $conn: expr) => {{
+        $obj.$prop.get(stringify!($prop), &$obj, $key, &$conn)
+    }}
+}
+
 /// Adds `$el` at the end of `$obj.$prop`. The property must be a List.
 #[macro_export]
 macro_rules! push_back {
@@ -479,6 +806,24 @@ macro_rules! push_front {
     }}
 }
 
+/// Adds `$el` before the first occurrence of `$pivot` in `$obj.$prop`.
+/// The property must be a List.
+#[macro_export]
+macro_rules! insert_before {
+    ($obj: ident.$prop: ident, $pivot: expr, $el: expr, $conn: expr) => {{
+        $obj.$prop.insert_before(stringify!($prop), &$obj, &$pivot, &$el, &$conn)
+    }}
+}
+
+/// Adds `$el` after the first occurrence of `$pivot` in `$obj.$prop`.
+/// The property must be a List.
+#[macro_export]
+macro_rules! insert_after {
+    ($obj: ident.$prop: ident, $pivot: expr, $el: expr, $conn: expr) => {{
+        $obj.$prop.insert_after(stringify!($prop), &$obj, &$pivot, &$el, &$conn)
+    }}
+}
+
 /// Retrieves and remove an element from the end of `$obj.$prop`.
 /// The property must be a List.
 #[macro_export]
@@ -497,6 +842,15 @@ macro_rules! pop_front {
     }}
 }
 
+/// Retrieves and remove an element from the end of `$obj.$prop` and adds it
+/// at the beginning of `$dst.$dst_prop`. Both properties must be Lists.
+#[macro_export]
+macro_rules! move_to {
+    ($obj: ident.$prop: ident => $dst: ident.$dst_prop: ident, $conn: expr) => {{
+        $obj.$prop.move_to(stringify!($prop), &$obj, &$dst.$dst_prop, stringify!($dst_prop), &$dst, &$conn)
+    }}
+}
+
 /// Retrieves an element from the beginning of `$obj.$prop`.
 /// The property must be a List.
 #[macro_export]
@@ -515,6 +869,24 @@ macro_rules! last {
     }}
 }
 
+/// Retrieves the element at `$index` in `$obj.$prop`.
+/// The property must be a List.
+#[macro_export]
+macro_rules! list_get {
+    ($obj: ident.$prop: ident[$index: expr], $conn: expr) => {{
+        $obj.$prop.get(stringify!($prop), &$obj, $index, &$conn)
+    }}
+}
+
+/// Replaces the element at `$index` in `$obj.$prop` with `$el`.
+/// The property must be a List.
+#[macro_export]
+macro_rules! list_set {
+    ($obj: ident.$prop: ident[$index: expr], $el: expr, $conn: expr) => {{
+        $obj.$prop.set(stringify!($prop), &$obj, $index, &$el, &$conn)
+    }}
+}
+
 /// Creates an iterable of `$obj.$prop` between `$start` and `$end`.
 /// The property must be a List.
 ///
@@ -538,6 +910,15 @@ macro_rules! try_iter {
     }}
 }
 
+/// Creates an iterable of all elements in `$obj.$prop`.
+/// The property must be a Set. The iteration order is unspecified.
+#[macro_export]
+macro_rules! set_iter {
+    ($obj: ident.$prop: ident, $conn: expr) => {{
+        $obj.$prop.try_iter(stringify!($prop), &$obj, &$conn)
+    }}
+}
+
 /// Checks if an element is in a List or a Set.
 #[macro_export]
 macro_rules! contains {
@@ -581,10 +962,10 @@ macro_rules! remove {
 /// assert_eq!(ohmers::with::<OperativeSystem, _>("name", "OS X", &client).unwrap().unwrap().major_version, 10);
 /// # }
 /// ```
-pub fn with<T: Ohmer, S: ToRedisArgs>(property: &str, value: S, r: &redis::Client) -> Result<Option<T>, DecoderError> {
+pub fn with<T: Ohmer, S: ToRedisArgs>(property: &str, value: S, r: &OhmerConnection) -> Result<Option<T>, DecoderError> {
     let mut obj = T::default();
 
-    let opt_id:Option<usize> = try!(r.hget(format!("{}:uniques:{}", obj.get_class_name(), property), value));
+    let opt_id:Option<usize> = try!(redis::cmd("HGET").arg(format!("{}:uniques:{}", obj.get_class_name(), stored_name(&obj, property))).arg(value).query(r.conn()));
 
     let id = match opt_id {
         Some(id) => id,
@@ -596,6 +977,10 @@ pub fn with<T: Ohmer, S: ToRedisArgs>(property: &str, value: S, r: &redis::Clien
 
 /// Gets an element by id.
 ///
+/// An id that was never saved, or whose object was deleted, is not an
+/// error: the result is the default object with that id. Use `find` to
+/// tell missing objects apart.
+///
 /// # Examples
 ///
 /// ```rust
@@ -614,88 +999,1647 @@ pub fn with<T: Ohmer, S: ToRedisArgs>(property: &str, value: S, r: &redis::Clien
 /// assert_eq!(&*ohmers::get::<Server>(server.id, &client).unwrap().name, "My Server");
 /// # }
 /// ```
-pub fn get<T: Ohmer>(id: usize, r: &redis::Client) -> Result<T, DecoderError> {
+pub fn get<T: Ohmer>(id: usize, r: &OhmerConnection) -> Result<T, DecoderError> {
     let mut obj = T::default();
     try!(obj.load(id, r));
     Ok(obj)
 }
 
-/// Gets a query for all elements.
+/// Gets an element by id, or `None` if there is no object stored with that
+/// id. Unlike `get`, a missing object is not mistaken for one that has
+/// default values.
 ///
 /// # Examples
 ///
 /// ```rust
-/// # #[macro_use(model, create, new)] extern crate ohmers;
+/// # #[macro_use(model, create)] extern crate ohmers;
 /// # extern crate rustc_serialize;
 /// # extern crate redis;
 /// # use ohmers::Ohmer;
-/// # use redis::Commands;
 /// model!(
-///     URL {
-///         domain:String = "".to_string();
-///         path:String = "/".to_string();
+///     Kiosk {
+///         location:String = "".to_string();
 ///     });
 /// # fn main() {
 /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-/// # let _:bool = client.del("URL:all").unwrap();
-/// # let _:bool = client.del("URL:id").unwrap();
-/// create!(URL { domain: "example.com".to_owned(), }, &client).unwrap();
-/// create!(URL { domain: "example.org".to_owned(), path: "/ping".to_owned(), }, &client).unwrap();
-/// assert_eq!(ohmers::all_query::<URL>(&client).unwrap().sort("path", None, true, true).unwrap().collect::<Vec<_>>(),
-///     vec![
-///         new!(URL { id: 1, domain: "example.com".to_owned(), }),
-///         new!(URL { id: 2, domain: "example.org".to_owned(), path: "/ping".to_owned(), }),
-///     ]);
+/// let kiosk = create!(Kiosk { location: "Lobby".to_owned(), }, &client).unwrap();
+/// assert_eq!(&*ohmers::find::<Kiosk>(kiosk.id, &client).unwrap().unwrap().location, "Lobby");
+///
+/// let id = kiosk.id;
+/// kiosk.delete(&client).unwrap();
+/// assert_eq!(ohmers::find::<Kiosk>(id, &client).unwrap(), None);
+/// assert_eq!(ohmers::get::<Kiosk>(id, &client).unwrap().id, id);
 /// # }
 /// ```
-pub fn all_query<'a, T: 'a + Ohmer>(r: &'a redis::Client) -> Result<Query<'a, T>, OhmerError> {
-    let class_name = T::default().get_class_name();
-    Ok(Query::<'a, T>::new(stal::Set::Key(format!("{}:all", class_name).as_bytes().to_vec()), r))
+pub fn find<T: Ohmer>(id: usize, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+    let obj = T::default();
+    let key = format!("{}:{}", obj.get_class_name(), id);
+    #[cfg(feature = "redisjson")]
+    {
+        if obj.store_as_json() {
+            let exists:bool = try!(redis::cmd("EXISTS").arg(&*key).query(r.conn()));
+            return Ok(if exists { Some(try!(get(id, r))) } else { None });
+        }
+    }
+    // HGETALL returns an empty hash for a missing key
+    let properties:HashMap<String, String> = try!(redis::cmd("HGETALL").arg(key).query(r.conn()));
+    if properties.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(try!(decode_properties(&obj, id, properties))))
 }
 
-/// Gets an iterator for all elements.
+/// Loads the object whose `Ohmer::string_id_field` is `string_id`, or
+/// `None` if there is none. Fails with `UnknownIndex` if the model has no
+/// string ids.
 ///
 /// # Examples
 ///
 /// ```rust
-/// # #[macro_use(model, create, new)] extern crate ohmers;
+/// # extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerConnection, OhmerError};
+/// #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+/// struct Receipt {
+///     id: usize,
+///     uid: String,
+///     total: u32,
+/// }
+/// impl Ohmer for Receipt {
+///     fn id(&self) -> usize { self.id }
+///     fn set_id(&mut self, id: usize) { self.id = id; }
+///     fn string_id_field(&self) -> Option<&'static str> { Some("uid") }
+///     fn generate_string_id(&self, r: &OhmerConnection) -> Result<Option<String>, OhmerError> {
+///         // a real model would use a UUID or ULID crate
+///         let n:u64 = try!(redis::cmd("INCR").arg("Receipt:uid").query(r.conn()));
+///         Ok(Some(format!("rct-{:x}", n * 2654435761)))
+///     }
+/// }
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut receipt = Receipt { total: 30, ..Default::default() };
+/// receipt.save(&client).unwrap();
+/// assert!(receipt.uid.starts_with("rct-"));
+///
+/// let loaded = ohmers::get_by_string_id::<Receipt>(&*receipt.uid, &client).unwrap();
+/// assert_eq!(loaded, Some(receipt));
+/// assert_eq!(ohmers::get_by_string_id::<Receipt>("rct-missing", &client).unwrap(), None);
+/// # }
+/// ```
+pub fn get_by_string_id<T: Ohmer>(string_id: &str, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+    let default = T::default();
+    let field = match default.string_id_field() {
+        Some(field) => field,
+        None => return Err(OhmerError::UnknownIndex("string id".to_string())),
+    };
+    let opt_id:Option<usize> = try!(redis::cmd("HGET")
+            .arg(format!("{}:uniques:{}", default.get_class_name(), stored_name(&default, field)))
+            .arg(string_id).query(r.conn()));
+    match opt_id {
+        Some(id) => find(id, r),
+        None => Ok(None),
+    }
+}
+
+/// Gets the fields stored for the object `id` as they are in Redis, without
+/// decoding them. Useful to find out why `get` fails with a `DecoderError`.
+/// Unlike `get`, the id is not added to the fields.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
 /// # extern crate rustc_serialize;
 /// # extern crate redis;
 /// # use ohmers::Ohmer;
 /// # use redis::Commands;
 /// model!(
-///     Furniture {
-///         kind:String = "".to_string();
+///     Thermostat {
+///         target:u8 = 20;
 ///     });
 /// # fn main() {
 /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-/// # let _:bool = client.del("Furniture:all").unwrap();
-/// # let _:bool = client.del("Furniture:id").unwrap();
-/// create!(Furniture { kind: "Couch".to_owned(), }, &client).unwrap();
-/// create!(Furniture { kind: "Chair".to_owned(), }, &client).unwrap();
-/// assert_eq!(ohmers::all::<Furniture>(&client).unwrap().collect::<Vec<_>>(),
-///     vec![
-///         new!(Furniture { id: 1, kind: "Chair".to_owned(), }),
-///         new!(Furniture { id: 2, kind: "Couch".to_owned(), }),
-///     ]);
+/// let thermostat = create!(Thermostat {}, &client).unwrap();
+/// let _:() = client.hset(format!("Thermostat:{}", thermostat.id), "target", "warm").unwrap();
+///
+/// assert!(ohmers::get::<Thermostat>(thermostat.id, &client).is_err());
+/// let fields = ohmers::raw::<Thermostat>(thermostat.id, &client).unwrap();
+/// assert_eq!(&*fields["target"], "warm");
 /// # }
 /// ```
-pub fn all<'a, T: 'a + Ohmer>(r: &'a redis::Client) -> Result<Iter<T>, OhmerError> {
-    Ok(try!(try!(all_query(r)).try_iter()))
+pub fn raw<T: Ohmer>(id: usize, r: &OhmerConnection) -> Result<HashMap<String, String>, OhmerError> {
+    Ok(try!(redis::cmd("HGETALL").arg(format!("{}:{}", T::default().get_class_name(), id)).query(r.conn())))
 }
 
-/// Structs that can be stored in and retrieved from Redis.
-/// You can use the `model!` macro as a helper.
-pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Default + Sized {
-    /// The name of the field storing the unique auto increment identifier.
-    /// It must be named "id" to be consistent with the LUA scripts.
-    fn id_field(&self) -> String { "id".to_string() }
+/// Sets a single field of the object `id` without loading it, updating
+/// its index or unique index if it has one. Much cheaper than `save` for
+/// hot updates of one field. `value` is written as it is, so it must be
+/// encoded like `save` would: numbers in decimal, booleans as `1` or `0`,
+/// and references as the id in the `<field>_id` field.
+///
+/// Fails with `NotFound` if there is no such object, and with
+/// `UniqueIndexViolation` if another object has the value of a unique
+/// field.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerError, Query};
+/// # use redis::Commands;
+/// model!(
+///     Visitor {
+///         indices { country:String = "".to_string(); };
+///         last_seen:u64 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let visitor = create!(Visitor { country: "PE".to_string(), last_seen: 100, }, &client).unwrap();
+/// ohmers::set_field::<Visitor>(visitor.id, "last_seen", "200", &client).unwrap();
+/// ohmers::set_field::<Visitor>(visitor.id, "country", "CL", &client).unwrap();
+///
+/// let loaded = ohmers::get::<Visitor>(visitor.id, &client).unwrap();
+/// assert_eq!(loaded.last_seen, 200);
+/// assert_eq!(&*loaded.country, "CL");
+/// assert!(Query::<Visitor>::find("country", "CL", &client).contains_id(visitor.id).unwrap());
+/// assert!(!Query::<Visitor>::find("country", "PE", &client).contains_id(visitor.id).unwrap());
+///
+/// let id:usize = client.get("Visitor:id").unwrap();
+/// assert_eq!(ohmers::set_field::<Visitor>(id + 1, "last_seen", "1", &client),
+///     Err(OhmerError::NotFound(id + 1)));
+/// # }
+/// ```
+pub fn set_field<T: Ohmer>(id: usize, field: &str, value: &str, r: &OhmerConnection) -> Result<(), OhmerError> {
+    try!(check_writable(r));
+    with_retry(r, || invoke_set_field::<T, ()>(id, field, None, value, r))
+}
 
-    /// The object unique identifier. It is 0 if it was not saved yet.
-    fn id(&self) -> usize;
-    /// Sets the object unique identifier. It should not be called manually,
-    /// it is set after save.
-    fn set_id(&mut self, id: usize);
+/// Sets a single field of the object `id` like `set_field`, but only if
+/// its stored value is `expected`, checking and setting it atomically.
+/// Returns whether the field was set, which gives lock-free transitions
+/// of a state machine kept in one field. `expected` and `value` are
+/// encoded like in `set_field`; a field that was never stored does not
+/// match any value.
+///
+/// Fails with `NotFound` if there is no such object, and with
+/// `UniqueIndexViolation` if another object has the value of a unique
+/// field. It is not retried on connection errors, since a retry could
+/// report `false` for a change that was applied.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerError, Query};
+/// # use redis::Commands;
+/// model!(
+///     Purchase {
+///         indices { status:String = "".to_string(); };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let purchase = create!(Purchase { status: "PAID".to_string(), }, &client).unwrap();
+/// assert!(ohmers::compare_and_set_field::<Purchase>(purchase.id, "status", "PAID", "SHIPPED", &client).unwrap());
+/// assert!(!ohmers::compare_and_set_field::<Purchase>(purchase.id, "status", "PAID", "SHIPPED", &client).unwrap());
+///
+/// assert_eq!(&*ohmers::get::<Purchase>(purchase.id, &client).unwrap().status, "SHIPPED");
+/// assert!(Query::<Purchase>::find("status", "SHIPPED", &client).contains_id(purchase.id).unwrap());
+/// assert!(!Query::<Purchase>::find("status", "PAID", &client).contains_id(purchase.id).unwrap());
+///
+/// let id:usize = client.get("Purchase:id").unwrap();
+/// assert_eq!(ohmers::compare_and_set_field::<Purchase>(id + 1, "status", "PAID", "SHIPPED", &client),
+///     Err(OhmerError::NotFound(id + 1)));
+/// # }
+/// ```
+pub fn compare_and_set_field<T: Ohmer>(id: usize, field: &str, expected: &str, value: &str, r: &OhmerConnection) -> Result<bool, OhmerError> {
+    try!(check_writable(r));
+    invoke_set_field::<T, bool>(id, field, Some(expected), value, r)
+}
+
+fn invoke_set_field<T: Ohmer, R: redis::FromRedisValue>(id: usize, field: &str, expected: Option<&str>, value: &str,
+        r: &OhmerConnection) -> Result<R, OhmerError> {
+    let default = T::default();
+    let class_name = default.get_class_name();
+    let index_fields = default.index_fields();
+    let indexed = index_fields.contains(field) ||
+        (field.ends_with("_id") && index_fields.contains(&field[..field.len() - 3]));
+    let stored = stored_name(&default, field);
+    let (prefix, index) = if indexed {
+        (default.key_for_index(&*stored, ""), default.key_for_index(&*stored, &*default.index_value(field, value)))
+    } else {
+        ("".to_string(), "".to_string())
+    };
+    let unique = if default.unique_fields().contains(field) || default.string_id_field() == Some(field) {
+        format!("{}:uniques:{}", class_name, stored)
+    } else {
+        "".to_string()
+    };
+
+    let keys = vec![format!("{}:{}", class_name, id), default.all_key()];
+    let mut args = vec![format!("{}", id), stored, value.to_string(), prefix, index, unique];
+    if let Some(expected) = expected {
+        args.push(expected.to_string());
+    }
+    let script = redis::Script::new(SET_FIELD);
+    script.key(keys).arg(args).invoke(r.conn()).map_err(OhmerError::from_script_error)
+}
+
+/// Gets a query for all elements.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, new)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     URL {
+///         domain:String = "".to_string();
+///         path:String = "/".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("URL:all").unwrap();
+/// # let _:bool = client.del("URL:id").unwrap();
+/// create!(URL { domain: "example.com".to_owned(), }, &client).unwrap();
+/// create!(URL { domain: "example.org".to_owned(), path: "/ping".to_owned(), }, &client).unwrap();
+/// assert_eq!(ohmers::all_query::<URL>(&client).unwrap().sort("path", None, true, true).unwrap().collect::<Vec<_>>(),
+///     vec![
+///         new!(URL { id: 1, domain: "example.com".to_owned(), }),
+///         new!(URL { id: 2, domain: "example.org".to_owned(), path: "/ping".to_owned(), }),
+///     ]);
+/// # }
+/// ```
+///
+/// It composes with indices like any other query, for example to leave
+/// some elements out:
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Record {
+///         indices { archived:bool = false; };
+///         title:String = "".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Record:all").unwrap();
+/// # let _:bool = client.del("Record:indices:archived:1").unwrap();
+/// create!(Record { title: "Draft".to_owned(), }, &client).unwrap();
+/// create!(Record { title: "Old".to_owned(), archived: true, }, &client).unwrap();
+/// create!(Record { title: "Final".to_owned(), }, &client).unwrap();
+///
+/// let mut titles = ohmers::all_query::<Record>(&client).unwrap()
+///     .diff("archived", "1")
+///     .try_iter().unwrap()
+///     .map(|r| r.title).collect::<Vec<_>>();
+/// titles.sort();
+/// assert_eq!(titles, vec!["Draft".to_owned(), "Final".to_owned()]);
+/// # }
+/// ```
+pub fn all_query<'a, T: 'a + Ohmer>(r: &'a OhmerConnection) -> Result<Query<'a, T>, OhmerError> {
+    Ok(Query::<'a, T>::new(stal::Set::Key(T::default().all_key().into_bytes()), r))
+}
+
+/// Gets an iterator for all elements.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, new)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Furniture {
+///         kind:String = "".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Furniture:all").unwrap();
+/// # let _:bool = client.del("Furniture:id").unwrap();
+/// create!(Furniture { kind: "Couch".to_owned(), }, &client).unwrap();
+/// create!(Furniture { kind: "Chair".to_owned(), }, &client).unwrap();
+/// assert_eq!(ohmers::all::<Furniture>(&client).unwrap().collect::<Vec<_>>(),
+///     vec![
+///         new!(Furniture { id: 1, kind: "Chair".to_owned(), }),
+///         new!(Furniture { id: 2, kind: "Couch".to_owned(), }),
+///     ]);
+/// # }
+/// ```
+pub fn all<'a, T: 'a + Ohmer>(r: &'a OhmerConnection) -> Result<Iter<T>, OhmerError> {
+    Ok(try!(try!(all_query(r)).try_iter()))
+}
+
+/// Creates many new objects in a single round trip. Their ids are taken as
+/// one block from `<Class>:id` and set on each object.
+///
+/// Unique fields are checked before anything is written, against existing
+/// objects and against the rest of the batch, so a violation leaves the
+/// database untouched. Ids are always sequential: `Ohmer::generate_id` is
+/// not used. Fails with `IdInUse` if an object was already saved.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, new)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Query};
+/// # use redis::Commands;
+/// model!(
+///     Seed {
+///         indices { kind:String = "".to_string(); };
+///         row:u32 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Seed:indices:kind:bulk").unwrap();
+/// let mut seeds = (0..1000).map(|row| new!(Seed { kind: "bulk".to_string(), row: row, }))
+///     .collect::<Vec<_>>();
+/// ohmers::create_many(&mut seeds, &client).unwrap();
+/// assert_eq!(seeds[999].id, seeds[0].id + 999);
+/// assert_eq!(ohmers::get::<Seed>(seeds[10].id, &client).unwrap().row, 10);
+/// assert_eq!(Query::<Seed>::find("kind", "bulk", &client).try_iter().unwrap().len(), 1000);
+/// # }
+/// ```
+pub fn create_many<T: Ohmer>(objs: &mut [T], r: &OhmerConnection) -> Result<(), OhmerError> {
+    try!(check_writable(r));
+    if objs.is_empty() {
+        return Ok(());
+    }
+    let encoding = T::default().script_encoding();
+    let mut args = Vec::with_capacity(objs.len() * 4);
+    for obj in objs.iter() {
+        if obj.id() != 0 {
+            return Err(OhmerError::IdInUse(obj.id()));
+        }
+        let mut encoder = try!(obj.encoder());
+        try!(save_features(obj, &mut encoder));
+        let (uniques, indices) = try!(obj.uniques_indices(&encoder));
+        args.push(try!(encoding.encode(&encoder.features)));
+        args.push(try!(encoding.encode(&encoder.attributes.iter().map(|x| &*x).collect::<Vec<_>>())));
+        args.push(try!(encoding.encode(&indices)));
+        args.push(try!(encoding.encode(&uniques)));
+    }
+    let script = redis::Script::new(&*encoding.adapt(CREATE_MANY));
+    let first:usize = try!(script.arg(args).invoke(r.conn()).map_err(OhmerError::from_script_error));
+    for (i, obj) in objs.iter_mut().enumerate() {
+        obj.set_id(first + i);
+    }
+    Ok(())
+}
+
+/// Saves many objects that were already saved once, like `Ohmer::save`
+/// on each of them, in one round trip. The objects are written atomically:
+/// if a unique value of any of them is taken, nothing is written and the
+/// error is `UniqueIndexViolationAt` with the id of the object and the
+/// field. Objects in the batch may trade unique values with each other.
+///
+/// Objects without an id fail with `NotSaved`; use `create_many` to create
+/// them.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerError};
+/// # use redis::Commands;
+/// model!(
+///     Seat {
+///         uniques { holder:String = "".to_string(); };
+///         row:u8 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Seat:uniques:holder").unwrap();
+/// let a = create!(Seat { holder: "Ann".to_string(), row: 1, }, &client).unwrap();
+/// let b = create!(Seat { holder: "Bob".to_string(), row: 2, }, &client).unwrap();
+/// let mut seats = vec![a, b];
+///
+/// seats[0].holder = "Bob".to_string();
+/// seats[1].holder = "Ann".to_string();
+/// ohmers::save_all(&seats, &client).unwrap();
+/// assert_eq!(ohmers::with::<Seat, _>("holder", "Bob", &client).unwrap().unwrap().row, 1);
+///
+/// create!(Seat { holder: "Cid".to_string(), row: 3, }, &client).unwrap();
+/// seats[0].holder = "Cid".to_string();
+/// seats[1].row = 5;
+/// assert_eq!(ohmers::save_all(&seats, &client),
+///         Err(OhmerError::UniqueIndexViolationAt { id: seats[0].id, field: "holder".to_string() }));
+/// assert_eq!(ohmers::with::<Seat, _>("holder", "Ann", &client).unwrap().unwrap().row, 2);
+/// # }
+/// ```
+pub fn save_all<T: Ohmer>(objs: &[T], r: &OhmerConnection) -> Result<(), OhmerError> {
+    try!(check_writable(r));
+    if objs.is_empty() {
+        return Ok(());
+    }
+    let encoding = T::default().script_encoding();
+    let mut args = Vec::with_capacity(objs.len() * 5);
+    for obj in objs.iter() {
+        if obj.id() == 0 {
+            return Err(OhmerError::NotSaved);
+        }
+        let mut encoder = try!(obj.encoder());
+        try!(save_features(obj, &mut encoder));
+        let (uniques, indices) = try!(obj.uniques_indices(&encoder));
+        args.push(try!(encoding.encode(&encoder.features)));
+        args.push(try!(encoding.encode(&encoder.attributes.iter().map(|x| &*x).collect::<Vec<_>>())));
+        args.push(try!(encoding.encode(&indices)));
+        args.push(try!(encoding.encode(&uniques)));
+        args.push(try!(encoding.encode(&encoder.nil_attributes)));
+    }
+    let script = redis::Script::new(&*encoding.adapt(SAVE_MANY));
+    with_retry(r, || script.arg(&*args).invoke::<usize>(r.conn()).map(|_| ()).map_err(OhmerError::from_script_error))
+}
+
+/// Saves an object with the save script. `only` restricts the write to
+/// objects that are `absent` or `present`, and `None` saves either way.
+fn save_only<T: Ohmer>(obj: &mut T, only: Option<&str>, r: &OhmerConnection) -> Result<SaveOutcome, OhmerError> {
+    try!(check_writable(r));
+    let created = obj.id() == 0;
+    if let Some(field) = obj.string_id_field() {
+        try!(fill_string_id(obj, field, r));
+    }
+    let mut encoder = try!(obj.encoder());
+    if created {
+        if let Some(id) = try!(obj.generate_id(r)) {
+            encoder.features.insert("id".to_string(), format!("{}", id));
+            encoder.features.insert("new".to_string(), "1".to_string());
+        }
+    }
+    try!(save_features(obj, &mut encoder));
+    if let Some(only) = only {
+        encoder.features.insert("only".to_string(), only.to_string());
+    }
+    let preserve = obj.preserve_unknown_fields();
+    let (uniques, indices) = try!(obj.uniques_indices(&encoder));
+    let encoding = obj.script_encoding();
+    let mut args = vec![
+        try!(encoding.encode(&encoder.features)),
+        try!(encoding.encode(&encoder.attributes.iter().map(|x| &*x).collect::<Vec<_>>())),
+        try!(encoding.encode(&indices)),
+        try!(encoding.encode(&uniques)),
+    ];
+    if preserve {
+        args.push(try!(encoding.encode(&encoder.nil_attributes)));
+    }
+    let script = redis::Script::new(&*obj.save_script());
+    let invoke = || script.arg(&*args).invoke::<usize>(r.conn()).map_err(OhmerError::from_script_error);
+    // Creating is not retried: if the reply is lost after the object was
+    // saved, trying again would create it twice. Neither is saving only
+    // absent objects, since the retry would find the first write.
+    let id = try!(timed(r, "save", || if created || only == Some("absent") { invoke() } else { with_retry(r, invoke) }));
+    obj.set_id(id);
+    Ok(if created { SaveOutcome::Created { id: id } } else { SaveOutcome::Updated })
+}
+
+/// Sets the `string_id_field` of an object to a `generate_string_id` if it
+/// is empty.
+fn fill_string_id<T: Ohmer>(obj: &mut T, field: &str, r: &OhmerConnection) -> Result<(), OhmerError> {
+    let stored = stored_name(obj, field);
+    let mut map = try!(obj.to_map());
+    match map.get(&stored) {
+        Some(value) if !value.is_empty() => return Ok(()),
+        Some(_) => (),
+        None => return Err(OhmerError::UnknownIndex(field.to_string())),
+    }
+    let string_id = match try!(obj.generate_string_id(r)) {
+        Some(string_id) => string_id,
+        None => return Err(OhmerError::MissingStringId),
+    };
+    map.insert(stored, string_id);
+    *obj = try!(T::from_map(map));
+    Ok(())
+}
+
+/// Sets the `soft_delete_field` of an object and saves it.
+fn set_deleted<T: Ohmer>(obj: &mut T, deleted: bool, r: &OhmerConnection) -> Result<(), OhmerError> {
+    if obj.id() == 0 {
+        return Err(OhmerError::NotSaved);
+    }
+    let field = obj.soft_delete_field().unwrap_or("");
+    let stored = stored_name(obj, field);
+    let mut map = try!(obj.to_map());
+    if !map.contains_key(&stored) {
+        return Err(OhmerError::UnknownIndex(field.to_string()));
+    }
+    map.insert(stored, (if deleted { "1" } else { "0" }).to_string());
+    *obj = try!(T::from_map(map));
+    try!(obj.save(r));
+    Ok(())
+}
+
+/// The one-to-one references of an object as the scripts expect them,
+/// `<field>_id <Class> <inverse>_id` triples separated by commas.
+fn inverses_feature<T: Ohmer>(obj: &T) -> Option<String> {
+    let inverses = obj.one_to_one();
+    if inverses.is_empty() {
+        return None;
+    }
+    Some(inverses.iter()
+            .map(|&(field, class, inverse)| format!("{} {} {}_id",
+                    stored_name(obj, &*format!("{}_id", field.to_ascii_lowercase())),
+                    class, inverse.to_ascii_lowercase()))
+            .collect::<Vec<_>>().join(","))
+}
+
+/// Adds `_schema` to the attributes of `encoder`, unless the model has no
+/// schema version.
+fn add_schema_version<T: Ohmer>(obj: &T, encoder: &mut Encoder) {
+    let version = obj.schema_version();
+    if version > 0 {
+        encoder.attributes.push("_schema".to_string());
+        encoder.attributes.push(format!("{}", version));
+    }
+}
+
+/// Adds the flags of the save script that depend on the model, other than
+/// the id, and its schema version, to `encoder`.
+fn save_features<T: Ohmer>(obj: &T, encoder: &mut Encoder) -> Result<(), OhmerError> {
+    add_schema_version(obj, encoder);
+    if !obj.track_all() {
+        encoder.features.insert("untracked".to_string(), "1".to_string());
+    }
+    if let Some(shared) = obj.shared_index_class() {
+        encoder.features.insert("shared".to_string(), shared.to_string());
+    }
+    if obj.preserve_unknown_fields() {
+        encoder.features.insert("preserve".to_string(), "1".to_string());
+    }
+    if let Some(inverses) = inverses_feature(obj) {
+        encoder.features.insert("inverses".to_string(), inverses);
+    }
+    #[cfg(feature = "redisjson")]
+    {
+        if obj.store_as_json() {
+            let doc = try!(json::encode(obj).map_err(|_| EncoderError::JsonError));
+            let mut fields = match json::Json::from_str(&*doc) {
+                Ok(json::Json::Object(fields)) => fields,
+                _ => return Err(EncoderError::JsonError.into()),
+            };
+            fields.remove(&encoder.id_field);
+            encoder.features.insert("json".to_string(), json::Json::Object(fields).to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Loads the objects referenced by `objs` through the reference returned by
+/// `f`, fetching every distinct target in one round trip. The result maps
+/// each target id to its object; empty references and targets that no
+/// longer exist are left out.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Reference};
+/// model!(
+///     Director {
+///         name:String = "".to_string();
+///     });
+/// model!(
+///     Film {
+///         title:String = "".to_string();
+///         director:Reference<Director> = Reference::new();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let kubrick = create!(Director { name: "Kubrick".to_string(), }, &client).unwrap();
+/// let films = vec![
+///     create!(Film { title: "Dr. Strangelove".to_string(), director: Reference::with_value(&kubrick), }, &client).unwrap(),
+///     create!(Film { title: "The Shining".to_string(), director: Reference::with_value(&kubrick), }, &client).unwrap(),
+///     create!(Film { title: "Untitled".to_string(), }, &client).unwrap(),
+/// ];
+///
+/// let directors = ohmers::preload(&films, |f| &f.director, &client).unwrap();
+/// assert_eq!(directors.len(), 1);
+/// assert_eq!(&*directors[&films[1].director.id()].name, "Kubrick");
+/// assert!(directors.get(&films[2].director.id()).is_none());
+/// # }
+/// ```
+pub fn preload<T: Ohmer, R: Ohmer, F: Fn(&T) -> &Reference<R>>(objs: &[T], f: F, r: &OhmerConnection) -> Result<HashMap<usize, R>, OhmerError> {
+    Reference::get_many(&*objs.iter().map(f).collect::<Vec<_>>(), r)
+}
+
+/// Gets an iterator over all elements that walks `<Class>:all` with `SSCAN`
+/// instead of loading every id up front. Objects are fetched `batch` at a
+/// time, so memory use is bounded by the batch size. A `batch` of 0 is
+/// taken as 1.
+///
+/// Like `SSCAN`, an element may be returned more than once if the set
+/// changes during the scan. Elements deleted while the scan is running are
+/// skipped.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Book {
+///         title:String = "".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Book:all").unwrap();
+/// create!(Book { title: "Dune".to_owned(), }, &client).unwrap();
+/// create!(Book { title: "Emma".to_owned(), }, &client).unwrap();
+/// let mut titles = ohmers::scan::<Book>(&client, 1).map(|b| b.unwrap().title).collect::<Vec<_>>();
+/// titles.sort();
+/// assert_eq!(titles, vec!["Dune".to_owned(), "Emma".to_owned()]);
+/// # }
+/// ```
+pub fn scan<'a, T: 'a + Ohmer>(r: &'a OhmerConnection, batch: usize) -> Scan<'a, T> {
+    Scan {
+        key: T::default().all_key(),
+        cursor: Some(0),
+        batch: std::cmp::max(batch, 1),
+        buffer: vec![].into_iter(),
+        r: r,
+    }
+}
+
+/// Loads many objects in a single round trip. Ids with no stored object
+/// are returned as `None`. Like `find`, an object exists if its hash does,
+/// so objects of models that do not `track_all` are loaded too.
+fn load_many<T: Ohmer>(ids: &[usize], r: &OhmerConnection) -> Result<Vec<Option<T>>, OhmerError> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let default = T::default();
+    let class_name = default.get_class_name();
+    #[cfg(feature = "redisjson")]
+    {
+        if default.store_as_json() {
+            let mut q = redis::pipe();
+            for id in ids {
+                q.cmd("JSON.GET").arg(format!("{}:{}", class_name, id));
+            }
+            let docs:Vec<Option<String>> = try!(q.query(r.conn()));
+            let mut objs = Vec::with_capacity(ids.len());
+            for (id, doc) in ids.iter().zip(docs.into_iter()) {
+                objs.push(match doc {
+                    Some(doc) => Some(try!(decode_json(*id, &*doc))),
+                    None => None,
+                });
+            }
+            return Ok(objs);
+        }
+    }
+    let mut q = redis::pipe();
+    for id in ids {
+        q.cmd("HGETALL").arg(format!("{}:{}", class_name, id));
+    }
+    let hashes:Vec<HashMap<String, String>> = try!(q.query(r.conn()));
+
+    let mut objs = Vec::with_capacity(ids.len());
+    for (id, properties) in ids.iter().zip(hashes.into_iter()) {
+        // HGETALL returns an empty hash for a missing key
+        if properties.is_empty() {
+            objs.push(None);
+            continue;
+        }
+        objs.push(Some(try!(decode_properties(&default, *id, properties))));
+    }
+    Ok(objs)
+}
+
+/// Decodes a RedisJSON document written by `save`, taking the id from
+/// its key.
+#[cfg(feature = "redisjson")]
+fn decode_json<T: Ohmer>(id: usize, doc: &str) -> Result<T, DecoderError> {
+    let mut fields = match json::Json::from_str(doc) {
+        Ok(json::Json::Object(fields)) => fields,
+        _ => return Err(DecoderError::ExpectedError("JSON object".to_string(), doc.to_string())),
+    };
+    fields.insert(T::default().id_field(), json::Json::U64(id as u64));
+    json::decode(&*json::Json::Object(fields).to_string())
+            .map_err(|e| DecoderError::ApplicationError(format!("{:?}", e)))
+}
+
+/// Loads many objects in a single round trip like `load_many`, failing
+/// with `NotFound` if any of them no longer exists.
+fn load_all<T: Ohmer>(ids: &[usize], r: &OhmerConnection) -> Result<Vec<T>, OhmerError> {
+    let objs = try!(load_many(ids, r));
+    let mut loaded = Vec::with_capacity(objs.len());
+    for (id, obj) in ids.iter().zip(objs.into_iter()) {
+        match obj {
+            Some(obj) => loaded.push(obj),
+            None => return Err(OhmerError::NotFound(*id)),
+        }
+    }
+    Ok(loaded)
+}
+
+/// The id the next new object of the class will get, read from the
+/// counter in `<Class>:id` without changing it. Another client may take
+/// it before it is used; see `reserve_ids` to keep ids for later.
+pub fn peek_next_id<T: Ohmer>(r: &OhmerConnection) -> Result<usize, OhmerError> {
+    let last:Option<usize> = try!(redis::cmd("GET").arg(T::default().id_key()).query(r.conn()));
+    Ok(last.unwrap_or(0) + 1)
+}
+
+/// Takes `count` consecutive ids from the counter in `<Class>:id`, so no
+/// new object gets them. Objects are saved with a reserved id by setting
+/// it before `save`, which reports them as updated. Ids that are reserved
+/// and never used leave gaps in the sequence.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// model!(
+///     Parcel {
+///         weight:u32 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let next = ohmers::peek_next_id::<Parcel>(&client).unwrap();
+/// let ids = ohmers::reserve_ids::<Parcel>(3, &client).unwrap();
+/// assert_eq!(ids, next..next + 3);
+///
+/// let created = create!(Parcel { weight: 1, }, &client).unwrap();
+/// assert_eq!(created.id, next + 3);
+///
+/// let mut reserved = Parcel::default();
+/// reserved.id = ids.start;
+/// reserved.weight = 2;
+/// reserved.save(&client).unwrap();
+/// assert_eq!(ohmers::get::<Parcel>(ids.start, &client).unwrap().weight, 2);
+/// # }
+/// ```
+pub fn reserve_ids<T: Ohmer>(count: usize, r: &OhmerConnection) -> Result<::std::ops::Range<usize>, OhmerError> {
+    if count == 0 {
+        let next = try!(peek_next_id::<T>(r));
+        return Ok(next..next);
+    }
+    try!(check_writable(r));
+    let last:usize = try!(redis::cmd("INCRBY").arg(T::default().id_key()).arg(count).query(r.conn()));
+    Ok(last + 1 - count..last + 1)
+}
+
+/// Key where `Query::cached` stores the ids for `key`.
+fn query_cache_key<T: Ohmer>(key: &str) -> String {
+    format!("{}:cache:{}", T::default().get_class_name(), key)
+}
+
+/// Removes the ids stored by `Query::cached` under `key`, so the next call
+/// runs the query again. Returns whether they were stored.
+pub fn invalidate_query_cache<T: Ohmer>(key: &str, r: &OhmerConnection) -> Result<bool, OhmerError> {
+    try!(check_writable(r));
+    Ok(try!(redis::cmd("DEL").arg(query_cache_key::<T>(key)).query(r.conn())))
+}
+
+/// Renames a field to its alias from `Ohmer::field_aliases`, or back if
+/// `back` is set. References and their class keep their `_id` and
+/// `_type` suffixes.
+fn rename_field(aliases: &[(&str, &str)], name: &str, back: bool) -> String {
+    for suffix in ["", "_id", "_type"].iter() {
+        if !name.ends_with(suffix) {
+            continue;
+        }
+        let base = &name[..name.len() - suffix.len()];
+        for &(field, alias) in aliases {
+            let (from, to) = if back { (alias, field) } else { (field, alias) };
+            if base == from {
+                return format!("{}{}", to, suffix);
+            }
+        }
+    }
+    name.to_string()
+}
+
+/// The name `field` is stored under in the hash, see
+/// `Ohmer::field_aliases`.
+fn stored_name<T: Ohmer>(obj: &T, field: &str) -> String {
+    rename_field(&*obj.field_aliases(), field, false)
+}
+
+/// Decodes an object with the fields of its hash, migrating them first if
+/// they were saved with an older `Ohmer::schema_version`.
+fn decode_properties<T: Ohmer>(default: &T, id: usize, mut properties: HashMap<String, String>) -> Result<T, DecoderError> {
+    let aliases = default.field_aliases();
+    if !aliases.is_empty() {
+        properties = properties.into_iter()
+                .map(|(name, value)| (rename_field(&*aliases, &*name, true), value))
+                .collect();
+    }
+    let version = properties.remove("_schema").and_then(|v| v.parse().ok()).unwrap_or(0);
+    if version < default.schema_version() {
+        default.migrate(&mut properties, version);
+    }
+    properties.insert(default.id_field(), format!("{}", id));
+    let mut decoder = Decoder::new(properties);
+    rustc_serialize::Decodable::decode(&mut decoder)
+}
+
+/// Escapes the glob characters in a key so it can be used in a `MATCH`
+/// pattern.
+fn glob_escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '\\' => escaped.push('\\'),
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Gets all the distinct values of an indexed field, sorted. The index keys
+/// are found using `SCAN`, so no extra bookkeeping is needed. See
+/// `distinct_count` to only count them.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Shirt {
+///         indices {
+///             color:String = "".to_string();
+///         };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # for color in ["red", "blue", "green"].iter() {
+/// #     let _:bool = client.del(format!("Shirt:indices:color:{}", color)).unwrap();
+/// # }
+/// create!(Shirt { color: "red".to_owned(), }, &client).unwrap();
+/// create!(Shirt { color: "blue".to_owned(), }, &client).unwrap();
+/// create!(Shirt { color: "red".to_owned(), }, &client).unwrap();
+/// assert_eq!(ohmers::index_values::<Shirt>("color", &client).unwrap(),
+///     vec!["blue".to_owned(), "red".to_owned()]);
+/// # }
+/// ```
+pub fn index_values<T: Ohmer>(field: &str, r: &OhmerConnection) -> Result<Vec<String>, OhmerError> {
+    let default = T::default();
+    let prefix = default.key_for_index(&*stored_name(&default, field), "");
+    let keys:HashSet<String> = try!(redis::cmd("SCAN").cursor_arg(0)
+        .arg("MATCH").arg(format!("{}*", glob_escape(&*prefix)))
+        .iter(r.conn())).collect();
+    let mut values = keys.into_iter().map(|key| key[prefix.len()..].to_string()).collect::<Vec<_>>();
+    values.sort();
+    Ok(values)
+}
+
+/// Gets all the distinct values of an indexed field and how many elements
+/// have each of them.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Sock {
+///         indices {
+///             size:u8 = 0;
+///         };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Sock:indices:size:9").unwrap();
+/// # let _:bool = client.del("Sock:indices:size:10").unwrap();
+/// create!(Sock { size: 9, }, &client).unwrap();
+/// create!(Sock { size: 10, }, &client).unwrap();
+/// create!(Sock { size: 10, }, &client).unwrap();
+/// let counts = ohmers::index_value_counts::<Sock>("size", &client).unwrap();
+/// assert_eq!(counts["9"], 1);
+/// assert_eq!(counts["10"], 2);
+/// # }
+/// ```
+pub fn index_value_counts<T: Ohmer>(field: &str, r: &OhmerConnection) -> Result<HashMap<String, usize>, OhmerError> {
+    let values = try!(index_values::<T>(field, r));
+    if values.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let default = T::default();
+    let mut q = redis::pipe();
+    for value in values.iter() {
+        q.cmd("SCARD").arg(default.key_for_index(&*stored_name(&default, field), value));
+    }
+    let counts:Vec<usize> = try!(q.query(r.conn()));
+    Ok(values.into_iter().zip(counts.into_iter()).filter(|&(_, count)| count > 0).collect())
+}
+
+/// Counts the distinct values of an indexed field, the number of values
+/// `index_values` returns. Fails with `UnknownIndex` if the field is not
+/// in `index_fields` or `computed_indices`, since the values of other
+/// fields are only found by loading every object. References are counted
+/// by `<field>_id`, like their index.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerError};
+/// # use redis::Commands;
+/// model!(
+///     Kite {
+///         indices {
+///             color:String = "".to_string();
+///         };
+///         length:u8 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # for color in ["red", "blue"].iter() {
+/// #     let _:bool = client.del(format!("Kite:indices:color:{}", color)).unwrap();
+/// # }
+/// create!(Kite { color: "red".to_owned(), }, &client).unwrap();
+/// create!(Kite { color: "blue".to_owned(), }, &client).unwrap();
+/// create!(Kite { color: "red".to_owned(), }, &client).unwrap();
+/// assert_eq!(ohmers::distinct_count::<Kite>("color", &client).unwrap(), 2);
+/// assert_eq!(ohmers::distinct_count::<Kite>("length", &client),
+///     Err(OhmerError::UnknownIndex("length".to_string())));
+/// # }
+/// ```
+pub fn distinct_count<T: Ohmer>(field: &str, r: &OhmerConnection) -> Result<usize, OhmerError> {
+    let default = T::default();
+    let name = if field.ends_with("_id") { &field[..field.len() - 3] } else { field };
+    let indexed = default.index_fields().contains(field) || default.index_fields().contains(name) ||
+            default.computed_indices().iter().any(|&(ref computed, _)| computed == field);
+    if !indexed {
+        return Err(OhmerError::UnknownIndex(field.to_string()));
+    }
+    Ok(try!(index_values::<T>(field, r)).len())
+}
+
+/// Key of the index of the `Reference` property `field` of `T` for the
+/// target `id`, `<Class>:indices:<field>_id:<id>`.
+fn reference_index_key<T: Ohmer>(field: &str, id: usize) -> Result<String, OhmerError> {
+    let default = T::default();
+    if !default.index_fields().contains(field) {
+        return Err(OhmerError::UnknownIndex(field.to_string()));
+    }
+    Ok(default.key_for_index(&*stored_name(&default, &*format!("{}_id", field)), &*format!("{}", id)))
+}
+
+/// Counts the objects of `T` whose indexed `Reference` property `field`
+/// points to the object with id `target_id`. It fails with `UnknownIndex`
+/// if `field` is not indexed.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Reference};
+/// model!(
+///     Stage {
+///         name:String = "".to_string();
+///     });
+/// model!(
+///     Concert {
+///         indices { stage:Reference<Stage> = Reference::new(); };
+///         band:String = "".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let main = create!(Stage { name: "Main".to_string(), }, &client).unwrap();
+/// let tent = create!(Stage { name: "Tent".to_string(), }, &client).unwrap();
+/// create!(Concert { stage: Reference::with_value(&main), band: "A".to_string(), }, &client).unwrap();
+/// create!(Concert { stage: Reference::with_value(&main), band: "B".to_string(), }, &client).unwrap();
+/// assert_eq!(ohmers::referenced_by_count::<Concert>(main.id, "stage", &client).unwrap(), 2);
+/// assert_eq!(ohmers::referenced_by_count::<Concert>(tent.id, "stage", &client).unwrap(), 0);
+///
+/// let bands = ohmers::referenced_by::<Concert>(main.id, "stage", &client).unwrap()
+///     .try_iter().unwrap().map(|c| c.band).collect::<Vec<_>>();
+/// assert_eq!(bands.len(), 2);
+/// # }
+/// ```
+pub fn referenced_by_count<T: Ohmer>(target_id: usize, field: &str, r: &OhmerConnection) -> Result<usize, OhmerError> {
+    let key = try!(reference_index_key::<T>(field, target_id));
+    Ok(try!(redis::cmd("SCARD").arg(key).query(r.conn())))
+}
+
+/// A query for the objects of `T` whose indexed `Reference` property
+/// `field` points to the object with id `target_id`. See
+/// `referenced_by_count`.
+pub fn referenced_by<'a, T: Ohmer>(target_id: usize, field: &str, r: &'a OhmerConnection) -> Result<Query<'a, T>, OhmerError> {
+    let key = try!(reference_index_key::<T>(field, target_id));
+    Ok(Query::new(stal::Set::Key(key.into_bytes()), r))
+}
+
+/// Finds the objects of every model sharing the indices of the class
+/// `shared` (see `Ohmer::shared_index_class`) with all the `field`/`value`
+/// pairs. Returns the class and id of each one, sorted, so they can be
+/// loaded with `get` into the right type.
+///
+/// Values are used as given: they don't go through
+/// `Ohmer::index_value`.
+pub fn find_shared(shared: &str, kv: &[(&str, &str)], r: &OhmerConnection) -> Result<Vec<(String, usize)>, OhmerError> {
+    if kv.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys = kv.iter().map(|&(field, value)| format!("{}:indices:{}:{}", shared, field, value)).collect::<Vec<_>>();
+    let members:Vec<String> = try!(with_retry(r, || Ok(try!(redis::cmd("SINTER").arg(&*keys).query(r.conn())))));
+    let mut found = members.iter().filter_map(|member| {
+        let mut parts = member.rsplitn(2, ':');
+        match (parts.next().and_then(|id| id.parse().ok()), parts.next()) {
+            (Some(id), Some(class)) => Some((class.to_string(), id)),
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    found.sort();
+    Ok(found)
+}
+
+/// The indexed fields of `obj` whose indices don't match its values, sorted,
+/// with the index keys missing the object and the ones that should not
+/// have it.
+fn index_mismatches<T: Ohmer>(obj: &T, r: &OhmerConnection) -> Result<Vec<(String, Vec<String>, Vec<String>)>, OhmerError> {
+    let id = obj.id();
+    if id == 0 {
+        return Err(OhmerError::NotSaved);
+    }
+    let encoder = try!(obj.encoder());
+    let (_, indices) = try!(obj.uniques_indices(&encoder));
+    let mut fields = obj.index_fields().into_iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    fields.extend(obj.computed_indices().into_iter().map(|(f, _)| f));
+    fields.sort();
+    fields.dedup();
+
+    let mut mismatches = vec![];
+    for field in fields.into_iter() {
+        let reference = format!("{}_id", field);
+        // References are indexed by the id of their target.
+        let name = if encoder.references.contains(&stored_name(obj, &*reference)) { reference } else { field.clone() };
+        let stored = stored_name(obj, &*name);
+        let expected = indices.get(&stored).cloned().unwrap_or(vec![]).into_iter()
+                .map(|value| obj.key_for_index(&*stored, &*value))
+                .collect::<HashSet<_>>();
+        let mut keys = try!(index_values::<T>(&*name, r)).into_iter()
+                .map(|value| obj.key_for_index(&*stored, &*value))
+                .collect::<HashSet<_>>();
+        keys.extend(expected.iter().cloned());
+        if keys.is_empty() {
+            continue;
+        }
+        let keys = keys.into_iter().collect::<Vec<_>>();
+
+        let mut q = redis::pipe();
+        for key in keys.iter() {
+            q.cmd("SISMEMBER").arg(&**key).arg(id);
+        }
+        let members:Vec<bool> = try!(q.query(r.conn()));
+
+        let mut missing = vec![];
+        let mut stray = vec![];
+        for (key, member) in keys.into_iter().zip(members.into_iter()) {
+            match (expected.contains(&key), member) {
+                (true, false) => missing.push(key),
+                (false, true) => stray.push(key),
+                _ => (),
+            }
+        }
+        if !missing.is_empty() || !stray.is_empty() {
+            mismatches.push((field, missing, stray));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// The keys matching a pattern, found with `SCAN`.
+fn matching_keys(pattern: &str, r: &OhmerConnection) -> Result<Vec<String>, OhmerError> {
+    Ok(try!(redis::cmd("SCAN").cursor_arg(0).arg("MATCH").arg(pattern).iter(r.conn())).collect())
+}
+
+/// Deletes the keys matching a pattern, found with `SCAN`.
+fn delete_matching(pattern: &str, r: &OhmerConnection) -> Result<(), OhmerError> {
+    let keys = try!(matching_keys(pattern, r));
+    for chunk in keys.chunks(1000) {
+        let _:() = try!(redis::cmd("DEL").arg(chunk).query(r.conn()));
+    }
+    Ok(())
+}
+
+/// Rebuilds the indices and uniques of every element from its current
+/// values, replacing the existing ones. Returns how many elements were
+/// processed. It is meant to repair inconsistent indices offline, while
+/// nothing else writes to the class.
+///
+/// Every element is loaded before anything is changed, so one that fails
+/// to decode leaves the indices as they were. The index and unique keys,
+/// the memos of each element and its members of the `shared_index_class`
+/// indices are then replaced in one transaction. The objects themselves
+/// are not written.
+///
+/// The elements are read from `<Class>:all`, so it fails with `Untracked`,
+/// leaving the indices alone, for models that do not `track_all`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Query};
+/// # use redis::Commands;
+/// model!(
+///     Plant {
+///         indices { kind:String = "".to_string(); };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Plant:all").unwrap();
+/// let plant = create!(Plant { kind: "fern".to_string(), }, &client).unwrap();
+/// let _:bool = client.srem("Plant:indices:kind:fern", plant.id).unwrap();
+/// let _:bool = client.sadd("Plant:indices:kind:cactus", plant.id).unwrap();
+/// let _:bool = client.hset(format!("Plant:{}", plant.id), "note", "kept").unwrap();
+///
+/// assert_eq!(ohmers::reindex::<Plant>(&client).unwrap(), 1);
+/// let note:String = client.hget(format!("Plant:{}", plant.id), "note").unwrap();
+/// assert_eq!(&*note, "kept");
+/// assert_eq!(Query::<Plant>::find("kind", "fern", &client).try_iter().unwrap().collect::<Vec<_>>(), vec![plant]);
+/// assert_eq!(Query::<Plant>::find("kind", "cactus", &client).try_iter().unwrap().count(), 0);
+/// # }
+/// ```
+pub fn reindex<T: Ohmer>(r: &OhmerConnection) -> Result<usize, OhmerError> {
+    try!(check_writable(r));
+    let default = T::default();
+    let name = default.get_class_name();
+    if !default.track_all() {
+        return Err(OhmerError::Untracked(name));
+    }
+    let objs = try!(scan::<T>(r, 100).collect::<Result<Vec<T>, _>>());
+
+    let class_name = glob_escape(&*name);
+    let mut stale = vec![];
+    for pattern in ["indices:*", "uniques:*", "*:_indices", "*:_uniques", "*:_shared"].iter() {
+        stale.extend(try!(matching_keys(&*format!("{}:{}", class_name, pattern), r)));
+    }
+    let mut q = redis::pipe();
+    q.atomic();
+    for chunk in stale.chunks(1000) {
+        q.cmd("DEL").arg(chunk).ignore();
+    }
+    let shared = default.shared_index_class();
+    if let Some(shared) = shared {
+        // Only the members of this class are removed from the shared indices.
+        let prefix = format!("{}:", name);
+        for key in try!(matching_keys(&*format!("{}:indices:*", glob_escape(shared)), r)) {
+            let members:Vec<String> = try!(redis::cmd("SMEMBERS").arg(&*key).query(r.conn()));
+            let members = members.into_iter().filter(|member| member.starts_with(&*prefix)).collect::<Vec<_>>();
+            if !members.is_empty() {
+                q.cmd("SREM").arg(&*key).arg(members).ignore();
+            }
+        }
+    }
+
+    for obj in objs.iter() {
+        let encoder = try!(obj.encoder());
+        let (uniques, indices) = try!(obj.uniques_indices(&encoder));
+        let key = format!("{}:{}", name, obj.id());
+        for (field, values) in indices.iter() {
+            for value in values.iter() {
+                let index = format!("{}:indices:{}:{}", name, field, value);
+                q.cmd("SADD").arg(format!("{}:_indices", key)).arg(&*index).ignore();
+                q.cmd("SADD").arg(&*index).arg(obj.id()).ignore();
+                if let Some(shared) = shared {
+                    let index = format!("{}:indices:{}:{}", shared, field, value);
+                    q.cmd("SADD").arg(format!("{}:_shared", key)).arg(&*index).ignore();
+                    q.cmd("SADD").arg(&*index).arg(&*key).ignore();
+                }
+            }
+        }
+        for (field, value) in uniques.iter() {
+            let unique = format!("{}:uniques:{}", name, field);
+            q.cmd("HSET").arg(format!("{}:_uniques", key)).arg(&*unique).arg(&**value).ignore();
+            q.cmd("HSET").arg(&*unique).arg(&**value).arg(obj.id()).ignore();
+        }
+    }
+    let _:() = try!(q.query(r.conn()));
+    Ok(objs.len())
+}
+
+/// Moves all the keys of the class `old` to the class `new`: the objects,
+/// their indices, uniques, counters, sets and lists, and the id sequence.
+/// Index and unique key names stored in the objects memos are rewritten too.
+///
+/// Keys are moved atomically in batches, but not all at once, so nothing
+/// should be writing to the class while it runs. It fails with
+/// `ClassExists` if `new` already has keys.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Query};
+/// model!(
+///     Member {
+///         uniques { email:String = "".to_string(); };
+///         indices { plan:String = "free".to_string(); };
+///     });
+/// model!(
+///     Subscriber {
+///         uniques { email:String = "".to_string(); };
+///         indices { plan:String = "free".to_string(); };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # for class in ["Member", "Subscriber"].iter() {
+/// #     let keys:Vec<String> = redis::cmd("KEYS").arg(format!("{}:*", class)).query(&client).unwrap();
+/// #     if !keys.is_empty() { let _:() = redis::cmd("DEL").arg(keys).query(&client).unwrap(); }
+/// # }
+/// let member = create!(Member { email: "a@example.com".to_string(), }, &client).unwrap();
+/// ohmers::rename_class("Member", "Subscriber", &client).unwrap();
+///
+/// let subscriber = ohmers::with::<Subscriber, _>("email", "a@example.com", &client).unwrap().unwrap();
+/// assert_eq!(subscriber.id, member.id);
+/// assert_eq!(Query::<Subscriber>::find("plan", "free", &client).try_iter().unwrap().count(), 1);
+/// assert!(ohmers::with::<Member, _>("email", "a@example.com", &client).unwrap().is_none());
+///
+/// // the memos point to the new keys, so deleting cleans up the new indices
+/// subscriber.delete(&client).unwrap();
+/// assert_eq!(Query::<Subscriber>::find("plan", "free", &client).try_iter().unwrap().count(), 0);
+/// # }
+/// ```
+pub fn rename_class(old: &str, new: &str, r: &OhmerConnection) -> Result<(), OhmerError> {
+    try!(check_writable(r));
+    let class_keys = |class: &str| -> Result<Vec<String>, OhmerError> {
+        Ok(try!(redis::cmd("SCAN").cursor_arg(0).arg("MATCH").arg(format!("{}:*", glob_escape(class))).iter(r.conn())).collect())
+    };
+    if !try!(class_keys(new)).is_empty() {
+        return Err(OhmerError::ClassExists(new.to_string()));
+    }
+    let mut keys = try!(class_keys(old));
+    keys.sort();
+    keys.dedup();
+
+    let script = redis::Script::new(RENAME_CLASS);
+    for chunk in keys.chunks(1000) {
+        let _:() = try!(script.key(chunk).arg(old).arg(new).invoke(r.conn()));
+    }
+    Ok(())
+}
+
+/// Names of the classes with data in the database, sorted. They are
+/// found with `SCAN` from their `<Class>:all` and `<Class>:id` keys, so
+/// it is a guess based on key names: a class whose objects were all
+/// deleted can still be listed, and classes with a `:` in their name or
+/// keys written by something else with those suffixes can be wrong.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// model!(
+///     Lantern {
+///         lit:bool = false;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// create!(Lantern {}, &client).unwrap();
+/// assert!(ohmers::known_classes(&client).unwrap().contains(&"Lantern".to_string()));
+/// # }
+/// ```
+pub fn known_classes(r: &OhmerConnection) -> Result<Vec<String>, OhmerError> {
+    let mut classes = HashSet::new();
+    for suffix in [":all", ":id"].iter() {
+        let keys:Vec<String> = try!(redis::cmd("SCAN").cursor_arg(0)
+            .arg("MATCH").arg(format!("*{}", suffix))
+            .iter(r.conn())).collect();
+        // Keys of counters, sets and lists have the id in the middle.
+        classes.extend(keys.into_iter()
+                .map(|key| key[..key.len() - suffix.len()].to_string())
+                .filter(|class| !class.is_empty() && !class.contains(':')));
+    }
+    let mut classes = classes.into_iter().collect::<Vec<_>>();
+    classes.sort();
+    Ok(classes)
+}
+
+/// Writes every element as a line of JSON, as `Ohmer::to_json` does, with
+/// the current value of its counters. Returns how many elements were
+/// written.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, incr)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Counter};
+/// # use redis::Commands;
+/// model!(
+///     Song {
+///         title:String = "".to_string();
+///         plays:Counter = Counter;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Song:all").unwrap();
+/// let song = create!(Song { title: "Help!".to_string(), }, &client).unwrap();
+/// incr!(song.plays, 3, &client).unwrap();
+///
+/// let mut out = vec![];
+/// assert_eq!(ohmers::export_all::<Song, _>(&client, &mut out).unwrap(), 1);
+/// let line = String::from_utf8(out).unwrap();
+/// assert!(line.contains("\"title\":\"Help!\""));
+/// assert!(line.contains("\"plays\":3"));
+/// assert_eq!(&*Song::from_json(line.trim()).unwrap().title, "Help!");
+/// # }
+/// ```
+pub fn export_all<T: Ohmer, W: std::io::Write>(r: &OhmerConnection, writer: &mut W) -> Result<usize, OhmerError> {
+    let names = T::default().counters().into_iter().collect::<Vec<_>>();
+    let props = names.iter().map(|name| &**name).collect::<Vec<_>>();
+    let mut count = 0;
+    for obj in scan::<T>(r, 100) {
+        let obj = try!(obj);
+        let mut fields = try!(json_fields(&obj));
+        for (prop, value) in try!(counters(&obj, &*props, r)) {
+            fields.insert(prop, json::Json::I64(value));
+        }
+        try!(writeln!(writer, "{}", json::Json::Object(fields)).map_err(|e| OhmerError::IoError(format!("{}", e))));
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes `fields` of every element as CSV, a header line with the field
+/// names and then one row per element. Returns how many rows were
+/// written, not counting the header.
+///
+/// Values are written as they are stored: references as the id of their
+/// target, counters as their current value, booleans as `1` or `0`, and
+/// missing values as an empty cell. Elements are read from `<Class>:all`
+/// 100 at a time, each batch in one round trip, without decoding them.
+/// Models with `store_as_json` are not supported.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, incr)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Counter, Reference};
+/// # use redis::Commands;
+/// model!(
+///     Depot {
+///         city:String = "".to_string();
+///     });
+/// model!(
+///     Courier {
+///         name:String = "".to_string();
+///         depot:Reference<Depot> = Reference::new();
+///         deliveries:Counter = Counter;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Courier:all").unwrap();
+/// let depot = create!(Depot { city: "Lima".to_string(), }, &client).unwrap();
+/// let courier = create!(Courier { name: "Smith, \"J\"".to_string(), depot: Reference::with_value(&depot), }, &client).unwrap();
+/// incr!(courier.deliveries, 4, &client).unwrap();
+///
+/// let mut out = vec![];
+/// assert_eq!(ohmers::export_csv::<Courier, _>(&["id", "name", "depot", "deliveries"], &mut out, &client).unwrap(), 1);
+/// assert_eq!(String::from_utf8(out).unwrap(),
+///     format!("id,name,depot,deliveries\n{},\"Smith, \"\"J\"\"\",{},4\n", courier.id, depot.id));
+/// # }
+/// ```
+pub fn export_csv<T: Ohmer, W: std::io::Write>(fields: &[&str], writer: &mut W, r: &OhmerConnection) -> Result<usize, OhmerError> {
+    let default = T::default();
+    let class_name = default.get_class_name();
+    let id_field = default.id_field();
+    let counters = default.counters();
+    let references = try!(default.encoder()).references;
+    let io_error = |e: std::io::Error| OhmerError::IoError(format!("{}", e));
+
+    try!(writeln!(writer, "{}", fields.iter().map(|f| csv_cell(f)).collect::<Vec<_>>().join(","))
+            .map_err(&io_error));
+    let all = default.all_key();
+    let mut cursor = 0;
+    let mut count = 0;
+    loop {
+        let (next, ids):(u64, Vec<usize>) = try!(redis::cmd("SSCAN").arg(&*all).arg(cursor)
+                .arg("COUNT").arg(100).query(r.conn()));
+        if !ids.is_empty() {
+            let mut q = redis::pipe();
+            for id in ids.iter() {
+                let key = format!("{}:{}", class_name, id);
+                q.cmd("EXISTS").arg(&*key);
+                for field in fields.iter() {
+                    let reference = stored_name(&default, &*format!("{}_id", field));
+                    if counters.contains(*field) {
+                        q.cmd("GET").arg(format!("{}:{}", key, field));
+                    } else if references.contains(&reference) {
+                        q.cmd("HGET").arg(&*key).arg(reference);
+                    } else {
+                        q.cmd("HGET").arg(&*key).arg(stored_name(&default, field));
+                    }
+                }
+            }
+            let values:Vec<redis::Value> = try!(q.query(r.conn()));
+            for (id, row) in ids.iter().zip(values.chunks(fields.len() + 1)) {
+                // Deleted after the scan found it.
+                if row[0] == redis::Value::Int(0) {
+                    continue;
+                }
+                let cells = fields.iter().zip(row[1..].iter()).map(|(field, value)| {
+                    match *value {
+                        _ if *field == &*id_field => format!("{}", id),
+                        redis::Value::Data(ref data) => csv_cell(&*String::from_utf8_lossy(data)),
+                        redis::Value::Int(i) => format!("{}", i),
+                        _ if counters.contains(*field) => "0".to_string(),
+                        _ => "".to_string(),
+                    }
+                }).collect::<Vec<_>>();
+                try!(writeln!(writer, "{}", cells.join(",")).map_err(&io_error));
+                count += 1;
+            }
+        }
+        if next == 0 {
+            return Ok(count);
+        }
+        cursor = next;
+    }
+}
+
+/// Quotes a CSV cell if it has a comma, a quote or a line break.
+fn csv_cell(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The fields of an object as a JSON object with their decoded values.
+/// References are replaced by the id of their target, or null, and the
+/// properties stored in keys of their own are left out.
+fn json_fields<T: Ohmer>(obj: &T) -> Result<json::Object, OhmerError> {
+    let encoder = try!(obj.encoder());
+    let doc = try!(json::encode(obj).map_err(|_| EncoderError::JsonError));
+    let mut fields = match try!(json::Json::from_str(&*doc)) {
+        json::Json::Object(fields) => fields,
+        _ => return Err(EncoderError::JsonError.into()),
+    };
+    for name in encoder.counters.iter().chain(encoder.hll_counters.iter())
+            .chain(encoder.sets.iter()).chain(encoder.lists.iter())
+            .chain(encoder.maps.iter()).chain(encoder.collections.iter()) {
+        fields.remove(name);
+    }
+    let target_id = |fields: &json::Object, field: &str| fields.get(field)
+            .and_then(|value| value.find("id")).and_then(|id| id.as_u64()).unwrap_or(0);
+    let aliases = obj.field_aliases();
+    for reference in encoder.references.iter() {
+        let name = rename_field(&*aliases, reference, true);
+        let field = name[..name.len() - 3].to_string();
+        let id = target_id(&fields, &*field);
+        fields.insert(field, if id == 0 { json::Json::Null } else { json::Json::U64(id) });
+    }
+    // A `PolyReference` keeps its class and id, unless it is empty.
+    for field in encoder.poly_references.iter() {
+        if target_id(&fields, &**field) == 0 {
+            fields.insert(field.clone(), json::Json::Null);
+        }
+    }
+    Ok(fields)
+}
+
+/// Deletes every key of a class: the objects, their indices, uniques,
+/// counters, sets and lists, and the id sequence. Meant to clean up test
+/// fixtures, so it is only available with the `testing` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::Ohmer;
+/// # use redis::Commands;
+/// model!(
+///     Fixture {
+///         uniques { name:String = "".to_string(); };
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// ohmers::purge_class::<Fixture>(&client).unwrap();
+/// let fixture = create!(Fixture { name: "one".to_string(), }, &client).unwrap();
+/// assert_eq!(fixture.id, 1);
+/// ohmers::purge_class::<Fixture>(&client).unwrap();
+/// let fixture = create!(Fixture { name: "one".to_string(), }, &client).unwrap();
+/// assert_eq!(fixture.id, 1);
+/// # }
+/// ```
+#[cfg(feature = "testing")]
+pub fn purge_class<T: Ohmer>(r: &OhmerConnection) -> Result<(), OhmerError> {
+    try!(check_writable(r));
+    delete_matching(&*format!("{}:*", glob_escape(&*T::default().get_class_name())), r)
+}
+
+/// The name of a field as stored in Redis, without the `r#` prefix of raw
+/// identifiers. Used by `model!`.
+#[doc(hidden)]
+pub fn field_name(name: &'static str) -> &'static str {
+    if name.starts_with("r#") { &name[2..] } else { name }
+}
+
+/// Prefixes a class name with a hash tag, if any. Used by `model!`.
+#[doc(hidden)]
+pub fn tag_class_name(tag: Option<&str>, name: &str) -> String {
+    match tag {
+        Some(tag) => format!("{{{}}}{}", tag, name),
+        None => name.to_owned(),
+    }
+}
+
+/// Finds the counter fields by encoding the object. Used by `model!` to
+/// compute them once per type.
+#[doc(hidden)]
+pub fn encoded_counters<T: Ohmer>(obj: &T) -> HashSet<String> {
+    let mut encoder = Encoder::new();
+    obj.encode(&mut encoder).unwrap();
+    encoder.counters
+}
+
+/// Structs that can be stored in and retrieved from Redis.
+/// You can use the `model!` macro as a helper.
+pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Default + Sized {
+    /// The name of the field storing the unique auto increment identifier,
+    /// `id` by default. Set it when the struct names the field differently.
+    ///
+    /// The name only matters to the encoding of the struct: the id is not
+    /// stored in the hash, but in its key, `<Class>:<id>`. The Lua scripts
+    /// always receive it as `id`, and `to_json` and `from_json` use the
+    /// field name. References are always stored as `<field>_id`, whatever
+    /// the name of the id field of the referenced class.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// model!(
+    ///     Legacy(uid) {
+    ///         name:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut legacy = create!(Legacy { name: "old".to_string(), }, &client).unwrap();
+    /// assert!(legacy.uid > 0);
+    /// assert_eq!(&*legacy.id_field(), "uid");
+    ///
+    /// legacy.name = "older".to_string();
+    /// legacy.save(&client).unwrap();
+    /// let loaded = ohmers::get::<Legacy>(legacy.uid, &client).unwrap();
+    /// assert_eq!(loaded.uid, legacy.uid);
+    /// assert_eq!(&*loaded.name, "older");
+    /// # }
+    /// ```
+    fn id_field(&self) -> String { "id".to_string() }
+
+    /// The object unique identifier. It is 0 if it was not saved yet.
+    fn id(&self) -> usize;
+    /// Sets the object unique identifier. It should not be called manually,
+    /// it is set after save.
+    fn set_id(&mut self, id: usize);
 
     /// Fields with a unique index.
     fn unique_fields<'a>(&self) -> HashSet<&'a str> { HashSet::new() }
@@ -703,6 +2647,103 @@ pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Defa
     /// Fields with an index.
     fn index_fields<'a>(&self) -> HashSet<&'a str> { HashSet::new() }
 
+    /// Values to index that are not stored in a field, as pairs of an
+    /// index name and a value, like whether a person is an adult from
+    /// their age. They are indexed by `save` next to the fields in
+    /// `index_fields`, go through `index_value`, and can be queried like
+    /// them. A name may repeat to index several values. By default there
+    /// are none.
+    ///
+    /// They are computed from the whole object, so `set_field` does not
+    /// update them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+    /// struct Voter {
+    ///     id: usize,
+    ///     age: u8,
+    /// }
+    /// impl Ohmer for Voter {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn computed_indices(&self) -> Vec<(String, String)> {
+    ///         vec![("adult".to_string(), if self.age >= 18 { "1" } else { "0" }.to_string())]
+    ///     }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Voter:indices:adult:1").unwrap();
+    /// let mut voter = Voter { age: 17, ..Default::default() };
+    /// voter.save(&client).unwrap();
+    /// assert!(Query::<Voter>::find("adult", "1", &client).is_empty().unwrap());
+    ///
+    /// voter.age = 18;
+    /// voter.save(&client).unwrap();
+    /// assert_eq!(Query::<Voter>::find("adult", "1", &client).into_vec().unwrap(), vec![voter]);
+    /// # }
+    /// ```
+    fn computed_indices(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Shorter names to store fields under in the hash, as pairs of the
+    /// field and its alias, to save memory when there are many objects.
+    /// The struct keeps the field names; indices, uniques and sorting use
+    /// the alias in their keys, and references are stored as
+    /// `<alias>_id`. Aliases must not collide with other fields. By
+    /// default there are none. Models with `store_as_json` do not use
+    /// them. The inverse field of a `one_to_one` triple is written as it
+    /// is stored in the target model, so use its alias if it has one.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use std::collections::HashSet;
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+    /// struct Registration {
+    ///     id: usize,
+    ///     registration_date: String,
+    ///     country_code: String,
+    /// }
+    /// impl Ohmer for Registration {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn index_fields<'a>(&self) -> HashSet<&'a str> { vec!["country_code"].into_iter().collect() }
+    ///     fn field_aliases(&self) -> Vec<(&'static str, &'static str)> {
+    ///         vec![("registration_date", "rd"), ("country_code", "cc")]
+    ///     }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Registration:indices:cc:PE").unwrap();
+    /// let mut registration = Registration { registration_date: "2016-01-31".to_string(),
+    ///     country_code: "PE".to_string(), ..Default::default() };
+    /// registration.save(&client).unwrap();
+    ///
+    /// let fields = ohmers::raw::<Registration>(registration.id, &client).unwrap();
+    /// assert_eq!(&*fields["rd"], "2016-01-31");
+    /// assert!(!fields.contains_key("registration_date"));
+    /// assert_eq!(ohmers::get::<Registration>(registration.id, &client).unwrap(), registration);
+    /// assert_eq!(Query::<Registration>::find("country_code", "PE", &client).into_vec().unwrap(),
+    ///     vec![registration]);
+    /// # }
+    /// ```
+    fn field_aliases(&self) -> Vec<(&'static str, &'static str)> {
+        vec![]
+    }
+
     /// Redis key to find an element with a unique index field value.
     fn key_for_unique(&self, field: &str, value: &str) -> String {
         format!("{}:uniques:{}:{}", self.get_class_name(), field, value)
@@ -713,44 +2754,364 @@ pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Defa
         format!("{}:indices:{}:{}", self.get_class_name(), field, value)
     }
 
+    /// Transforms a value before it is used in the index of `field`, both
+    /// when saving and when querying. By default values are indexed as
+    /// they are. `model!` overrides it for indices declared with
+    /// `=> transform`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Member {
+    ///         indices {
+    ///             email:String = "".to_string() => str::to_lowercase;
+    ///         };
+    ///         name:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Member:indices:email:alice@x.com").unwrap();
+    /// let alice = create!(Member { email: "Alice@X.com".to_string(), }, &client).unwrap();
+    /// assert_eq!(&*ohmers::get::<Member>(alice.id, &client).unwrap().email, "Alice@X.com");
+    /// assert_eq!(Query::<Member>::find("email", "ALICE@x.com", &client).try_iter().unwrap()
+    ///     .collect::<Vec<_>>(), vec![alice]);
+    /// # }
+    /// ```
+    fn index_value(&self, _field: &str, value: &str) -> String {
+        value.to_string()
+    }
+
+    /// Redis key of the hash with the object fields, `<Class>:<id>`. It
+    /// fails with `NotSaved` if the object was not saved yet.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, OhmerError};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Gadget {
+    ///         name:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// assert_eq!(Gadget::default().key(), Err(OhmerError::NotSaved));
+    /// assert_eq!(Gadget::default().all_key(), "Gadget:all");
+    /// assert_eq!(Gadget::default().id_key(), "Gadget:id");
+    ///
+    /// let gadget = create!(Gadget { name: "Sonic screwdriver".to_string(), }, &client).unwrap();
+    /// assert_eq!(gadget.key().unwrap(), format!("Gadget:{}", gadget.id));
+    /// let name:String = client.hget(gadget.key().unwrap(), "name").unwrap();
+    /// assert_eq!(&*name, "Sonic screwdriver");
+    /// # }
+    /// ```
+    fn key(&self) -> Result<String, OhmerError> {
+        match self.id() {
+            0 => Err(OhmerError::NotSaved),
+            id => Ok(format!("{}:{}", self.get_class_name(), id)),
+        }
+    }
+
+    /// Redis key of the set with the ids of all the objects, `<Class>:all`.
+    fn all_key(&self) -> String {
+        format!("{}:all", self.get_class_name())
+    }
+
+    /// Redis key of the counter used to assign ids, `<Class>:id`.
+    fn id_key(&self) -> String {
+        format!("{}:id", self.get_class_name())
+    }
+
+    /// Redis key of the lock taken by `try_lock`, `<Class>:lock:<id>`. It
+    /// fails with `NotSaved` if the object was not saved yet.
+    fn lock_key(&self) -> Result<String, OhmerError> {
+        match self.id() {
+            0 => Err(OhmerError::NotSaved),
+            id => Ok(format!("{}:lock:{}", self.get_class_name(), id)),
+        }
+    }
+
+    /// Name used in the keys of the `List` and `Set` property `property`,
+    /// `<Class>:<name>:<id>`, and in the reference looked up by a
+    /// `Collection`. By default it is the property name itself. Override it
+    /// to share data with models that name things differently, like an Ohm
+    /// model written in Ruby.
+    ///
+    /// For a `Collection`, `property` is the name of the reference in the
+    /// other class, which `collection!` assumes is the class name.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(insert)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Set};
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Tag {
+    ///     id: usize,
+    /// }
+    /// impl Ohmer for Tag {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    /// }
+    ///
+    /// #[derive(RustcEncodable, RustcDecodable, Debug)]
+    /// struct Article {
+    ///     id: usize,
+    ///     tag_set: Set<Tag>,
+    /// }
+    /// impl Default for Article {
+    ///     fn default() -> Self {
+    ///         Article { id: 0, tag_set: Set::new() }
+    ///     }
+    /// }
+    /// impl Ohmer for Article {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn property_key(&self, property: &str) -> String {
+    ///         match property {
+    ///             "tag_set" => "tags".to_string(),
+    ///             _ => property.to_string(),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut tag = Tag::default();
+    /// tag.save(&client).unwrap();
+    /// let mut article = Article::default();
+    /// article.save(&client).unwrap();
+    /// insert!(article.tag_set, tag, &client).unwrap();
+    ///
+    /// let ids:Vec<usize> = client.smembers(format!("Article:tags:{}", article.id)).unwrap();
+    /// assert_eq!(ids, vec![tag.id]);
+    /// # }
+    /// ```
+    fn property_key(&self, property: &str) -> String {
+        property.to_string()
+    }
+
     /// Name of all the fields that are counters. Counters are stored
     /// independently to keep atomicity in its operations.
     fn counters(&self) -> HashSet<String> {
-        let mut encoder = Encoder::new();
-        self.encode(&mut encoder).unwrap();
-        encoder.counters
+        encoded_counters(self)
     }
 
-    /// Object name used in the database.
+    /// Object name used in the database, prefixed with the `key_tag` if
+    /// there is one.
     fn get_class_name(&self) -> String {
         let mut encoder = Encoder::new();
         self.encode(&mut encoder).unwrap();
-        encoder.features.remove("name").unwrap()
+        tag_class_name(self.key_tag(), &*encoder.features.remove("name").unwrap())
+    }
+
+    /// A Redis Cluster hash tag for the keys of this class. When set, the
+    /// class name becomes `{tag}Class`, and since every key of the class
+    /// (the objects, `:all`, `:id`, indices, uniques, sets, lists, maps and
+    /// counters) and every key the Lua scripts write starts with it, they
+    /// all land in the same hash slot. `model!` sets it with a
+    /// `#[key_tag = "..."]` prefix. Class names given by hand, such as
+    /// `shared_index_class` or `one_to_one` targets, must include the tag
+    /// of the class they name.
+    ///
+    /// ```rust,no_run
+    /// # #[macro_use(model)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// model!(
+    ///     #[key_tag = "shop"]
+    ///     Order {
+    ///         indices { status:String = "open".to_string(); };
+    ///         total:u32 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut order = Order::default();
+    /// order.save(&client).unwrap();
+    /// assert_eq!(order.get_class_name(), "{shop}Order");
+    /// assert_eq!(order.key(), format!("{{shop}}Order:{}", order.id));
+    /// assert_eq!(order.key_for_index("status", "open"), "{shop}Order:indices:status:open");
+    /// # }
+    /// ```
+    fn key_tag(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The version of the schema of this model, stored with every object
+    /// it saves in the `_schema` field of the hash, unless it is 0, the
+    /// default. Increase it when a change needs the objects saved before
+    /// to be migrated, and handle the change in `migrate`.
+    fn schema_version(&self) -> u32 {
+        0
+    }
+
+    /// Transforms the fields of an object saved with an older
+    /// `schema_version`, `version`, before it is decoded. Objects saved
+    /// before the model had a version have version 0. By default nothing
+    /// changes.
+    ///
+    /// Objects are migrated when they are loaded, and stored with the
+    /// current version the next time they are saved. Objects stored as
+    /// RedisJSON documents are not migrated.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use std::collections::HashMap;
+    /// # use ohmers::Ohmer;
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Patient {
+    ///     id: usize,
+    ///     first_name: String,
+    /// }
+    /// impl Ohmer for Patient {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn schema_version(&self) -> u32 { 1 }
+    ///     fn migrate(&self, raw: &mut HashMap<String, String>, version: u32) {
+    ///         if version < 1 {
+    ///             if let Some(name) = raw.remove("fname") {
+    ///                 raw.insert("first_name".to_string(), name);
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let id:usize = client.incr("Patient:id", 1).unwrap();
+    /// let _:bool = client.hset(format!("Patient:{}", id), "fname", "Ada").unwrap();
+    /// let _:bool = client.sadd("Patient:all", id).unwrap();
+    ///
+    /// let mut patient = ohmers::get::<Patient>(id, &client).unwrap();
+    /// assert_eq!(&*patient.first_name, "Ada");
+    ///
+    /// patient.save(&client).unwrap();
+    /// let version:String = client.hget(format!("Patient:{}", id), "_schema").unwrap();
+    /// assert_eq!(&*version, "1");
+    /// # }
+    /// ```
+    fn migrate(&self, _raw: &mut HashMap<String, String>, _version: u32) {
     }
 
     /// Loads an object by id.
-    fn load(&mut self, id: usize, r: &redis::Client) -> Result<(), DecoderError> {
-        let mut properties:HashMap<String, String> = try!(try!(r.get_connection()).hgetall(format!("{}:{}", self.get_class_name(), id)));
-        properties.insert("id".to_string(), format!("{}", id));
+    fn load(&mut self, id: usize, r: &OhmerConnection) -> Result<(), DecoderError> {
+        #[cfg(feature = "redisjson")]
+        {
+            if self.store_as_json() {
+                return self.load_json(id, r);
+            }
+        }
+        let key = format!("{}:{}", self.get_class_name(), id);
+        let properties:HashMap<String, String> = try!(timed(r, "load", || redis::cmd("HGETALL").arg(&*key).query(r.conn())));
+        *self = try!(decode_properties(self, id, properties));
+        Ok(())
+    }
 
-        let mut decoder = Decoder::new(properties);
-        *self = try!(rustc_serialize::Decodable::decode(&mut decoder));
+    /// Loads an object stored as a RedisJSON document.
+    #[cfg(feature = "redisjson")]
+    fn load_json(&mut self, id: usize, r: &OhmerConnection) -> Result<(), DecoderError> {
+        let key = format!("{}:{}", self.get_class_name(), id);
+        let doc:Option<String> = try!(timed(r, "load", || redis::cmd("JSON.GET").arg(&*key).query(r.conn())));
+        match doc {
+            Some(doc) => *self = try!(decode_json(id, &*doc)),
+            None => return Err(DecoderError::ExpectedError("JSON object".to_string(), "Not found".to_string())),
+        }
         Ok(())
     }
 
+    /// Reads only `fields` from the hash of this object, as they are stored
+    /// in Redis, without loading the rest. Fields without a value are left
+    /// out of the map. Counters, sets and lists are not in the hash, and
+    /// references are stored as `<field>_id`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// model!(
+    ///     Experiment {
+    ///         name:String = "".to_string();
+    ///         variant:String = "".to_string();
+    ///         weight:u8 = 0;
+    ///         notes:Option<String> = None;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let exp = create!(Experiment { name: "button".to_string(), variant: "blue".to_string(), weight: 30, }, &client).unwrap();
+    /// let fields = exp.get_fields(&["variant", "weight", "notes"], &client).unwrap();
+    /// assert_eq!(fields.len(), 2);
+    /// assert_eq!(fields["variant"], "blue");
+    /// assert_eq!(fields["weight"], "30");
+    /// # }
+    /// ```
+    fn get_fields(&self, fields: &[&str], r: &OhmerConnection) -> Result<HashMap<String, String>, OhmerError> {
+        let key = try!(self.key());
+        if fields.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let values:Vec<Option<String>> = try!(redis::cmd("HMGET").arg(key).arg(fields).query(r.conn()));
+        Ok(fields.iter().zip(values.into_iter())
+                .filter_map(|(field, value)| value.map(|value| (field.to_string(), value)))
+                .collect())
+    }
+
     /// Serializes this object.
     fn encoder(&self) -> Result<Encoder, OhmerError> {
         let mut encoder = Encoder::new();
         encoder.id_field = self.id_field();
+        #[cfg(feature = "redisjson")]
+        {
+            encoder.embed = self.store_as_json();
+        }
         try!(self.encode(&mut encoder));
+        encoder.features.insert("name".to_string(), self.get_class_name());
+        let aliases = self.field_aliases();
+        if !aliases.is_empty() {
+            for pair in encoder.attributes.chunks_mut(2) {
+                pair[0] = rename_field(&*aliases, &*pair[0], false);
+            }
+            encoder.nil_attributes = encoder.nil_attributes.iter()
+                    .map(|name| rename_field(&*aliases, name, false)).collect();
+            encoder.references = encoder.references.iter()
+                    .map(|name| rename_field(&*aliases, name, false)).collect();
+        }
         Ok(encoder)
     }
 
     /// Grabs all the uniques and indices from this object.
     fn uniques_indices(&self, encoder: &Encoder
             ) -> Result<(HashMap<String, String>, HashMap<String, Vec<String>>), OhmerError> {
-        let mut unique_fields = self.unique_fields();
-        let mut index_fields = self.index_fields();
+        let mut unique_fields = self.unique_fields().into_iter()
+                .map(|field| stored_name(self, field)).collect::<HashSet<_>>();
+        if let Some(field) = self.string_id_field() {
+            unique_fields.insert(stored_name(self, field));
+        }
+        let mut index_fields = self.index_fields().into_iter()
+                .map(|field| stored_name(self, field)).collect::<HashSet<_>>();
+        if let Some(field) = self.soft_delete_field() {
+            index_fields.insert(stored_name(self, field));
+        }
+        let aliases = self.field_aliases();
         let mut uniques = HashMap::new();
         let mut indices = HashMap::new();
 
@@ -761,7 +3122,8 @@ pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Defa
                 uniques.insert(key.clone(), encoder.attributes[pos + 1].clone());
             }
             if index_fields.remove(&**key) {
-                indices.insert(key.clone(), vec![encoder.attributes[pos + 1].clone()]);
+                let field = rename_field(&*aliases, key, true);
+                indices.insert(key.clone(), vec![self.index_value(&*field, &encoder.attributes[pos + 1])]);
             } else if key.len() > 3 && &key[key.len() - 3..] == "_id" &&
                 index_fields.remove(&key[..key.len() - 3]) {
                 indices.insert(key.clone(), vec![encoder.attributes[pos + 1].clone()]);
@@ -770,45 +3132,653 @@ pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Defa
         if unique_fields.len() > 0 {
             return Err(OhmerError::UnknownIndex(unique_fields.iter().next().unwrap().to_string()));
         }
+        for (field, value) in self.computed_indices() {
+            let value = self.index_value(&*field, &*value);
+            indices.entry(field).or_insert_with(Vec::new).push(value);
+        }
+        // A soft-deleted object is only in the index of deleted objects.
+        if let Some(field) = self.soft_delete_field() {
+            let stored = stored_name(self, field);
+            if encoder.attributes.chunks(2).any(|pair| pair[0] == stored && pair[1] == "1") {
+                uniques.clear();
+                indices.retain(|name, _| *name == stored);
+            }
+        }
         Ok((uniques, indices))
 
     }
 
+    /// Whether saving adds the id to `<Class>:all`. Return `false` for
+    /// models that are written but never listed, like an event log, to
+    /// save the memory of that set.
+    ///
+    /// Everything reading `<Class>:all` sees no objects of such a model:
+    /// `all`, `all_query`, `scan`, `export_all` and `Query::missing`, and
+    /// `reindex` fails with `Untracked`. Ids returned by `generate_id` are
+    /// not checked to be unused either. Objects can still be loaded with
+    /// `get`, through references and the batch loaders like `preload`, and
+    /// found through their indices.
+    fn track_all(&self) -> bool {
+        true
+    }
+
+    /// The name of a class shared with other models, like `Animal` for
+    /// `Cat` and `Dog`, where the indices of this model are written too.
+    /// The shared sets, `<Shared>:indices:<field>:<value>`, hold
+    /// `<Class>:<id>` instead of the id, since ids of different classes
+    /// collide. Use `find_shared` to query them. By default there is none.
+    ///
+    /// Shared indices are kept by `save`, `save_all` and `delete`, but not
+    /// by `create_many` and `set_field`; `reindex` fixes them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use std::collections::HashSet;
+    /// # use ohmers::Ohmer;
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Horse {
+    ///     id: usize,
+    ///     age: u8,
+    /// }
+    /// impl Ohmer for Horse {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn index_fields<'a>(&self) -> HashSet<&'a str> { vec!["age"].into_iter().collect() }
+    ///     fn shared_index_class(&self) -> Option<&'static str> { Some("Livestock") }
+    /// }
+    ///
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Goat {
+    ///     id: usize,
+    ///     age: u8,
+    /// }
+    /// impl Ohmer for Goat {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn index_fields<'a>(&self) -> HashSet<&'a str> { vec!["age"].into_iter().collect() }
+    ///     fn shared_index_class(&self) -> Option<&'static str> { Some("Livestock") }
+    /// }
+    ///
+    /// enum Animal {
+    ///     Horse(Horse),
+    ///     Goat(Goat),
+    /// }
+    ///
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:() = redis::cmd("DEL").arg("Livestock:indices:age:3").query(&client).unwrap();
+    /// let mut horse = Horse { age: 3, ..Default::default() };
+    /// horse.save(&client).unwrap();
+    /// let mut goat = Goat { age: 3, ..Default::default() };
+    /// goat.save(&client).unwrap();
+    ///
+    /// let animals = ohmers::find_shared("Livestock", &[("age", "3")], &client).unwrap()
+    ///     .into_iter().map(|(class, id)| match &*class {
+    ///         "Horse" => Animal::Horse(ohmers::get(id, &client).unwrap()),
+    ///         _ => Animal::Goat(ohmers::get(id, &client).unwrap()),
+    ///     }).collect::<Vec<_>>();
+    /// assert_eq!(animals.len(), 2);
+    ///
+    /// goat.delete(&client).unwrap();
+    /// assert_eq!(ohmers::find_shared("Livestock", &[("age", "3")], &client).unwrap(),
+    ///         vec![("Horse".to_string(), horse.id)]);
+    /// # }
+    /// ```
+    fn shared_index_class(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// References that are one-to-one, as triples of the field, the class
+    /// of its target and the field of the target referencing back, like
+    /// `("holder", "Citizen", "passport")` for a `Passport`. By default
+    /// there are none.
+    ///
+    /// `save` keeps both sides paired in the same script that writes the
+    /// object: the target gets a reference back, the previous target and
+    /// the object the new target pointed to are left pointing to nothing.
+    /// `delete` clears the reference back too. Declare the relation on
+    /// both models, and do not index the fields, since the script changes
+    /// them without updating their indices. `save_all` and `create_many`
+    /// pair them too, `set_field` leaves the other side alone, and models
+    /// with `store_as_json` are not supported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Reference, get};
+    /// #[derive(RustcEncodable, RustcDecodable, Debug)]
+    /// struct Citizen {
+    ///     id: usize,
+    ///     name: String,
+    ///     passport: Reference<Passport>,
+    /// }
+    /// impl Default for Citizen {
+    ///     fn default() -> Self { Citizen { id: 0, name: "".to_string(), passport: Reference::new() } }
+    /// }
+    /// impl Ohmer for Citizen {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn one_to_one(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+    ///         vec![("passport", "Passport", "holder")]
+    ///     }
+    /// }
+    ///
+    /// #[derive(RustcEncodable, RustcDecodable, Debug)]
+    /// struct Passport {
+    ///     id: usize,
+    ///     holder: Reference<Citizen>,
+    /// }
+    /// impl Default for Passport {
+    ///     fn default() -> Self { Passport { id: 0, holder: Reference::new() } }
+    /// }
+    /// impl Ohmer for Passport {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn one_to_one(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+    ///         vec![("holder", "Citizen", "passport")]
+    ///     }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut ann = Citizen { name: "Ann".to_string(), ..Default::default() };
+    /// ann.save(&client).unwrap();
+    /// let mut bob = Citizen { name: "Bob".to_string(), ..Default::default() };
+    /// bob.save(&client).unwrap();
+    ///
+    /// let mut first = Passport { holder: Reference::with_value(&ann), ..Default::default() };
+    /// first.save(&client).unwrap();
+    /// assert_eq!(get::<Citizen>(ann.id, &client).unwrap().passport.id(), first.id);
+    ///
+    /// // Reassigned: Ann no longer has it.
+    /// first.holder.set(&bob);
+    /// first.save(&client).unwrap();
+    /// assert_eq!(get::<Citizen>(ann.id, &client).unwrap().passport.id(), 0);
+    /// assert_eq!(get::<Citizen>(bob.id, &client).unwrap().passport.id(), first.id);
+    ///
+    /// // Bob gets a new one: the first belongs to nobody.
+    /// let mut second = Passport { holder: Reference::with_value(&bob), ..Default::default() };
+    /// second.save(&client).unwrap();
+    /// assert_eq!(get::<Passport>(first.id, &client).unwrap().holder.id(), 0);
+    ///
+    /// // Created in bulk: Ann gets the third one.
+    /// let mut third = vec![Passport { holder: Reference::with_value(&ann), ..Default::default() }];
+    /// ohmers::create_many(&mut third, &client).unwrap();
+    /// assert_eq!(get::<Citizen>(ann.id, &client).unwrap().passport.id(), third[0].id);
+    ///
+    /// second.delete(&client).unwrap();
+    /// assert_eq!(get::<Citizen>(bob.id, &client).unwrap().passport.id(), 0);
+    /// # }
+    /// ```
+    fn one_to_one(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+        vec![]
+    }
+
+    /// Whether saving keeps the fields of the hash that this model does not
+    /// declare. By default the hash is replaced, like Ohm does, and fields
+    /// written by other clients are lost.
+    ///
+    /// Return `true` when other programs, or other versions of this one,
+    /// store fields of their own in the same hash. Saving then only sets
+    /// the declared fields and removes the ones that are `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Profile {
+    ///     id: usize,
+    ///     name: String,
+    ///     bio: Option<String>,
+    /// }
+    /// impl Ohmer for Profile {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn preserve_unknown_fields(&self) -> bool { true }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut profile = Profile { bio: Some("hi".to_string()), ..Default::default() };
+    /// profile.save(&client).unwrap();
+    /// let _:bool = client.hset(profile.key().unwrap(), "avatar", "cat.png").unwrap();
+    ///
+    /// profile.name = "Alice".to_string();
+    /// profile.bio = None;
+    /// profile.save(&client).unwrap();
+    /// let avatar:String = client.hget(profile.key().unwrap(), "avatar").unwrap();
+    /// assert_eq!(&*avatar, "cat.png");
+    /// let bio:Option<String> = client.hget(profile.key().unwrap(), "bio").unwrap();
+    /// assert_eq!(bio, None);
+    /// # }
+    /// ```
+    fn preserve_unknown_fields(&self) -> bool {
+        false
+    }
+
+    /// Whether the object is stored as a RedisJSON document under
+    /// `<Class>:<id>` instead of a hash. It requires the RedisJSON module,
+    /// included in Redis Stack, and the `redisjson` feature.
+    ///
+    /// The document is the object encoded as JSON without the id, which is
+    /// already in the key, so fields keep their types and may be nested
+    /// structs, which a hash cannot hold. Nested structs are only in the
+    /// document: they cannot be indexed or unique. Indices, uniques,
+    /// counters, sets and lists of the other fields work as with a hash.
+    ///
+    /// `save`, `load`, `get`, `find`, `delete`, the bulk writes
+    /// `create_many` and `save_all`, and everything loading many objects at
+    /// once (`Query::into_vec`, `Query::each`, `preload`,
+    /// `Reference::get_many`) know about documents. Everything reading the
+    /// hash directly fails with a wrong type error: `raw`, `get_fields`,
+    /// `set_field`, and sorting by a field.
+    #[cfg(feature = "redisjson")]
+    fn store_as_json(&self) -> bool {
+        false
+    }
+
+    /// Generates the id for a new object. By default it returns `None` and
+    /// ids are assigned sequentially from `<Class>:id`.
+    ///
+    /// Override it to use unpredictable ids, for example random numbers.
+    /// Saving fails with `IdInUse` if the generated id is already taken.
+    /// Ids are always integers, since `Reference`, `Set`, `List` and the
+    /// Lua scripts store them as such; see `string_id_field` for UUID or
+    /// ULID strings.
+    fn generate_id(&self, _r: &OhmerConnection) -> Result<Option<usize>, OhmerError> {
+        Ok(None)
+    }
+
+    /// The `String` field holding a string id, like a UUID or ULID, for
+    /// models whose ids are shown outside the application and should not
+    /// be sequential. By default there is none.
+    ///
+    /// The object keeps its integer id, which references, sets, lists and
+    /// keys still use, and the string id is a unique index mapping to it:
+    /// load objects by it with `get_by_string_id`. `save` fills an empty
+    /// field with `generate_string_id`, and fails with
+    /// `UniqueIndexViolation` if another object has the same string id.
+    /// `save_all` and `create_many` do not generate them.
+    fn string_id_field(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Generates the string id of an object whose `string_id_field` is
+    /// empty when it is saved. By default it returns `None` and saving
+    /// fails with `MissingStringId`; ohmers does not generate UUIDs or
+    /// ULIDs itself, so override it with the crate of your choice.
+    fn generate_string_id(&self, _r: &OhmerConnection) -> Result<Option<String>, OhmerError> {
+        Ok(None)
+    }
+
+    /// How `save` serializes the arguments of its Lua script. MessagePack
+    /// by default, like Ohm. `save_script` must decode the same encoding.
+    ///
+    /// `create_many`, `save_all` and `delete` use it too, with their
+    /// bundled scripts adapted to it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, ScriptEncoding};
+    /// #[derive(RustcEncodable, RustcDecodable, Debug, Default)]
+    /// struct Trace {
+    ///     id: usize,
+    ///     step: String,
+    /// }
+    /// impl Ohmer for Trace {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn script_encoding(&self) -> ScriptEncoding { ScriptEncoding::Json }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut trace = Trace { step: "parse".to_string(), ..Default::default() };
+    /// trace.save(&client).unwrap();
+    /// assert_eq!(&*ohmers::get::<Trace>(trace.id, &client).unwrap().step, "parse");
+    ///
+    /// let mut traces = vec![Trace { step: "lex".to_string(), ..Default::default() }];
+    /// ohmers::create_many(&mut traces, &client).unwrap();
+    /// traces[0].step = "emit".to_string();
+    /// ohmers::save_all(&traces, &client).unwrap();
+    /// assert_eq!(&*ohmers::get::<Trace>(traces[0].id, &client).unwrap().step, "emit");
+    /// let id = traces[0].id;
+    /// traces.pop().unwrap().delete(&client).unwrap();
+    /// assert!(ohmers::find::<Trace>(id, &client).unwrap().is_none());
+    /// # }
+    /// ```
+    fn script_encoding(&self) -> ScriptEncoding {
+        ScriptEncoding::MsgPack
+    }
+
+    /// The Lua script run by `save`. By default, the one bundled with
+    /// ohmers, adapted to `script_encoding`.
+    ///
+    /// A patched script receives the same arguments: the model, the
+    /// attributes, the indices and the uniques, plus the removed fields
+    /// when `preserve_unknown_fields` is set, all encoded with
+    /// `script_encoding`. It must return the id of the object.
+    /// `create_many`, `save_all` and `delete` run their own bundled
+    /// scripts, adapted to `script_encoding` as well.
+    fn save_script(&self) -> String {
+        self.script_encoding().adapt(SAVE)
+    }
+
     /// Saves the object in the database, and sets the instance `id` if it was
-    /// not set.
-    fn save(&mut self, r: &redis::Client) -> Result<(), OhmerError> {
+    /// not set. Returns whether the object was created or updated.
+    fn save(&mut self, r: &OhmerConnection) -> Result<SaveOutcome, OhmerError> {
+        save_only(self, None, r)
+    }
+
+    /// Saves the object only if there is no object stored with its id yet.
+    /// Fails with `AlreadyExists` otherwise. The check and the write run
+    /// in the same script, so no other client can create the object in
+    /// between. An object without an id is always new and is just saved.
+    ///
+    /// Unique fields are still checked against every other object: a
+    /// value taken by another id fails with `UniqueIndexViolation`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, OhmerError};
+    /// model!(
+    ///     Voucher {
+    ///         amount:u32 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let mut voucher = Voucher::default();
+    /// voucher.save(&client).unwrap();
+    /// let id = voucher.id;
+    ///
+    /// let mut copy = Voucher::default();
+    /// copy.id = id;
+    /// assert_eq!(copy.save_if_absent(&client), Err(OhmerError::AlreadyExists(id)));
+    ///
+    /// let mut missing = Voucher::default();
+    /// missing.id = id + 1000000;
+    /// assert_eq!(missing.save_if_present(&client), Err(OhmerError::NotFound(id + 1000000)));
+    /// assert_eq!(ohmers::find::<Voucher>(id + 1000000, &client).unwrap(), None);
+    ///
+    /// voucher.amount = 20;
+    /// voucher.save_if_present(&client).unwrap();
+    /// assert_eq!(ohmers::get::<Voucher>(id, &client).unwrap().amount, 20);
+    /// # }
+    /// ```
+    fn save_if_absent(&mut self, r: &OhmerConnection) -> Result<SaveOutcome, OhmerError> {
+        save_only(self, Some("absent"), r)
+    }
+
+    /// Saves the object only if it is already stored, failing with
+    /// `NotFound` otherwise, and with `NotSaved` if it has no id. The
+    /// check and the write run in the same script, so an object deleted
+    /// by another client is not created again. Unique fields are checked
+    /// like in `save`. See `save_if_absent`.
+    fn save_if_present(&mut self, r: &OhmerConnection) -> Result<SaveOutcome, OhmerError> {
+        if self.id() == 0 {
+            return Err(OhmerError::NotSaved);
+        }
+        save_only(self, Some("present"), r)
+    }
+
+    /// Saves a new object and returns it. The builders generated by
+    /// `model!` use it to finish a chain of setters.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// model!(
+    ///     Account {
+    ///         name:String = "".to_string();
+    ///         email:String = "nobody@example.com".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let email = None;
+    /// let account = AccountBuilder::default()
+    ///     .name("Alice".to_string())
+    ///     .maybe_email(email)
+    ///     .create(&client).unwrap();
+    /// assert!(account.id > 0);
+    /// assert_eq!(&*account.email, "nobody@example.com");
+    /// # }
+    /// ```
+    fn create(mut self, r: &OhmerConnection) -> Result<Self, OhmerError> {
+        try!(self.save(r));
+        Ok(self)
+    }
+
+    /// The fields of the object as `save` writes them to its hash, plus
+    /// the id. Fields with no value, like a `None`, are left out.
+    fn to_map(&self) -> Result<HashMap<String, String>, OhmerError> {
         let encoder = try!(self.encoder());
-        let (uniques, indices) = try!(self.uniques_indices(&encoder));
-        let script = redis::Script::new(SAVE);
-        let result = script
-                .arg(try!(msgpack_encode(&encoder.features)))
-                .arg(try!(msgpack_encode(&encoder.attributes.iter().map(|x| &*x).collect::<Vec<_>>())))
-                .arg(try!(msgpack_encode(&indices)))
-                .arg(try!(msgpack_encode(&uniques)))
-                .invoke(&try!(r.get_connection()));
-        let id = match result {
-            Ok(id) => id,
-            Err(e) => {
-                let re = Regex::new(r"UniqueIndexViolation: (\w+)").unwrap();
-                let s = format!("{}", e);
-                match re.find(&*s) {
-                    Some((start, stop)) => return Err(OhmerError::UniqueIndexViolation(s[start + 22..stop].to_string())),
-                    None => return Err(OhmerError::RedisError(e)),
-                }
-            },
+        let mut map = encoder.attributes.chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect::<HashMap<_, _>>();
+        map.insert(encoder.id_field, format!("{}", self.id()));
+        Ok(map)
+    }
+
+    /// Creates an object from its fields as they are stored in Redis, like
+    /// the ones `raw` or `to_map` return, the same way `get` does but
+    /// without reading them from the server. The id is taken from the id
+    /// field, and is 0 if there is none.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// # use std::collections::HashMap;
+    /// model!(
+    ///     Airport {
+    ///         code:String = "".to_string();
+    ///         international:bool = false;
+    ///     });
+    /// # fn main() {
+    /// let mut fields = HashMap::new();
+    /// fields.insert("id".to_string(), "4".to_string());
+    /// fields.insert("code".to_string(), "LIM".to_string());
+    /// fields.insert("international".to_string(), "1".to_string());
+    ///
+    /// let airport = Airport::from_map(fields.clone()).unwrap();
+    /// assert_eq!(airport.id, 4);
+    /// assert_eq!(&*airport.code, "LIM");
+    /// assert!(airport.international);
+    /// assert_eq!(airport.to_map().unwrap(), fields);
+    /// # }
+    /// ```
+    fn from_map(map: HashMap<String, String>) -> Result<Self, DecoderError> {
+        let default = Self::default();
+        let id = match map.get(&*default.id_field()) {
+            Some(id) => try!(id.parse().map_err(|_| DecoderError::ExpectedError("usize".to_string(), id.clone()))),
+            None => 0,
+        };
+        decode_properties(&default, id, map)
+    }
+
+    /// Serializes the object fields to a JSON object, keeping their types:
+    /// numbers, booleans, and arrays for `Vec` fields. References are
+    /// written as the id of their target, and a `PolyReference` as an
+    /// object with its `class` and `id`; empty ones are null. Counters,
+    /// sets, lists, maps and collections live in their own keys and are
+    /// left out; `export_all` adds the counters.
+    fn to_json(&self) -> Result<String, OhmerError> {
+        Ok(json::Json::Object(try!(json_fields(self))).to_string())
+    }
+
+    /// Creates an object from a JSON object like the ones written by
+    /// `to_json`. Unknown keys are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Reference};
+    /// model!(
+    ///     Author {
+    ///         name:String = "".to_string();
+    ///     });
+    /// model!(
+    ///     Novel {
+    ///         title:String = "".to_string();
+    ///         pages:u16 = 0;
+    ///         author:Reference<Author> = Reference::new();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let author = create!(Author { name: "Jane Austen".to_string(), }, &client).unwrap();
+    /// let mut novel = create!(Novel { title: "Emma".to_string(), pages: 474, }, &client).unwrap();
+    /// novel.author.set(&author);
+    ///
+    /// let json = novel.to_json().unwrap();
+    /// assert!(json.contains("\"pages\":474"));
+    /// assert!(json.contains(&*format!("\"author\":{}", author.id)));
+    /// let copy = Novel::from_json(&*json).unwrap();
+    /// assert_eq!(copy.id, novel.id);
+    /// assert_eq!(&*copy.title, "Emma");
+    /// assert_eq!(copy.pages, 474);
+    /// assert_eq!(&*copy.author.get(&client).unwrap().name, "Jane Austen");
+    /// # }
+    /// ```
+    fn from_json(s: &str) -> Result<Self, OhmerError> {
+        let fields = match try!(json::Json::from_str(s)) {
+            json::Json::Object(fields) => fields,
+            _ => return Err(OhmerError::DecoderError),
         };
-        self.set_id(id);
+        let default = Self::default();
+        let encoder = try!(default.encoder());
+        let references = encoder.references;
+
+        let mut properties = HashMap::new();
+        for (key, value) in fields.into_iter() {
+            let value = match value {
+                json::Json::String(v) => v,
+                json::Json::I64(v) => format!("{}", v),
+                json::Json::U64(v) => format!("{}", v),
+                json::Json::F64(v) => format!("{}", v),
+                json::Json::Boolean(v) => (if v { "1" } else { "0" }).to_string(),
+                // `Vec` fields are stored as a JSON array.
+                json::Json::Array(values) => json::Json::Array(values).to_string(),
+                json::Json::Object(ref target) if encoder.poly_references.contains(&key) => {
+                    let field = key.to_ascii_lowercase();
+                    if let Some(class) = target.get("class").and_then(|class| class.as_string()) {
+                        properties.insert(format!("{}_type", field), class.to_string());
+                    }
+                    if let Some(id) = target.get("id").and_then(|id| id.as_u64()) {
+                        properties.insert(format!("{}_id", field), format!("{}", id));
+                    }
+                    continue;
+                },
+                json::Json::Null => continue,
+                _ => return Err(OhmerError::DecoderError),
+            };
+            let reference = format!("{}_id", key);
+            if references.contains(&stored_name(&default, &*reference)) {
+                properties.insert(reference, value);
+            } else {
+                properties.insert(key, value);
+            }
+        }
+
+        let mut decoder = Decoder::new(properties);
+        Ok(try!(rustc_serialize::Decodable::decode(&mut decoder)))
+    }
+
+    /// Called by `delete` before the object is removed. By default it does
+    /// nothing.
+    ///
+    /// Deleting an object removes its own hash, indices, counters, sets and
+    /// lists, but not the objects pointing to it or the ones it points to.
+    /// Override this to delete them too, for example with
+    /// `Collection::delete_all`. If it fails the object is not deleted.
+    ///
+    /// The cascade is not atomic: every object is deleted with its own
+    /// script, so a failure halfway leaves the objects deleted so far gone
+    /// and the rest in place, and other clients may see the intermediate
+    /// states.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Collection, Ohmer, OhmerConnection, OhmerError, Query, Reference};
+    /// #[derive(RustcEncodable, RustcDecodable, Debug)]
+    /// struct Forum {
+    ///     id: usize,
+    ///     posts: Collection<Post>,
+    /// }
+    /// impl Default for Forum {
+    ///     fn default() -> Self { Forum { id: 0, posts: Collection::new() } }
+    /// }
+    /// impl Ohmer for Forum {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn on_delete(&self, r: &OhmerConnection) -> Result<(), OhmerError> {
+    ///         self.posts.delete_all("Forum", self, r).map(|_| ())
+    ///     }
+    /// }
+    /// model!(
+    ///     Post {
+    ///         indices { forum:Reference<Forum> = Reference::new(); };
+    ///         body:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let forum = Forum::default().create(&client).unwrap();
+    /// let post = create!(Post { forum: Reference::with_value(&forum), }, &client).unwrap();
+    /// let forum_id = format!("{}", forum.id);
+    /// forum.delete(&client).unwrap();
+    /// assert_eq!(ohmers::find::<Post>(post.id, &client).unwrap(), None);
+    /// assert!(Query::<Post>::find("forum_id", &*forum_id, &client).is_empty().unwrap());
+    /// # }
+    /// ```
+    fn on_delete(&self, _r: &OhmerConnection) -> Result<(), OhmerError> {
         Ok(())
     }
 
-    /// Deletes the object from the database.
-    fn delete(self, r: &redis::Client) -> Result<(), OhmerError> {
+    /// Deletes the object from the database, after calling `on_delete`.
+    fn delete(self, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        try!(self.on_delete(r));
         let encoder = try!(self.encoder());
         let (uniques, _) = try!(self.uniques_indices(&encoder));
 
-        let mut tracked = encoder.sets;
+        let mut tracked = encoder.sets.iter().chain(encoder.lists.iter())
+                .map(|property| self.property_key(property))
+                .collect::<HashSet<_>>();
         tracked.extend(encoder.counters);
-        tracked.extend(encoder.lists);
+        tracked.extend(encoder.hll_counters);
+        let keys = encoder.maps.iter()
+                .map(|property| format!("{}:{}:{}", self.get_class_name(), self.property_key(property), self.id()))
+                .collect::<Vec<_>>();
 
         let mut model = HashMap::new();
         let id = self.id();
@@ -816,17 +3786,291 @@ pub trait Ohmer : rustc_serialize::Encodable + rustc_serialize::Decodable + Defa
         model.insert("key", format!("{}:{}", name, id));
         model.insert("id", format!("{}", id));
         model.insert("name", name);
+        if let Some(inverses) = inverses_feature(&self) {
+            model.insert("inverses", inverses);
+        }
 
-        let script = redis::Script::new(DELETE);
-        let _:() = try!(script
-                .arg(try!(msgpack_encode(&model)))
-                .arg(try!(msgpack_encode(&uniques)))
-                .arg(try!(msgpack_encode(&tracked)))
-                .invoke(&try!(r.get_connection())));
-        Ok(())
+        let encoding = self.script_encoding();
+        let model = try!(encoding.encode(&model));
+        let uniques = try!(encoding.encode(&uniques));
+        let tracked = try!(encoding.encode(&tracked));
+        let keys = try!(encoding.encode(&keys));
+        let script = redis::Script::new(&*encoding.adapt(DELETE));
+        timed(r, "delete", || with_retry(r, || Ok(try!(script
+                .arg(&*model).arg(&*uniques).arg(&*tracked).arg(&*keys)
+                .invoke::<()>(r.conn())))))
+    }
+
+    /// The `bool` field marking the object as deleted by `soft_delete`, or
+    /// `None`, the default, if the model is only deleted with `delete`.
+    ///
+    /// The field is always indexed. A marked object is only in the index
+    /// of this field: it drops out of every other index and gives up its
+    /// unique values, so `find`, `with` and queries on indices leave it
+    /// out. It is still in `<Class>:all`; `Query::exclude_deleted` leaves
+    /// it out of queries starting from `all_query`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use std::collections::HashSet;
+    /// # use redis::Commands;
+    /// #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+    /// struct Memo {
+    ///     id: usize,
+    ///     topic: String,
+    ///     deleted: bool,
+    /// }
+    /// impl Ohmer for Memo {
+    ///     fn id(&self) -> usize { self.id }
+    ///     fn set_id(&mut self, id: usize) { self.id = id; }
+    ///     fn index_fields<'a>(&self) -> HashSet<&'a str> { vec!["topic"].into_iter().collect() }
+    ///     fn soft_delete_field(&self) -> Option<&'static str> { Some("deleted") }
+    /// }
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Memo:indices:topic:budget").unwrap();
+    /// let mut kept = Memo { topic: "budget".to_string(), ..Default::default() };
+    /// kept.save(&client).unwrap();
+    /// let mut gone = Memo { topic: "budget".to_string(), ..Default::default() };
+    /// gone.save(&client).unwrap();
+    ///
+    /// gone.soft_delete(&client).unwrap();
+    /// assert!(gone.deleted);
+    /// assert!(ohmers::get::<Memo>(gone.id, &client).unwrap().deleted);
+    /// assert_eq!(Query::<Memo>::find("topic", "budget", &client)
+    ///     .try_iter().unwrap().collect::<Vec<_>>(), vec![kept]);
+    /// assert!(Query::<Memo>::find("deleted", "1", &client).contains_id(gone.id).unwrap());
+    /// assert!(!ohmers::all_query::<Memo>(&client).unwrap().exclude_deleted()
+    ///     .contains_id(gone.id).unwrap());
+    ///
+    /// gone.restore(&client).unwrap();
+    /// assert_eq!(Query::<Memo>::find("topic", "budget", &client).len().unwrap(), 2);
+    /// # }
+    /// ```
+    fn soft_delete_field(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Marks the object as deleted in its `soft_delete_field` and saves it,
+    /// keeping its data but leaving its indices and unique values (see
+    /// `soft_delete_field`). Fails with `NotSaved` if it was never saved,
+    /// and with `UnknownIndex` if the model has no such field.
+    fn soft_delete(&mut self, r: &OhmerConnection) -> Result<(), OhmerError> {
+        set_deleted(self, true, r)
+    }
+
+    /// Undoes `soft_delete`, putting the object back in its indices. Fails
+    /// with `UniqueIndexViolation` if another object took one of its
+    /// unique values in the meantime.
+    fn restore(&mut self, r: &OhmerConnection) -> Result<(), OhmerError> {
+        set_deleted(self, false, r)
+    }
+
+    /// Checks that the object is in the index of each indexed field for
+    /// its current value, and in no other index of that field. Returns the
+    /// fields that are not, sorted. Fails with `NotSaved` if the object was
+    /// not saved yet.
+    ///
+    /// The values of a field are found with `SCAN`, like `index_values`.
+    /// It is cheaper than `reindex` when only a few objects are suspect,
+    /// like the ones just loaded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Incident {
+    ///         indices {
+    ///             status:String = "open".to_string();
+    ///             queue:String = "support".to_string();
+    ///         };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let incident = create!(Incident {}, &client).unwrap();
+    /// assert!(incident.verify_indices(&client).unwrap().is_empty());
+    ///
+    /// let _:bool = client.srem("Incident:indices:status:open", incident.id).unwrap();
+    /// let _:bool = client.sadd("Incident:indices:status:closed", incident.id).unwrap();
+    /// assert_eq!(incident.verify_indices(&client).unwrap(), vec!["status".to_string()]);
+    ///
+    /// assert_eq!(incident.repair_indices(&client).unwrap(), vec!["status".to_string()]);
+    /// assert!(incident.verify_indices(&client).unwrap().is_empty());
+    /// assert!(Query::<Incident>::find("status", "open", &client).contains_id(incident.id).unwrap());
+    /// assert!(!Query::<Incident>::find("status", "closed", &client).contains_id(incident.id).unwrap());
+    /// # }
+    /// ```
+    fn verify_indices(&self, r: &OhmerConnection) -> Result<Vec<String>, OhmerError> {
+        Ok(try!(index_mismatches(self, r)).into_iter().map(|(field, _, _)| field).collect())
+    }
+
+    /// Adds the object to the indices it is missing from and removes it
+    /// from the ones it should not be in, for the fields reported by
+    /// `verify_indices`, which are returned. Other objects and fields are
+    /// left alone. See `verify_indices`.
+    fn repair_indices(&self, r: &OhmerConnection) -> Result<Vec<String>, OhmerError> {
+        try!(check_writable(r));
+        let mismatches = try!(index_mismatches(self, r));
+        if mismatches.is_empty() {
+            return Ok(vec![]);
+        }
+        let memo = format!("{}:_indices", try!(self.key()));
+        let mut q = redis::pipe();
+        q.atomic();
+        for &(_, ref missing, ref stray) in mismatches.iter() {
+            for key in missing.iter() {
+                q.cmd("SADD").arg(&**key).arg(self.id()).ignore();
+                q.cmd("SADD").arg(&*memo).arg(&**key).ignore();
+            }
+            for key in stray.iter() {
+                q.cmd("SREM").arg(&**key).arg(self.id()).ignore();
+                q.cmd("SREM").arg(&*memo).arg(&**key).ignore();
+            }
+        }
+        try!(with_retry(r, || Ok(try!(q.query::<()>(r.conn())))));
+        Ok(mismatches.into_iter().map(|(field, _, _)| field).collect())
+    }
+
+    /// Runs a Lua script with the hash of this object, `<Class>:<id>`, as
+    /// `KEYS[1]`, followed by `extra_keys`, and `args` as `ARGV`. It fails
+    /// with `NotSaved` if the object was not saved yet, and, since the
+    /// script may write, with `ReadOnly` on a read-only connection. Errors
+    /// of the script itself are returned as `RedisError`.
+    ///
+    /// The other keys of an object can be derived from its hash key:
+    /// counters are `<Class>:<id>:<counter>`, `Set` and `List` properties
+    /// are `<Class>:<property>:<id>` (see `property_key`), and indices and
+    /// uniques are given by `key_for_index` and `key_for_unique`. Pass every
+    /// key the script touches in `extra_keys` instead of building it inside
+    /// the script. Indices are not updated when a script changes an indexed
+    /// field.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// model!(
+    ///     Ticket {
+    ///         status:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let ticket = create!(Ticket { status: "open".to_string(), }, &client).unwrap();
+    /// let swap = "if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    ///     redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+    ///     return 1
+    /// end
+    /// return 0";
+    /// assert_eq!(ticket.eval_script(swap, &[], &["status", "open", "closed"], &client).unwrap(),
+    ///     redis::Value::Int(1));
+    /// assert_eq!(ticket.eval_script(swap, &[], &["status", "open", "closed"], &client).unwrap(),
+    ///     redis::Value::Int(0));
+    /// assert_eq!(&*ohmers::get::<Ticket>(ticket.id, &client).unwrap().status, "closed");
+    /// # }
+    /// ```
+    fn eval_script(&self, script: &str, extra_keys: &[&str], args: &[&str], r: &OhmerConnection) -> Result<redis::Value, OhmerError> {
+        try!(check_writable(r));
+        let mut keys = vec![try!(self.key())];
+        keys.extend(extra_keys.iter().map(|key| key.to_string()));
+        let script = redis::Script::new(script);
+        Ok(try!(script.key(keys).arg(args).invoke(r.conn())))
+    }
+
+    /// Takes a lock on this object for `ttl`, unless another client holds
+    /// it. Returns whether the lock was taken. `token` identifies the
+    /// owner, and must be passed to `unlock`; use a value no other client
+    /// can guess, like a random string.
+    ///
+    /// The lock is only advisory: saving or deleting the object does not
+    /// check it. It expires after `ttl` even if it is not released, so the
+    /// work done while holding it must take less than that.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::Ohmer;
+    /// use std::time::Duration;
+    ///
+    /// model!(
+    ///     Job {
+    ///         command:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let job = create!(Job { command: "backup".to_string(), }, &client).unwrap();
+    /// assert!(job.try_lock(Duration::from_secs(30), "worker-1", &client).unwrap());
+    /// assert!(!job.try_lock(Duration::from_secs(30), "worker-2", &client).unwrap());
+    /// assert!(!job.unlock("worker-2", &client).unwrap());
+    /// assert!(job.unlock("worker-1", &client).unwrap());
+    /// assert!(job.try_lock(Duration::from_secs(30), "worker-2", &client).unwrap());
+    /// # }
+    /// ```
+    fn try_lock(&self, ttl: Duration, token: &str, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.lock_key());
+        let millis = ttl.as_secs() * 1000 + (ttl.subsec_nanos() / 1_000_000) as u64;
+        Ok(try!(redis::cmd("SET").arg(key).arg(token).arg("NX").arg("PX")
+                .arg(std::cmp::max(millis, 1)).query(r.conn())))
+    }
+
+    /// Releases a lock taken with `try_lock`, if it is still held with
+    /// `token`. Returns whether it was released.
+    fn unlock(&self, token: &str, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.lock_key());
+        Ok(try!(redis::Script::new(UNLOCK).key(key).arg(token).invoke(r.conn())))
     }
 }
 
+/// What `Ohmer::save` did.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, SaveOutcome};
+/// model!(
+///     Invoice {
+///         total:u32 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut invoice = Invoice::default();
+/// let id = match invoice.save(&client).unwrap() {
+///     SaveOutcome::Created { id } => id,
+///     SaveOutcome::Updated => unreachable!(),
+/// };
+/// assert_eq!(id, invoice.id);
+/// invoice.total = 100;
+/// assert_eq!(invoice.save(&client).unwrap(), SaveOutcome::Updated);
+/// # }
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SaveOutcome {
+    /// The object was new and got the id `id`.
+    Created { id: usize },
+    /// An existing object was updated.
+    Updated,
+}
+
 /// A Reference to another Ohmer object.
 ///
 /// # Examples
@@ -875,15 +4119,169 @@ impl<T: Ohmer> Reference<T> {
     }
 
     /// Returns a new instance of the referenced object.
-    pub fn get(&self, r: &redis::Client) -> Result<T, DecoderError> {
+    /// It does not check the object exists: if the reference is empty or
+    /// its target was deleted, the result is a default object or a decoding
+    /// error. Use `try_get` when the target may be missing.
+    pub fn get(&self, r: &OhmerConnection) -> Result<T, DecoderError> {
         get(self.id, r)
     }
 
+    /// Returns a new instance of the referenced object, or `None` if the
+    /// reference is empty or its target is no longer in `<Class>:all`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Reference};
+    /// model!(
+    ///     Country {
+    ///         name:String = "".to_string();
+    ///     });
+    /// model!(
+    ///     City {
+    ///         country:Reference<Country> = Reference::new();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let country = create!(Country { name: "Yugoslavia".to_string(), }, &client).unwrap();
+    /// let mut city = City::default();
+    /// assert!(city.country.try_get(&client).unwrap().is_none());
+    ///
+    /// city.country.set(&country);
+    /// assert_eq!(&*city.country.try_get(&client).unwrap().unwrap().name, "Yugoslavia");
+    ///
+    /// country.delete(&client).unwrap();
+    /// assert!(city.country.try_get(&client).unwrap().is_none());
+    /// # }
+    /// ```
+    pub fn try_get(&self, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        if self.id == 0 {
+            return Ok(None);
+        }
+        Ok(try!(load_many(&[self.id], r)).pop().unwrap_or(None))
+    }
+
     /// Updates the reference to the new object. It does not save automatically,
     /// `Parent.save(&connection);` still needs to be called.
     pub fn set(&mut self, obj: &T) {
         self.id = obj.id();
     }
+
+    /// The id of the referenced object, or 0 if the reference is empty.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Loads the targets of many references in one round trip, keyed by
+    /// their id. Empty references and targets that no longer exist are
+    /// left out. See `preload`.
+    pub fn get_many(refs: &[&Reference<T>], r: &OhmerConnection) -> Result<HashMap<usize, T>, OhmerError> {
+        let mut ids = refs.iter().map(|reference| reference.id).filter(|id| *id != 0).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        let objs = try!(load_many::<T>(&*ids, r));
+        Ok(ids.into_iter().zip(objs.into_iter())
+                .filter_map(|(id, obj)| obj.map(|obj| (id, obj)))
+                .collect())
+    }
+}
+
+/// A reference to an object of any class, like a comment whose subject may
+/// be a post or a photo. It is stored in two fields of the hash:
+/// `<field>_type`, with the class name, and `<field>_id`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, OhmerError, PolyReference};
+/// # use redis::Commands;
+/// model!(
+///     BlogPost {
+///         title:String = "".to_string();
+///     });
+/// model!(
+///     Snapshot {
+///         url:String = "".to_string();
+///     });
+/// model!(
+///     Remark {
+///         subject:PolyReference = PolyReference::new();
+///         text:String = "".to_string();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let post = create!(BlogPost { title: "Hello".to_string(), }, &client).unwrap();
+/// let snapshot = create!(Snapshot { url: "cat.png".to_string(), }, &client).unwrap();
+///
+/// let created = create!(Remark { subject: PolyReference::with_value(&post), }, &client).unwrap();
+/// let mut remark = ohmers::get::<Remark>(created.id, &client).unwrap();
+/// assert!(remark.subject.is::<BlogPost>());
+/// assert_eq!(&*remark.subject.get::<BlogPost>(&client).unwrap().title, "Hello");
+/// assert_eq!(remark.subject.get::<Snapshot>(&client).unwrap_err(),
+///         OhmerError::ClassMismatch { expected: "Snapshot".to_string(), found: "BlogPost".to_string() });
+///
+/// remark.subject.set(&snapshot);
+/// remark.save(&client).unwrap();
+/// let class:String = client.hget(format!("Remark:{}", remark.id), "subject_type").unwrap();
+/// assert_eq!(&*class, "Snapshot");
+/// # }
+/// ```
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Clone, Default)]
+pub struct PolyReference {
+    class: String,
+    id: usize,
+}
+
+impl PolyReference {
+    /// Creates a new reference with no value.
+    pub fn new() -> Self {
+        PolyReference { class: "".to_string(), id: 0 }
+    }
+
+    /// Creates a new reference to `obj`.
+    pub fn with_value<T: Ohmer>(obj: &T) -> Self {
+        PolyReference { class: obj.get_class_name(), id: obj.id() }
+    }
+
+    /// Updates the reference to `obj`, recording its class. It does not
+    /// save automatically.
+    pub fn set<T: Ohmer>(&mut self, obj: &T) {
+        self.class = obj.get_class_name();
+        self.id = obj.id();
+    }
+
+    /// The class of the referenced object, or `None` if the reference is
+    /// empty.
+    pub fn class(&self) -> Option<&str> {
+        if self.class.is_empty() { None } else { Some(&*self.class) }
+    }
+
+    /// The id of the referenced object, or 0 if the reference is empty.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether the referenced object is a `T`.
+    pub fn is<T: Ohmer>(&self) -> bool {
+        self.class == T::default().get_class_name()
+    }
+
+    /// Returns a new instance of the referenced object. It fails with
+    /// `ClassMismatch` if the reference is empty or points to another
+    /// class. Like `Reference::get`, it does not check the object exists.
+    pub fn get<T: Ohmer>(&self, r: &OhmerConnection) -> Result<T, OhmerError> {
+        let expected = T::default().get_class_name();
+        if self.class != expected {
+            return Err(OhmerError::ClassMismatch { expected: expected, found: self.class.clone() });
+        }
+        Ok(try!(get(self.id, r)))
+    }
 }
 
 /// A wrapper for classes that are referenced from another classes property.
@@ -930,8 +4328,22 @@ impl<T: Ohmer> Collection<T> {
     }
 
     /// Returns a query for all T elements referencing this object.
-    pub fn all<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a redis::Client) -> Query<T> {
-        Query::<T>::find(&*format!("{}_id", property.to_ascii_lowercase()), &*format!("{}", parent.id()), r)
+    pub fn all<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a OhmerConnection) -> Query<T> {
+        Query::<T>::find(&*format!("{}_id", parent.property_key(property).to_ascii_lowercase()), &*format!("{}", parent.id()), r)
+    }
+
+    /// Deletes all T elements referencing this object, and returns how
+    /// many were deleted. Each of them is deleted with `Ohmer::delete`, so
+    /// their own `on_delete` runs too. It is meant to be called from
+    /// `on_delete` to cascade deletes, and it is not atomic. Nothing is
+    /// deleted if any of the elements cannot be loaded.
+    pub fn delete_all<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        let objs = try!(self.all(property, parent, r).into_vec());
+        let deleted = objs.len();
+        for obj in objs {
+            try!(obj.delete(r));
+        }
+        Ok(deleted)
     }
 }
 
@@ -983,78 +4395,254 @@ impl<T: Ohmer> List<T> {
         if id == 0 {
             Err(OhmerError::NotSaved)
         } else {
-            Ok(format!("{}:{}:{}", parent.get_class_name(), property, parent.id()))
+            Ok(format!("{}:{}:{}", parent.get_class_name(), parent.property_key(property), parent.id()))
         }
     }
 
     /// Number of items in the list.
-    pub fn len<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<usize, OhmerError> {
-        Ok(try!(r.llen(try!(self.key_name(property, parent)))))
+    pub fn len<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("LLEN").arg(&*key), &*key, "list", r)
+    }
+
+    /// Removes every element of the list, returning whether it had any.
+    pub fn clear<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        Ok(try!(redis::cmd("DEL").arg(&*key).query(r.conn())))
     }
 
     /// Adds an element at the end of the list.
-    pub fn push_back<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<(), OhmerError> {
-        Ok(try!(r.rpush(try!(self.key_name(property, parent)), obj.id())))
+    pub fn push_back<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<(), OhmerError> {
+        self.push_back_id(property, parent, obj.id(), r)
+    }
+
+    /// Adds an element at the end of the list by id.
+    pub fn push_back_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("RPUSH").arg(&*key).arg(id), &*key, "list", r)
+    }
+
+    /// Adds an element at the end of the list, and then removes elements
+    /// from the beginning so at most `max_len` remain.
+    pub fn push_back_capped<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, max_len: usize, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        let (start, stop) = if max_len == 0 { (1, 0) } else { (-(max_len as isize), -1) };
+        redis::pipe()
+                .atomic()
+                .cmd("RPUSH").arg(&*key).arg(obj.id()).ignore()
+                .cmd("LTRIM").arg(&*key).arg(start).arg(stop).ignore()
+                .query(r.conn())
+                .map_err(|e| OhmerError::from_key_error(e, &*key, "list"))
+    }
+
+    /// Trims the list so it only contains the elements between `start` and
+    /// `stop`. Negative indices start from the end.
+    pub fn trim<P: Ohmer>(&self, property: &str, parent: &P, start: isize, stop: isize, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("LTRIM").arg(&*key).arg(start).arg(stop), &*key, "list", r)
     }
 
     /// Takes an element from the end of the list.
-    pub fn pop_back<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<Option<T>, OhmerError> {
-        Ok(match try!(r.rpop(try!(self.key_name(property, parent)))) {
+    pub fn pop_back<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        Ok(match try!(query_key(redis::cmd("RPOP").arg(&*key), &*key, "list", r)) {
             Some(id) => Some(try!(get(id, r))),
             None => None,
         })
     }
 
     /// Adds an element at the beginning of the list.
-    pub fn push_front<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<(), OhmerError> {
-        Ok(try!(r.lpush(try!(self.key_name(property, parent)), obj.id())))
+    pub fn push_front<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<(), OhmerError> {
+        self.push_front_id(property, parent, obj.id(), r)
+    }
+
+    /// Adds an element at the beginning of the list by id.
+    pub fn push_front_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("LPUSH").arg(&*key).arg(id), &*key, "list", r)
     }
 
     /// Takes an element from the beginning of the list.
-    pub fn pop_front<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<Option<T>, OhmerError> {
-        Ok(match try!(r.lpop(try!(self.key_name(property, parent)))) {
+    pub fn pop_front<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        Ok(match try!(query_key(redis::cmd("LPOP").arg(&*key), &*key, "list", r)) {
+            Some(id) => Some(try!(get(id, r))),
+            None => None,
+        })
+    }
+
+    /// Takes an element from the end of the list and adds it at the
+    /// beginning of `dst` atomically, so it is always in one of them.
+    pub fn move_to<P: Ohmer, Q: Ohmer>(&self, property: &str, parent: &P, dst: &List<T>, dst_property: &str, dst_parent: &Q, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        try!(check_writable(r));
+        let src_key = try!(self.key_name(property, parent));
+        let dst_key = try!(dst.key_name(dst_property, dst_parent));
+        let moved:redis::RedisResult<Option<usize>> = redis::cmd("RPOPLPUSH").arg(&*src_key).arg(&*dst_key).query(r.conn());
+        // Either key may have the wrong type; name the source unless it is a list.
+        let moved = try!(moved.map_err(|e| {
+            let kind:redis::RedisResult<String> = redis::cmd("TYPE").arg(&*src_key).query(r.conn());
+            match kind {
+                Ok(ref kind) if kind == "list" || kind == "none" => OhmerError::from_key_error(e, &*dst_key, "list"),
+                _ => OhmerError::from_key_error(e, &*src_key, "list"),
+            }
+        }));
+        Ok(match moved {
             Some(id) => Some(try!(get(id, r))),
             None => None,
         })
     }
 
     /// Retrieves an element from the beginning of the list.
-    pub fn first<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<Option<T>, OhmerError> {
-        Ok(match try!(r.lindex(try!(self.key_name(property, parent)), 0)) {
+    pub fn first<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        Ok(match try!(query_key(redis::cmd("LINDEX").arg(&*key).arg(0), &*key, "list", r)) {
             Some(id) => Some(try!(get(id, r))),
             None => None,
         })
     }
 
     /// Retrieves an element from the end of the list.
-    pub fn last<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<Option<T>, OhmerError> {
-        Ok(match try!(r.lindex(try!(self.key_name(property, parent)), -1)) {
+    pub fn last<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        Ok(match try!(query_key(redis::cmd("LINDEX").arg(&*key).arg(-1), &*key, "list", r)) {
+            Some(id) => Some(try!(get(id, r))),
+            None => None,
+        })
+    }
+
+    /// Inserts an element right before the first occurrence of `pivot`.
+    /// Returns the new length of the list, or `None` if `pivot` was not
+    /// found.
+    pub fn insert_before<P: Ohmer>(&self, property: &str, parent: &P, pivot: &T, obj: &T, r: &OhmerConnection) -> Result<Option<usize>, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        let len:isize = try!(query_key(redis::cmd("LINSERT").arg(&*key).arg("BEFORE").arg(pivot.id()).arg(obj.id()), &*key, "list", r));
+        Ok(if len > 0 { Some(len as usize) } else { None })
+    }
+
+    /// Inserts an element right after the first occurrence of `pivot`.
+    /// Returns the new length of the list, or `None` if `pivot` was not
+    /// found.
+    pub fn insert_after<P: Ohmer>(&self, property: &str, parent: &P, pivot: &T, obj: &T, r: &OhmerConnection) -> Result<Option<usize>, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        let len:isize = try!(query_key(redis::cmd("LINSERT").arg(&*key).arg("AFTER").arg(pivot.id()).arg(obj.id()), &*key, "list", r));
+        Ok(if len > 0 { Some(len as usize) } else { None })
+    }
+
+    /// Retrieves the element at position `index`.
+    /// Negative indices start from the end.
+    pub fn get<P: Ohmer>(&self, property: &str, parent: &P, index: isize, r: &OhmerConnection) -> Result<Option<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        Ok(match try!(query_key(redis::cmd("LINDEX").arg(&*key).arg(index), &*key, "list", r)) {
             Some(id) => Some(try!(get(id, r))),
             None => None,
         })
     }
 
+    /// Replaces the element at position `index`.
+    /// Negative indices start from the end. Fails if the index is out of
+    /// range.
+    pub fn set<P: Ohmer>(&self, property: &str, parent: &P, index: isize, obj: &T, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("LSET").arg(&*key).arg(index).arg(obj.id()), &*key, "list", r)
+    }
+
     /// Creates an iterator for the list between `start` and `end`.
     /// Negative indices start from the end.
-    pub fn try_range<'a, P: Ohmer>(&'a self, property: &str, parent: &P, start: isize, end: isize, r: &'a redis::Client) -> Result<Iter<T>, OhmerError> {
-        let ids:Vec<usize> = try!(r.lrange(try!(self.key_name(property, parent)), start, end));
+    pub fn try_range<'a, P: Ohmer>(&'a self, property: &str, parent: &P, start: isize, end: isize, r: &'a OhmerConnection) -> Result<Iter<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let ids:Vec<usize> = try!(query_key(redis::cmd("LRANGE").arg(&*key).arg(start).arg(end), &*key, "list", r));
         Ok(Iter::new(ids.into_iter(), r))
     }
 
     /// Creates an iterator for all the elements in the list.
-    pub fn try_iter<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a redis::Client) -> Result<Iter<T>, OhmerError> {
+    pub fn try_iter<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a OhmerConnection) -> Result<Iter<T>, OhmerError> {
         self.try_range(property, parent, 0, -1, r)
     }
 
+    /// Loads all the elements in the list, in order, in a single round
+    /// trip after reading their ids. Unlike `try_iter`, which skips
+    /// elements it cannot load, it fails if any of them cannot be loaded,
+    /// with `NotFound` if it was deleted.
+    pub fn to_vec<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Vec<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let ids:Vec<usize> = try!(query_key(redis::cmd("LRANGE").arg(&*key).arg(0).arg(-1), &*key, "list", r));
+        load_all(&*ids, r)
+    }
+
+    /// Gets a `Query` object for all the elements in the list, so they can
+    /// be filtered with indices. The ids are copied to a temporary set when
+    /// the query runs, so the order of the list is lost.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create, push_back)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, List};
+    /// model!(
+    ///     Job {
+    ///         indices { priority:String = "low".to_string(); };
+    ///         name:String = "".to_string();
+    ///     });
+    /// model!(
+    ///     Worker {
+    ///         jobs:List<Job> = List::new();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let worker = create!(Worker {}, &client).unwrap();
+    /// let j1 = create!(Job { name: "backup".to_string(), priority: "high".to_string(), }, &client).unwrap();
+    /// let j2 = create!(Job { name: "cleanup".to_string(), }, &client).unwrap();
+    /// push_back!(worker.jobs, j1, &client).unwrap();
+    /// push_back!(worker.jobs, j2, &client).unwrap();
+    ///
+    /// let high = worker.jobs.query("jobs", &worker, &client).unwrap()
+    ///     .inter("priority", "high").try_iter().unwrap().collect::<Vec<_>>();
+    /// assert_eq!(high, vec![j1]);
+    /// # }
+    /// ```
+    pub fn query<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a OhmerConnection) -> Result<Query<'a, T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let temporary = format!("{}:query", key).into_bytes();
+        let mut query = Query::new(stal::Set::Key(temporary.clone()), r);
+        query.setup.push(vec![b"EVAL".to_vec(), LIST_TO_SET.as_bytes().to_vec(), b"2".to_vec(), key.into_bytes(), temporary.clone()]);
+        query.temporary.push(temporary);
+        Ok(query)
+    }
+
     /// Checks if an element is in the list.
-    pub fn contains<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<bool, OhmerError> {
-        let ids:Vec<usize> = try!(r.lrange(try!(self.key_name(property, parent)), 0, -1));
-        Ok(ids.contains(&obj.id()))
+    pub fn contains<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        self.contains_id(property, parent, obj.id(), r)
+    }
+
+    /// Checks if an element with this id is in the list.
+    pub fn contains_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let ids:Vec<usize> = try!(query_key(redis::cmd("LRANGE").arg(&*key).arg(0).arg(-1), &*key, "list", r));
+        Ok(ids.contains(&id))
     }
 
     /// Remove all occurrences of an element in the list.
-    pub fn remove<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<usize, OhmerError> {
-        Ok(try!(r.lrem(try!(self.key_name(property, parent)), 0, obj.id())))
+    pub fn remove<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        self.remove_id(property, parent, obj.id(), r)
+    }
+
+    /// Remove all occurrences of an element in the list by id.
+    pub fn remove_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("LREM").arg(&*key).arg(0).arg(id), &*key, "list", r)
     }
 }
 
@@ -1106,7 +4694,7 @@ impl<T: Ohmer> Set<T> {
         if id == 0 {
             Err(OhmerError::NotSaved)
         } else {
-            Ok(format!("{}:{}:{}", parent.get_class_name(), property, parent.id()))
+            Ok(format!("{}:{}:{}", parent.get_class_name(), parent.property_key(property), parent.id()))
         }
     }
 
@@ -1116,31 +4704,184 @@ impl<T: Ohmer> Set<T> {
     }
 
     /// Gets a `Query` object for all the elements in the set.
-    pub fn query<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a redis::Client) -> Result<Query<T>, OhmerError> {
+    pub fn query<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a OhmerConnection) -> Result<Query<T>, OhmerError> {
         let key = try!(self.key(property, parent));
         Ok(Query::new(key, r))
     }
 
     /// Adds an element to the set. Returns true when the element was added,
     /// false if it was already present.
-    pub fn insert<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<bool, OhmerError> {
-        Ok(try!(r.sadd(try!(self.key_name(property, parent)), obj.id())))
+    pub fn insert<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        self.insert_id(property, parent, obj.id(), r)
+    }
+
+    /// Adds an element to the set by id. Returns true when the element was
+    /// added, false if it was already present.
+    pub fn insert_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("SADD").arg(&*key).arg(id), &*key, "set", r)
     }
 
     /// Removes an element to the set. Returns true when the element was removed,
     /// false if it was already absent.
-    pub fn remove<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<bool, OhmerError> {
-        Ok(try!(r.srem(try!(self.key_name(property, parent)), obj.id())))
+    pub fn remove<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        self.remove_id(property, parent, obj.id(), r)
+    }
+
+    /// Removes an element from the set by id. Returns true when the element
+    /// was removed, false if it was already absent.
+    pub fn remove_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("SREM").arg(&*key).arg(id), &*key, "set", r)
+    }
+
+    /// Moves an element from the set to `dst` atomically with `SMOVE`, so
+    /// other clients see it in exactly one of them. Returns false, and
+    /// changes nothing, if the element was not in the set.
+    pub fn move_to<P: Ohmer, Q: Ohmer>(&self, property: &str, parent: &P, obj: &T, dst: &Set<T>, dst_property: &str, dst_parent: &Q, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let src_key = try!(self.key_name(property, parent));
+        let dst_key = try!(dst.key_name(dst_property, dst_parent));
+        let moved:redis::RedisResult<bool> = redis::cmd("SMOVE").arg(&*src_key).arg(&*dst_key).arg(obj.id()).query(r.conn());
+        // Either key may have the wrong type; name the source unless it is a set.
+        moved.map_err(|e| {
+            let kind:redis::RedisResult<String> = redis::cmd("TYPE").arg(&*src_key).query(r.conn());
+            match kind {
+                Ok(ref kind) if kind == "set" || kind == "none" => OhmerError::from_key_error(e, &*dst_key, "set"),
+                _ => OhmerError::from_key_error(e, &*src_key, "set"),
+            }
+        })
     }
 
     /// Returns true if the element is in the set.
-    pub fn contains<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &redis::Client) -> Result<bool, OhmerError> {
-        Ok(try!(r.sismember(try!(self.key_name(property, parent)), obj.id())))
+    pub fn contains<P: Ohmer>(&self, property: &str, parent: &P, obj: &T, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        self.contains_id(property, parent, obj.id(), r)
+    }
+
+    /// Returns true if an element with this id is in the set.
+    pub fn contains_id<P: Ohmer>(&self, property: &str, parent: &P, id: usize, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("SISMEMBER").arg(&*key).arg(id), &*key, "set", r)
     }
 
     /// Counts the number of elements in the set.
-    pub fn len<P: Ohmer>(&self, property: &str, parent: &P, r: &redis::Client) -> Result<usize, OhmerError> {
-        Ok(try!(r.scard(try!(self.key_name(property, parent)))))
+    pub fn len<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        query_key(redis::cmd("SCARD").arg(&*key), &*key, "set", r)
+    }
+
+    /// Removes every element of the set, returning whether it had any.
+    pub fn clear<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.key_name(property, parent));
+        Ok(try!(redis::cmd("DEL").arg(&*key).query(r.conn())))
+    }
+
+    /// Creates an iterator for all the elements in the set.
+    /// Sets are unordered, so the iteration order is unspecified.
+    pub fn try_iter<'a, P: Ohmer>(&'a self, property: &str, parent: &P, r: &'a OhmerConnection) -> Result<Iter<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let ids:Vec<usize> = try!(query_key(redis::cmd("SMEMBERS").arg(&*key), &*key, "set", r));
+        Ok(Iter::new(ids.into_iter(), r))
+    }
+
+    /// Loads all the elements in the set in a single round trip after
+    /// reading their ids, in no particular order. Unlike `try_iter`, it
+    /// fails if any of them cannot be loaded, with `NotFound` if it was
+    /// deleted.
+    pub fn to_vec<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<Vec<T>, OhmerError> {
+        let key = try!(self.key_name(property, parent));
+        let ids:Vec<usize> = try!(query_key(redis::cmd("SMEMBERS").arg(&*key), &*key, "set", r));
+        load_all(&*ids, r)
+    }
+}
+
+/// A map of keys to values, stored in its own hash, for data like tags or
+/// attributes that does not need a model of its own. Keys and values can
+/// be any type Redis can store, like strings or numbers.
+///
+/// The hash is named `<Class>:<property>:<id>` and is deleted with the
+/// object.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, map_set, map_get)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Map, Ohmer};
+/// model!(
+///     Device {
+///         name:String = "".to_string();
+///         metadata:Map<String, String> = Map::new();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let device = create!(Device { name: "router".to_string(), }, &client).unwrap();
+/// assert!(map_set!(device.metadata, "os".to_string(), "linux".to_string(), &client).unwrap());
+/// map_set!(device.metadata, "rack".to_string(), "b2".to_string(), &client).unwrap();
+/// assert_eq!(map_get!(device.metadata, "os".to_string(), &client).unwrap(), Some("linux".to_string()));
+/// assert!(device.metadata.remove("metadata", &device, "rack".to_string(), &client).unwrap());
+/// assert_eq!(device.metadata.len("metadata", &device, &client).unwrap(), 1);
+///
+/// let key = format!("Device:metadata:{}", device.id);
+/// device.delete(&client).unwrap();
+/// assert!(!redis::cmd("EXISTS").arg(key).query::<bool>(&client).unwrap());
+/// # }
+/// ```
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Clone)]
+pub struct Map<K, V> {
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K: ToRedisArgs + redis::FromRedisValue, V: ToRedisArgs + redis::FromRedisValue> Map<K, V> {
+    pub fn new() -> Self {
+        Map { phantom: PhantomData }
+    }
+
+    /// Name of the map property in Redis
+    fn key_name<P: Ohmer>(&self, property: &str, parent: &P) -> Result<String, OhmerError> {
+        let id = parent.id();
+        if id == 0 {
+            Err(OhmerError::NotSaved)
+        } else {
+            Ok(format!("{}:{}:{}", parent.get_class_name(), parent.property_key(property), parent.id()))
+        }
+    }
+
+    /// Sets the value of `key`. Returns whether the key is new.
+    pub fn set<P: Ohmer>(&self, property: &str, parent: &P, key: K, value: V, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let name = try!(self.key_name(property, parent));
+        query_key(redis::cmd("HSET").arg(&*name).arg(key).arg(value), &*name, "hash", r)
+    }
+
+    /// Gets the value of `key`, if it has one.
+    pub fn get<P: Ohmer>(&self, property: &str, parent: &P, key: K, r: &OhmerConnection) -> Result<Option<V>, OhmerError> {
+        let name = try!(self.key_name(property, parent));
+        query_key(redis::cmd("HGET").arg(&*name).arg(key), &*name, "hash", r)
+    }
+
+    /// Removes `key`. Returns whether it had a value.
+    pub fn remove<P: Ohmer>(&self, property: &str, parent: &P, key: K, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let name = try!(self.key_name(property, parent));
+        query_key(redis::cmd("HDEL").arg(&*name).arg(key), &*name, "hash", r)
+    }
+
+    /// Number of keys in the map.
+    pub fn len<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        let name = try!(self.key_name(property, parent));
+        query_key(redis::cmd("HLEN").arg(&*name), &*name, "hash", r)
+    }
+
+    /// Gets every key and value in the map.
+    pub fn all<P: Ohmer>(&self, property: &str, parent: &P, r: &OhmerConnection) -> Result<HashMap<K, V>, OhmerError>
+            where K: Eq + ::std::hash::Hash {
+        let name = try!(self.key_name(property, parent));
+        query_key(redis::cmd("HGETALL").arg(&*name), &*name, "hash", r)
     }
 }
 
@@ -1160,6 +4901,140 @@ pub enum OhmerError {
     UniqueIndexViolation(String),
     /// There was an error translating a field to a string using utf8.
     CommandError(Vec<u8>),
+    /// A generated id is already used by another object.
+    IdInUse(usize),
+    /// An object with a `string_id_field` is saved without a string id,
+    /// and `generate_string_id` did not make one.
+    MissingStringId,
+    /// `save_if_absent` found an object stored with this id.
+    AlreadyExists(usize),
+    /// A unique field value is already in use, found while saving several
+    /// objects at once. The id of the object and the field name are
+    /// returned.
+    UniqueIndexViolationAt { id: usize, field: String },
+    /// The JSON could not be parsed.
+    JsonError(json::ParserError),
+    /// Error writing an export. The error message is returned.
+    IoError(String),
+    /// The class already has keys in the database. The class name is returned.
+    ClassExists(String),
+    /// The operation writes, but the connection is read-only.
+    ReadOnly,
+    /// The counter would go past the range of a 64 bits integer.
+    CounterOverflow,
+    /// There is no object with this id.
+    NotFound(usize),
+    /// A key holds a different kind of value than the operation expected,
+    /// like a string where a `List` should be. The key and the expected
+    /// kind are returned.
+    WrongType { key: String, expected: &'static str },
+    /// A `PolyReference` points to an object of another class. The
+    /// expected and the stored class names are returned.
+    ClassMismatch { expected: String, found: String },
+    /// The operation needs every object of the class from `<Class>:all`,
+    /// but the model does not `track_all`. The class name is returned.
+    Untracked(String),
+}
+
+impl OhmerError {
+    /// Translates an error raised by the save script. A violated unique
+    /// index becomes `UniqueIndexViolation` with the field name (or
+    /// `UniqueIndexViolationAt` when saving several objects), a taken
+    /// generated id becomes `IdInUse`, an object that exists or is missing
+    /// becomes `AlreadyExists` or `NotFound`, anything else is kept as a
+    /// `RedisError`.
+    pub fn from_script_error(e: redis::RedisError) -> OhmerError {
+        let s = format!("{}", e);
+        let re = Regex::new(r"UniqueIndexViolationAt: (\d+) (\w+)").unwrap();
+        if let Some(caps) = re.captures(&*s) {
+            if let (Some(id), Some(field)) = (caps.at(1).and_then(|id| id.parse().ok()), caps.at(2)) {
+                return OhmerError::UniqueIndexViolationAt { id: id, field: field.to_string() };
+            }
+        }
+        let re = Regex::new(r"UniqueIndexViolation: (\w+)").unwrap();
+        if let Some(field) = re.captures(&*s).and_then(|caps| caps.at(1)) {
+            return OhmerError::UniqueIndexViolation(field.to_string());
+        }
+        let re = Regex::new(r"IdInUse: (\d+)").unwrap();
+        if let Some(id) = re.captures(&*s).and_then(|caps| caps.at(1)).and_then(|id| id.parse().ok()) {
+            return OhmerError::IdInUse(id);
+        }
+        let re = Regex::new(r"AlreadyExists: (\d+)").unwrap();
+        if let Some(id) = re.captures(&*s).and_then(|caps| caps.at(1)).and_then(|id| id.parse().ok()) {
+            return OhmerError::AlreadyExists(id);
+        }
+        let re = Regex::new(r"NotFound: (\d+)").unwrap();
+        if let Some(id) = re.captures(&*s).and_then(|caps| caps.at(1)).and_then(|id| id.parse().ok()) {
+            return OhmerError::NotFound(id);
+        }
+        OhmerError::RedisError(e)
+    }
+
+    /// Translates an error from an operation on `key`, which should hold
+    /// a value of kind `expected`. A `WRONGTYPE` error becomes `WrongType`,
+    /// anything else is kept as a `RedisError`.
+    pub fn from_key_error(e: redis::RedisError, key: &str, expected: &'static str) -> OhmerError {
+        if e.extension_error_code() == Some("WRONGTYPE") {
+            OhmerError::WrongType { key: key.to_string(), expected: expected }
+        } else {
+            OhmerError::RedisError(e)
+        }
+    }
+
+    /// Whether the error comes from talking to the server (the connection
+    /// was refused, dropped or timed out) rather than from the command
+    /// itself. These errors are usually worth retrying.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// use std::thread;
+    /// use std::time::Duration;
+    /// use ohmers::{Ohmer, OhmerError};
+    ///
+    /// model!(
+    ///     Ticket {
+    ///         code:String = "".to_string();
+    ///     });
+    ///
+    /// fn save_with_retry<T: Ohmer>(obj: &mut T, client: &redis::Client) -> Result<ohmers::SaveOutcome, OhmerError> {
+    ///     let mut attempts = 0;
+    ///     loop {
+    ///         match obj.save(client) {
+    ///             Err(ref e) if e.is_connection_error() && attempts < 3 => {
+    ///                 attempts += 1;
+    ///                 thread::sleep(Duration::from_millis(10));
+    ///             },
+    ///             result => return result,
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// assert!(save_with_retry(&mut Ticket::default(), &client).is_ok());
+    ///
+    /// let nowhere = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    /// assert!(save_with_retry(&mut Ticket::default(), &nowhere).unwrap_err().is_connection_error());
+    /// # }
+    /// ```
+    pub fn is_connection_error(&self) -> bool {
+        match *self {
+            OhmerError::RedisError(ref e) => e.is_io_error(),
+            _ => false,
+        }
+    }
+
+    /// Whether the error is a timeout talking to the server.
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            OhmerError::RedisError(ref e) => e.is_timeout(),
+            _ => false,
+        }
+    }
 }
 
 impl From<FromUtf8Error> for OhmerError {
@@ -1180,6 +5055,12 @@ impl From<EncoderError> for OhmerError {
     }
 }
 
+impl From<json::ParserError> for OhmerError {
+    fn from(e: json::ParserError) -> OhmerError {
+        OhmerError::JsonError(e)
+    }
+}
+
 impl From<DecoderError> for OhmerError {
     fn from(_: DecoderError) -> OhmerError {
         OhmerError::DecoderError
@@ -1224,19 +5105,180 @@ impl Counter {
     }
 
     /// Increments the counter by `incr` and returns the new value.
-    pub fn incr<T: Ohmer>(&self, obj: &T, prop: &str, incr: i64, r: &redis::Client) -> Result<i64, OhmerError> {
+    /// Fails with `CounterOverflow` if the result does not fit in an `i64`,
+    /// leaving the counter unchanged.
+    pub fn incr<T: Ohmer>(&self, obj: &T, prop: &str, incr: i64, r: &OhmerConnection) -> Result<i64, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.get_key(obj, prop));
+        redis::cmd("INCRBY").arg(key).arg(incr).query(r.conn()).map_err(Counter::incr_error)
+    }
+
+    /// Increments the counter by `incr` and returns the new value. If the
+    /// result does not fit in an `i64`, the counter is set to `i64::MAX` or
+    /// `i64::MIN` instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create, incr)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Counter, Ohmer, OhmerError};
+    /// use std::i64;
+    ///
+    /// model!(
+    ///     Meter {
+    ///         total: Counter = Counter;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let meter = create!(Meter {}, &client).unwrap();
+    /// incr!(meter.total, i64::MAX - 1, &client).unwrap();
+    /// assert_eq!(incr!(meter.total, 2, &client), Err(OhmerError::CounterOverflow));
+    /// assert_eq!(meter.total.incr_saturating(&meter, "total", 2, &client).unwrap(), i64::MAX);
+    /// assert_eq!(meter.total.incr_saturating(&meter, "total", -1, &client).unwrap(), i64::MAX - 1);
+    /// # }
+    /// ```
+    pub fn incr_saturating<T: Ohmer>(&self, obj: &T, prop: &str, incr: i64, r: &OhmerConnection) -> Result<i64, OhmerError> {
+        try!(check_writable(r));
         let key = try!(self.get_key(obj, prop));
-        Ok(try!(r.incr(key, incr)))
+        redis::Script::new(INCR_SATURATING).key(key).arg(incr).invoke(r.conn()).map_err(Counter::incr_error)
+    }
+
+    /// Increments the counter by `incr` and reads `field` of the object in
+    /// the same atomic step, returning the new counter value and the field.
+    /// The field must be stored in the object hash: if it is missing, for
+    /// example because it is `None`, nothing is incremented and an error is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Counter, Ohmer};
+    /// model!(
+    ///     Photo {
+    ///         caption:String = "".to_string();
+    ///         likes: Counter = Counter;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let photo = create!(Photo { caption: "Sunset".to_string(), }, &client).unwrap();
+    /// let (likes, caption) = photo.likes.incr_and_get_field(&photo, "likes", 1, "caption", &client).unwrap();
+    /// assert_eq!(likes, 1);
+    /// assert_eq!(&*caption, "Sunset");
+    ///
+    /// assert!(photo.likes.incr_and_get_field(&photo, "likes", 1, "location", &client).is_err());
+    /// assert_eq!(photo.likes.get(&photo, "likes", &client).unwrap(), 1);
+    /// # }
+    /// ```
+    pub fn incr_and_get_field<T: Ohmer>(&self, obj: &T, prop: &str, incr: i64, field: &str, r: &OhmerConnection) -> Result<(i64, String), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.get_key(obj, prop));
+        let hash = try!(obj.key());
+        redis::Script::new(INCR_AND_GET_FIELD).key(key).key(hash).arg(incr).arg(field)
+            .invoke(r.conn()).map_err(Counter::incr_error)
+    }
+
+    /// Translates the error Redis gives when an increment overflows.
+    fn incr_error(e: redis::RedisError) -> OhmerError {
+        if e.kind() == redis::ErrorKind::ResponseError && format!("{}", e).contains("would overflow") {
+            OhmerError::CounterOverflow
+        } else {
+            OhmerError::RedisError(e)
+        }
     }
 
     /// Gets the current counter value.
-    pub fn get<T: Ohmer>(&self, obj: &T, prop: &str, r: &redis::Client) -> Result<i64, OhmerError> {
+    pub fn get<T: Ohmer>(&self, obj: &T, prop: &str, r: &OhmerConnection) -> Result<i64, OhmerError> {
+        let key = try!(self.get_key(obj, prop));
+        let r:Option<i64> = try!(redis::cmd("GET").arg(key).query(r.conn()));
+        Ok(r.unwrap_or(0))
+    }
+
+    /// Sets the counter to `value`.
+    pub fn set<T: Ohmer>(&self, obj: &T, prop: &str, value: i64, r: &OhmerConnection) -> Result<(), OhmerError> {
+        try!(check_writable(r));
+        let key = try!(self.get_key(obj, prop));
+        Ok(try!(redis::cmd("SET").arg(key).arg(value).query(r.conn())))
+    }
+
+    /// Gets the current counter value and sets it to 0 in the same atomic
+    /// step, so no increment made by another client in between is lost.
+    /// Meant for counting in windows of time, like requests per minute.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create, incr)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Counter, Ohmer};
+    /// model!(
+    ///     Endpoint {
+    ///         path:String = "".to_string();
+    ///         hits: Counter = Counter;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let endpoint = create!(Endpoint { path: "/login".to_string(), }, &client).unwrap();
+    /// incr!(endpoint.hits, 3, &client).unwrap();
+    /// assert_eq!(endpoint.hits.get_and_reset(&endpoint, "hits", &client).unwrap(), 3);
+    /// assert_eq!(endpoint.hits.get_and_reset(&endpoint, "hits", &client).unwrap(), 0);
+    ///
+    /// endpoint.hits.set(&endpoint, "hits", 40, &client).unwrap();
+    /// assert_eq!(incr!(endpoint.hits, 2, &client).unwrap(), 42);
+    /// # }
+    /// ```
+    pub fn get_and_reset<T: Ohmer>(&self, obj: &T, prop: &str, r: &OhmerConnection) -> Result<i64, OhmerError> {
+        try!(check_writable(r));
         let key = try!(self.get_key(obj, prop));
-        let r:Option<i64> = try!(r.get(key));
+        let r:Option<i64> = try!(redis::cmd("GETSET").arg(key).arg(0).query(r.conn()));
         Ok(r.unwrap_or(0))
     }
 }
 
+/// Gets the current value of many counters of an object in a single round
+/// trip. Counters that were never incremented are 0.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, incr)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Counter};
+/// model!(
+///     Post {
+///         votes: Counter = Counter;
+///         views: Counter = Counter;
+///         shares: Counter = Counter;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let post = create!(Post {}, &client).unwrap();
+/// incr!(post.votes, 3, &client).unwrap();
+/// incr!(post.views, 10, &client).unwrap();
+/// let counters = ohmers::counters(&post, &["votes", "views", "shares"], &client).unwrap();
+/// assert_eq!(counters["votes"], 3);
+/// assert_eq!(counters["views"], 10);
+/// assert_eq!(counters["shares"], 0);
+/// # }
+/// ```
+pub fn counters<T: Ohmer>(obj: &T, props: &[&str], r: &OhmerConnection) -> Result<HashMap<String, i64>, OhmerError> {
+    if props.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut q = redis::pipe();
+    for prop in props {
+        q.cmd("GET").arg(try!(Counter.get_key(obj, prop)));
+    }
+    let values:Vec<Option<i64>> = try!(q.query(r.conn()));
+    Ok(props.iter().map(|p| p.to_string()).zip(values.into_iter().map(|v| v.unwrap_or(0))).collect())
+}
+
 #[macro_export]
 macro_rules! counter {
     ($obj: ident.$prop: ident, $client: expr) => {{
@@ -1261,6 +5303,140 @@ macro_rules! decr {
     }}
 }
 
+/// Approximate count of distinct elements, using a HyperLogLog. It takes a
+/// few kilobytes no matter how many elements are added, with a standard
+/// error of 0.81%.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create, pfadd, pfcount)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use redis::Commands;
+/// # use ohmers::{Ohmer, HllCounter};
+/// model!(
+///     Campaign {
+///         visitors: HllCounter = HllCounter;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let campaign = create!(Campaign {}, &client).unwrap();
+/// assert!(pfadd!(campaign.visitors, "alice", &client).unwrap());
+/// assert!(pfadd!(campaign.visitors, "bob", &client).unwrap());
+/// assert!(!pfadd!(campaign.visitors, "alice", &client).unwrap());
+/// assert_eq!(pfcount!(campaign.visitors, &client).unwrap(), 2);
+///
+/// let key = format!("Campaign:{}:visitors", campaign.id);
+/// campaign.delete(&client).unwrap();
+/// assert!(!client.exists::<_, bool>(key).unwrap());
+/// # }
+/// ```
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Clone)]
+pub struct HllCounter;
+
+impl HllCounter {
+    /// Adds `element`. Returns whether the approximate count changed.
+    pub fn add<T: Ohmer, E: ToRedisArgs>(&self, obj: &T, prop: &str, element: E, r: &OhmerConnection) -> Result<bool, OhmerError> {
+        try!(check_writable(r));
+        let key = try!(Counter.get_key(obj, prop));
+        Ok(try!(redis::cmd("PFADD").arg(key).arg(element).query(r.conn())))
+    }
+
+    /// Gets the approximate number of distinct elements added.
+    pub fn count<T: Ohmer>(&self, obj: &T, prop: &str, r: &OhmerConnection) -> Result<usize, OhmerError> {
+        let key = try!(Counter.get_key(obj, prop));
+        Ok(try!(redis::cmd("PFCOUNT").arg(key).query(r.conn())))
+    }
+}
+
+/// Adds `$el` to `$obj.$prop`. The property must be a HllCounter.
+#[macro_export]
+macro_rules! pfadd {
+    ($obj: ident.$prop: ident, $el: expr, $conn: expr) => {{
+        $obj.$prop.add(&$obj, stringify!($prop), $el, &$conn)
+    }}
+}
+
+/// Counts the distinct elements in `$obj.$prop`. The property must be a
+/// HllCounter.
+#[macro_export]
+macro_rules! pfcount {
+    ($obj: ident.$prop: ident, $conn: expr) => {{
+        $obj.$prop.count(&$obj, stringify!($prop), &$conn)
+    }}
+}
+
+/// Binary data. Hash values are read as strings, so the bytes are stored
+/// base64 encoded to survive values that are not valid UTF-8.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Binary, Ohmer};
+/// model!(
+///     Avatar {
+///         thumbnail:Binary = Binary::default();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let avatar = create!(Avatar { thumbnail: Binary(vec![0x89, 0x50, 0x4e, 0x47, 0xff, 0x00]), }, &client).unwrap();
+/// let copy = ohmers::get::<Avatar>(avatar.id, &client).unwrap();
+/// assert_eq!(&*copy.thumbnail, &[0x89, 0x50, 0x4e, 0x47, 0xff, 0x00]);
+/// # }
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Binary(pub Vec<u8>);
+
+impl std::ops::Deref for Binary {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &*self.0
+    }
+}
+
+impl From<Vec<u8>> for Binary {
+    fn from(v: Vec<u8>) -> Binary {
+        Binary(v)
+    }
+}
+
+impl rustc_serialize::Encodable for Binary {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&*self.0.to_base64(base64::STANDARD))
+    }
+}
+
+impl rustc_serialize::Decodable for Binary {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D) -> Result<Binary, D::Error> {
+        let s = try!(d.read_str());
+        s.from_base64().map(Binary).map_err(|e| d.error(&*format!("{}", e)))
+    }
+}
+
+/// The direction of a sort. See `Query::sort_by`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SortOrder {
+    /// Smallest first.
+    Asc,
+    /// Largest first.
+    Desc,
+}
+
+/// How a sort compares values. See `Query::sort_by`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SortMode {
+    /// As numbers (doubles). Values that are not numbers make the sort
+    /// fail.
+    Numeric,
+    /// As strings, byte by byte.
+    Alpha,
+}
+
 /// A query of a set, or a result of set operations.
 ///
 /// # Examples
@@ -1297,30 +5473,136 @@ macro_rules! decr {
 /// ```
 pub struct Query<'a, T: 'a + Ohmer> {
     set: stal::Set,
-    r: &'a redis::Client,
+    r: &'a OhmerConnection,
     phantom: PhantomData<T>,
+    /// Commands creating temporary keys used by `set`.
+    setup: Vec<Vec<Vec<u8>>>,
+    /// Temporary keys deleted after the query runs.
+    temporary: Vec<Vec<u8>>,
 }
 
 impl<'a, T: Ohmer> Query<'a, T> {
     /// Create a new Query for a Set
-    pub fn new(set: stal::Set, r: &'a redis::Client) -> Self {
-        Query { set: set, phantom: PhantomData, r: r }
+    pub fn new(set: stal::Set, r: &'a OhmerConnection) -> Self {
+        Query { set: set, phantom: PhantomData, r: r, setup: vec![], temporary: vec![] }
     }
 
     /// Creates a new query with the intersection of all key/value
-    pub fn from_keys(kv: &[(&str, &str)], r: &'a redis::Client) -> Self {
+    pub fn from_keys(kv: &[(&str, &str)], r: &'a OhmerConnection) -> Self {
         let set = stal::Set::Inter(kv.iter().map(|kv| Query::<T>::key(kv.0, kv.1)).collect());
         Query::new(set, r)
     }
 
-    /// Creates the stal set for a key/value combination
+    /// Creates the stal set for a key/value combination. The value goes
+    /// through `Ohmer::index_value` first.
     pub fn key(field: &str, value: &str) -> stal::Set {
-        stal::Set::Key(T::default().key_for_index(field, value).as_bytes().to_vec())
+        let default = T::default();
+        stal::Set::Key(default.key_for_index(&*stored_name(&default, field), &*default.index_value(field, value)).as_bytes().to_vec())
     }
 
     /// Creates a query for a key/value combination
-    pub fn find(field: &str, value: &str, r: &'a redis::Client) -> Self {
-        Query { set: Query::<T>::key(field, value), phantom: PhantomData, r: r }
+    pub fn find(field: &str, value: &str, r: &'a OhmerConnection) -> Self {
+        Query::new(Query::<T>::key(field, value), r)
+    }
+
+    /// Creates a query for the objects with the given ids, so a list of
+    /// ids from somewhere else can be filtered with indices. The ids are
+    /// copied to a temporary set when the query runs. Ids without an
+    /// object are left out, unless the class does not `track_all`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// model!(
+    ///     Wallet {
+    ///         indices { active:bool = true; };
+    ///         owner:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let a = create!(Wallet { owner: "ann".to_string(), }, &client).unwrap();
+    /// let b = create!(Wallet { owner: "bob".to_string(), active: false, }, &client).unwrap();
+    /// let c = create!(Wallet { owner: "cid".to_string(), }, &client).unwrap();
+    ///
+    /// let active = Query::<Wallet>::from_ids(&[a.id, b.id], &client)
+    ///     .inter("active", "1").try_iter().unwrap().collect::<Vec<_>>();
+    /// assert_eq!(active, vec![a]);
+    /// assert!(!Query::<Wallet>::from_ids(&[b.id, c.id], &client).contains_id(a.id).unwrap());
+    /// assert_eq!(Query::<Wallet>::from_ids(&[], &client).try_iter().unwrap().count(), 0);
+    /// # }
+    /// ```
+    pub fn from_ids(ids: &[usize], r: &'a OhmerConnection) -> Self {
+        let default = T::default();
+        let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+        ::std::hash::Hash::hash(ids, &mut hasher);
+        let temporary = format!("{}:ids:{:x}", default.get_class_name(),
+                ::std::hash::Hasher::finish(&hasher)).into_bytes();
+        let set = if default.track_all() {
+            stal::Set::Inter(vec![stal::Set::Key(temporary.clone()), stal::Set::Key(default.all_key().into_bytes())])
+        } else {
+            stal::Set::Key(temporary.clone())
+        };
+        let mut query = Query::new(set, r);
+        // With no ids the key is never created, and the query is empty.
+        if !ids.is_empty() {
+            let mut sadd = vec![b"SADD".to_vec(), temporary.clone()];
+            sadd.extend(ids.iter().map(|id| format!("{}", id).into_bytes()));
+            query.setup.push(sadd);
+        }
+        query.temporary.push(temporary);
+        query
+    }
+
+    /// Creates a query for the elements with no value in the index of
+    /// `field`, which is `<Class>:all` minus the sets of every indexed
+    /// value.
+    ///
+    /// Only `None` counts as missing: default values like `""` or `0` are
+    /// indexed like any other value, and can be found with
+    /// `find(field, "")`. The indexed values are found with `SCAN` when
+    /// the query is created.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// model!(
+    ///     Customer {
+    ///         indices { email:Option<String> = None; };
+    ///         name:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let alice = create!(Customer { name: "Alice".to_string(), email: Some("alice@example.com".to_string()), }, &client).unwrap();
+    /// let bob = create!(Customer { name: "Bob".to_string(), }, &client).unwrap();
+    /// let carol = create!(Customer { name: "Carol".to_string(), email: Some("".to_string()), }, &client).unwrap();
+    ///
+    /// let missing = Query::<Customer>::missing("email", &client).unwrap()
+    ///     .try_iter().unwrap().collect::<Vec<_>>();
+    /// assert!(missing.contains(&bob));
+    /// assert!(!missing.contains(&alice));
+    /// assert!(!missing.contains(&carol));
+    /// # }
+    /// ```
+    pub fn missing(field: &str, r: &'a OhmerConnection) -> Result<Self, OhmerError> {
+        let all = stal::Set::Key(T::default().all_key().into_bytes());
+        let values = try!(index_values::<T>(field, r));
+        if values.is_empty() {
+            return Ok(Query::new(all, r));
+        }
+        // The values were already transformed when they were indexed.
+        let default = T::default();
+        let indexed = stal::Set::Union(values.iter()
+                .map(|value| stal::Set::Key(default.key_for_index(&*stored_name(&default, field), value).into_bytes()))
+                .collect());
+        Ok(Query::new(stal::Set::Diff(vec![all, indexed]), r))
     }
 
     /// Updates the set to be the intersection of the current one and
@@ -1353,6 +5635,63 @@ impl<'a, T: Ohmer> Query<'a, T> {
         self.set = stal::Set::Union(sets);
     }
 
+    /// Leaves out the objects marked by `Ohmer::soft_delete`. Does nothing
+    /// if the model has no `soft_delete_field`. Marked objects are already
+    /// out of every other index, so this is only needed for queries that
+    /// start from `all_query`, like `all` does.
+    pub fn exclude_deleted(&mut self) -> &mut Self {
+        if let Some(field) = T::default().soft_delete_field() {
+            self.diff(field, "1");
+        }
+        self
+    }
+
+    /// Adds the elements where `field`=`value`, like `union`. Reads like
+    /// `find!` when building a query in code.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Hound {
+    ///         indices {
+    ///             color:String = "".to_string();
+    ///             trained:bool = false;
+    ///         };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # for key in ["Hound:indices:color:black", "Hound:indices:color:white", "Hound:indices:trained:1"].iter() {
+    /// #     let _:bool = client.del(*key).unwrap();
+    /// # }
+    /// let rex = create!(Hound { color: "black".to_string(), trained: true, }, &client).unwrap();
+    /// create!(Hound { color: "black".to_string(), }, &client).unwrap();
+    /// let max = create!(Hound { color: "white".to_string(), trained: true, }, &client).unwrap();
+    /// create!(Hound { color: "brown".to_string(), trained: true, }, &client).unwrap();
+    ///
+    /// let mut trained = Query::<Hound>::find("color", "black", &client)
+    ///     .or_find("color", "white")
+    ///     .and_find("trained", "1")
+    ///     .try_iter().unwrap().collect::<Vec<_>>();
+    /// trained.sort_by_key(|hound| hound.id);
+    /// assert_eq!(trained, vec![rex, max]);
+    /// # }
+    /// ```
+    pub fn or_find(&mut self, field: &str, value: &str) -> &mut Self {
+        self.union(field, value)
+    }
+
+    /// Keeps only the elements where `field`=`value`, like `inter`. See
+    /// `or_find`.
+    pub fn and_find(&mut self, field: &str, value: &str) -> &mut Self {
+        self.inter(field, value)
+    }
+
     /// Updates the set to remove all elements where `field`=`value`.
     pub fn diff(&mut self, field: &str, value: &str) -> &mut Self {
         self.sdiff(vec![Query::<T>::key(field, value)]);
@@ -1366,27 +5705,661 @@ impl<'a, T: Ohmer> Query<'a, T> {
         self.set = stal::Set::Diff(sets);
     }
 
+    /// Combines two queries into one with the elements present in both.
+    pub fn combine_inter(self, other: Query<'a, T>) -> Query<'a, T> {
+        self.combine(other, |a, b| stal::Set::Inter(vec![a, b]))
+    }
+
+    /// Combines two queries into one with the elements present in either.
+    pub fn combine_union(self, other: Query<'a, T>) -> Query<'a, T> {
+        self.combine(other, |a, b| stal::Set::Union(vec![a, b]))
+    }
+
+    /// Combines two queries into one with the elements of this query that
+    /// are not present in `other`.
+    pub fn combine_diff(self, other: Query<'a, T>) -> Query<'a, T> {
+        self.combine(other, |a, b| stal::Set::Diff(vec![a, b]))
+    }
+
+    /// Removes the elements of another query, like `combine_diff`. Unlike
+    /// `diff`, which removes the elements with a single value, `other` can
+    /// be any query, like a cohort built from several indices.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Patron {
+    ///         indices {
+    ///             active:bool = false;
+    ///             cohort:String = "".to_string();
+    ///         };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # for key in ["Patron:indices:active:1", "Patron:indices:cohort:beta", "Patron:indices:cohort:alpha"].iter() {
+    /// #     let _:bool = client.del(*key).unwrap();
+    /// # }
+    /// let regular = create!(Patron { active: true, }, &client).unwrap();
+    /// create!(Patron { active: true, cohort: "beta".to_string(), }, &client).unwrap();
+    /// create!(Patron { active: true, cohort: "alpha".to_string(), }, &client).unwrap();
+    /// create!(Patron { cohort: "beta".to_string(), }, &client).unwrap();
+    ///
+    /// let mut testers = Query::<Patron>::find("cohort", "beta", &client);
+    /// testers.union("cohort", "alpha");
+    /// let active = Query::<Patron>::find("active", "1", &client).without(testers);
+    /// assert_eq!(active.into_vec().unwrap(), vec![regular]);
+    /// # }
+    /// ```
+    pub fn without(self, other: Query<'a, T>) -> Query<'a, T> {
+        self.combine_diff(other)
+    }
+
+    /// Combines the sets of two queries, keeping the temporary keys both
+    /// depend on.
+    fn combine<F: FnOnce(stal::Set, stal::Set) -> stal::Set>(self, other: Query<'a, T>, f: F) -> Query<'a, T> {
+        let mut query = Query::new(f(self.set, other.set), self.r);
+        query.setup = self.setup;
+        query.setup.extend(other.setup);
+        query.temporary = self.temporary;
+        query.temporary.extend(other.temporary);
+        query
+    }
+
+    /// Adds the commands creating and deleting the temporary keys to the
+    /// operations solving the set. They run inside the same MULTI/EXEC, so
+    /// temporary keys are never visible to other clients.
+    fn wrap_ops(&self, ops: (Vec<Vec<Vec<u8>>>, usize)) -> (Vec<Vec<Vec<u8>>>, usize) {
+        if self.setup.is_empty() && self.temporary.is_empty() {
+            return ops;
+        }
+        let (mut ops, pos) = ops;
+        let exec = ops.pop().unwrap();
+        let multi = ops.remove(0);
+
+        let mut wrapped = vec![multi];
+        wrapped.extend(self.setup.iter().cloned());
+        wrapped.extend(ops);
+        if !self.temporary.is_empty() {
+            let mut del = vec![b"DEL".to_vec()];
+            del.extend(self.temporary.iter().cloned());
+            wrapped.push(del);
+        }
+        wrapped.push(exec);
+        (wrapped, pos + self.setup.len())
+    }
+
     /// Creates an iterator for all objects in the set.
     pub fn try_iter(&self) -> Result<Iter<'a, T>, OhmerError> {
-        Iter::from_ops(self.set.ids().solve(), self.r)
+        Iter::from_ops(self.wrap_ops(self.set.ids().solve()), self.r)
+    }
+
+    /// Like `try_iter`, but the ids are stored in `<Class>:cache:<key>`
+    /// for `ttl`, and later calls with the same `key` use them instead of
+    /// running the query again until they expire. The query is not
+    /// compared: `key` must identify it.
+    ///
+    /// Nothing invalidates the cache on its own, so saving or deleting
+    /// objects does not change the results until `ttl` passes or
+    /// `invalidate_query_cache` is called. Objects deleted meanwhile stop
+    /// the iterator early, see `Iter`. On a read-only connection the query
+    /// runs but its result is not stored.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// use std::time::Duration;
+    ///
+    /// model!(
+    ///     Shipment {
+    ///         indices { status:String = "".to_string(); };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Shipment:indices:status:pending").unwrap();
+    /// # ohmers::invalidate_query_cache::<Shipment>("pending", &client).unwrap();
+    /// let pending = || Query::<Shipment>::find("status", "pending", &client)
+    ///     .cached("pending", Duration::from_secs(60)).unwrap().count();
+    ///
+    /// create!(Shipment { status: "pending".to_string(), }, &client).unwrap();
+    /// assert_eq!(pending(), 1);
+    /// create!(Shipment { status: "pending".to_string(), }, &client).unwrap();
+    /// assert_eq!(pending(), 1);
+    ///
+    /// assert!(ohmers::invalidate_query_cache::<Shipment>("pending", &client).unwrap());
+    /// assert_eq!(pending(), 2);
+    /// # }
+    /// ```
+    pub fn cached(&self, key: &str, ttl: Duration) -> Result<Iter<'a, T>, OhmerError> {
+        let cache = query_cache_key::<T>(key);
+        let cached:Option<String> = try!(with_retry(self.r, || Ok(try!(redis::cmd("GET").arg(&*cache).query(self.r.conn())))));
+        let ids:Vec<usize> = match cached {
+            Some(ids) => ids.split(',').filter(|id| !id.is_empty()).filter_map(|id| id.parse().ok()).collect(),
+            None => {
+                let ids:Vec<usize> = try!(run_ops(self.wrap_ops(self.set.ids().solve()), self.r));
+                if !self.r.read_only() {
+                    let value = ids.iter().map(|id| format!("{}", id)).collect::<Vec<_>>().join(",");
+                    let millis = ttl.as_secs() * 1000 + (ttl.subsec_nanos() / 1_000_000) as u64;
+                    let _:() = try!(redis::cmd("SET").arg(&*cache).arg(value)
+                            .arg("PX").arg(std::cmp::max(millis, 1)).query(self.r.conn()));
+                }
+                ids
+            },
+        };
+        Ok(Iter::new(ids.into_iter(), self.r))
     }
 
     /// Creates an iterator for all objects in the set, consuming the query.
-    pub fn try_into_iter(self) -> Result<Iter<'a, T>, OhmerError> {
-        Iter::from_ops(self.set.into_ids().solve(), self.r)
+    /// The iterator stops at the first object that fails to load; use
+    /// `into_vec` to get an error instead.
+    pub fn try_into_iter(mut self) -> Result<Iter<'a, T>, OhmerError> {
+        let set = replace(&mut self.set, stal::Set::Key(vec![]));
+        let ops = self.wrap_ops(set.into_ids().solve());
+        Iter::from_ops(ops, self.r)
+    }
+
+    /// Loads all the objects in the set, failing if any of them cannot be
+    /// loaded, with `NotFound` if it was deleted after the query ran. The
+    /// objects are fetched in a single round trip. This is the
+    /// recommended way to get the results of a query unless they are too
+    /// many to keep in memory, see `each`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Sample {
+    ///         indices { batch:u8 = 0; };
+    ///         value:u8 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Sample:indices:batch:7").unwrap();
+    /// create!(Sample { batch: 7, value: 1, }, &client).unwrap();
+    /// let broken = create!(Sample { batch: 7, value: 2, }, &client).unwrap();
+    /// assert_eq!(Query::<Sample>::find("batch", "7", &client).into_vec().unwrap().len(), 2);
+    ///
+    /// let _:() = client.hset(format!("Sample:{}", broken.id), "value", "lots").unwrap();
+    /// assert!(Query::<Sample>::find("batch", "7", &client).into_vec().is_err());
+    /// assert!(Query::<Sample>::find("batch", "7", &client).try_iter().unwrap().count() < 2);
+    /// # }
+    /// ```
+    pub fn into_vec(mut self) -> Result<Vec<T>, OhmerError> {
+        let set = replace(&mut self.set, stal::Set::Key(vec![]));
+        let ids: Vec<usize> = try!(run_ops(self.wrap_ops(set.into_ids().solve()), self.r));
+        load_all(&*ids, self.r)
+    }
+
+    /// Calls `f` with every object in the set, stopping at the first error
+    /// from either loading the objects or `f`. Objects are loaded 100 at a
+    /// time, each batch in one round trip. Objects deleted after the query
+    /// runs are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, OhmerError, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Order {
+    ///         indices { status:String = "".to_string(); };
+    ///         total:u32 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Order:indices:status:paid").unwrap();
+    /// for total in 1..251 {
+    ///     create!(Order { status: "paid".to_string(), total: total, }, &client).unwrap();
+    /// }
+    /// let paid = Query::<Order>::find("status", "paid", &client);
+    ///
+    /// let mut sum = 0;
+    /// paid.each(|order| { sum += order.total; Ok(()) }).unwrap();
+    /// assert_eq!(sum, 250 * 251 / 2);
+    ///
+    /// let mut batches = vec![];
+    /// paid.each_batch(100, |orders| { batches.push(orders.len()); Ok(()) }).unwrap();
+    /// assert_eq!(batches, vec![100, 100, 50]);
+    ///
+    /// let mut seen = 0;
+    /// let result = paid.each(|_| {
+    ///     seen += 1;
+    ///     if seen == 3 { Err(OhmerError::NotSaved) } else { Ok(()) }
+    /// });
+    /// assert_eq!(result, Err(OhmerError::NotSaved));
+    /// assert_eq!(seen, 3);
+    /// # }
+    /// ```
+    pub fn each<F: FnMut(T) -> Result<(), OhmerError>>(&self, mut f: F) -> Result<(), OhmerError> {
+        self.each_batch(100, |objs| {
+            for obj in objs {
+                try!(f(obj));
+            }
+            Ok(())
+        })
+    }
+
+    /// Calls `f` with the objects in the set, `n` at a time, stopping at the
+    /// first error. Each batch is loaded in one round trip, and objects
+    /// deleted after the query runs are skipped, so a batch may be smaller
+    /// than `n`.
+    pub fn each_batch<F: FnMut(Vec<T>) -> Result<(), OhmerError>>(&self, n: usize, mut f: F) -> Result<(), OhmerError> {
+        let ids: Vec<usize> = try!(run_ops(self.wrap_ops(self.set.ids().solve()), self.r));
+        for chunk in ids.chunks(std::cmp::max(n, 1)) {
+            let objs = try!(load_many(chunk, self.r)).into_iter().filter_map(|obj| obj).collect::<Vec<_>>();
+            if !objs.is_empty() {
+                try!(f(objs));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets a random element of the set, or `None` if it is empty. Fails
+    /// if the element cannot be loaded, with `NotFound` if it was deleted
+    /// after the query ran.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Quote {
+    ///         indices { featured:bool = false; };
+    ///         text:String = "".to_string();
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Quote:indices:featured:1").unwrap();
+    /// assert!(Query::<Quote>::find("featured", "1", &client).random().unwrap().is_none());
+    /// let q1 = create!(Quote { text: "Veni, vidi, vici".to_string(), featured: true, }, &client).unwrap();
+    /// let q2 = create!(Quote { text: "Carpe diem".to_string(), featured: true, }, &client).unwrap();
+    /// create!(Quote { text: "Alea iacta est".to_string(), }, &client).unwrap();
+    ///
+    /// let quote = Query::<Quote>::find("featured", "1", &client).random().unwrap().unwrap();
+    /// assert!(quote == q1 || quote == q2);
+    /// assert_eq!(Query::<Quote>::find("featured", "1", &client).random_n(5).unwrap().count(), 2);
+    /// # }
+    /// ```
+    pub fn random(&self) -> Result<Option<T>, OhmerError> {
+        let ids = try!(self.sample_ids(1, false));
+        Ok(try!(load_all(&*ids, self.r)).pop())
+    }
+
+    /// Creates an iterator for up to `count` distinct random elements of
+    /// the set. See `sample_ids` to get only their ids.
+    pub fn random_n(&self, count: usize) -> Result<Iter<'a, T>, OhmerError> {
+        let template = vec![b"SRANDMEMBER".to_vec(), vec![], format!("{}", count).into_bytes()];
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        Iter::from_ops(self.wrap_ops(stal.solve()), self.r)
+    }
+
+    /// Gets the ids of `count` random elements of the set, without loading
+    /// them. Without `repeats`, the ids are distinct, and there are fewer
+    /// if the set is smaller (`SRANDMEMBER key count`). With `repeats`, an
+    /// id may come up more than once, and there are always `count` of them
+    /// unless the set is empty (`SRANDMEMBER key -count`).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Raffle {
+    ///         indices { prize:String = "".to_string(); };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Raffle:indices:prize:car").unwrap();
+    /// let a = create!(Raffle { prize: "car".to_string(), }, &client).unwrap();
+    /// let b = create!(Raffle { prize: "car".to_string(), }, &client).unwrap();
+    ///
+    /// let cars = Query::<Raffle>::find("prize", "car", &client);
+    /// let mut distinct = cars.sample_ids(5, false).unwrap();
+    /// distinct.sort();
+    /// assert_eq!(distinct, vec![a.id, b.id]);
+    ///
+    /// let repeated = cars.sample_ids(5, true).unwrap();
+    /// assert_eq!(repeated.len(), 5);
+    /// assert!(repeated.iter().all(|id| *id == a.id || *id == b.id));
+    /// # }
+    /// ```
+    pub fn sample_ids(&self, count: usize, repeats: bool) -> Result<Vec<usize>, OhmerError> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let count = if repeats { format!("-{}", count) } else { format!("{}", count) };
+        let template = vec![b"SRANDMEMBER".to_vec(), vec![], count.into_bytes()];
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        run_ops(self.wrap_ops(stal.solve()), self.r)
+    }
+
+    /// Whether the object `id` is in the set, without loading any object.
+    /// A set combining others is stored in a temporary key, which is
+    /// deleted in the same transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// model!(
+    ///     Listing {
+    ///         indices {
+    ///             city:String = "".to_string();
+    ///             open:bool = false;
+    ///         };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let shop = create!(Listing { city: "Oslo".to_string(), open: true, }, &client).unwrap();
+    /// let cafe = create!(Listing { city: "Oslo".to_string(), }, &client).unwrap();
+    ///
+    /// let oslo = Query::<Listing>::find("city", "Oslo", &client);
+    /// assert!(oslo.contains_id(shop.id).unwrap());
+    /// assert!(oslo.contains_id(cafe.id).unwrap());
+    ///
+    /// let mut open = Query::<Listing>::find("city", "Oslo", &client);
+    /// open.inter("open", "1");
+    /// assert!(open.contains_id(shop.id).unwrap());
+    /// assert!(!open.contains_id(cafe.id).unwrap());
+    /// # }
+    /// ```
+    pub fn contains_id(&self, id: usize) -> Result<bool, OhmerError> {
+        let template = vec![b"SISMEMBER".to_vec(), vec![], format!("{}", id).into_bytes()];
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        run_ops(self.wrap_ops(stal.solve()), self.r)
+    }
+
+    /// Number of objects in the set. Composite sets are stored in a
+    /// temporary key to count them, a single index is counted with one
+    /// `SCARD`.
+    pub fn len(&self) -> Result<usize, OhmerError> {
+        let template = vec![b"SCARD".to_vec(), vec![]];
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        run_ops(self.wrap_ops(stal.solve()), self.r)
+    }
+
+    /// Whether the set has no objects, without loading or sorting them.
+    /// See `len`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Vacancy {
+    ///         indices {
+    ///             team:String = "".to_string();
+    ///             remote:bool = false;
+    ///         };
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Vacancy:indices:team:infra").unwrap();
+    /// assert!(Query::<Vacancy>::find("team", "infra", &client).is_empty().unwrap());
+    /// create!(Vacancy { team: "infra".to_string(), }, &client).unwrap();
+    /// create!(Vacancy { team: "infra".to_string(), }, &client).unwrap();
+    ///
+    /// let infra = Query::<Vacancy>::find("team", "infra", &client);
+    /// assert!(!infra.is_empty().unwrap());
+    /// assert_eq!(infra.len().unwrap(), 2);
+    ///
+    /// let mut remote = Query::<Vacancy>::find("team", "infra", &client);
+    /// remote.inter("remote", "1");
+    /// assert!(remote.is_empty().unwrap());
+    /// # }
+    /// ```
+    pub fn is_empty(&self) -> Result<bool, OhmerError> {
+        Ok(try!(self.len()) == 0)
     }
 
     /// Creates an iterator for all objects in the set sorted by `by`.
+    /// Unless `alpha` is set, Redis compares the values as doubles, so
+    /// 64 bits integers that differ only past the 53rd bit may compare
+    /// equal.
+    ///
+    /// A counter that was never incremented has no key. Redis sorts it as
+    /// 0, the value `Counter::get` returns, unless `alpha` is set: then it
+    /// sorts before every value, even negative ones.
     pub fn sort(&self, by: &str, limit: Option<(usize, usize)>, asc: bool, alpha: bool) -> Result<Iter<'a, T>, OhmerError> {
+        let template = Query::<T>::sort_template(by, limit, asc, alpha);
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        Iter::from_ops(self.wrap_ops(stal.solve()), self.r)
+    }
+
+    /// Creates an iterator for all objects in the set sorted by `by`, like
+    /// `sort` with no limit, naming the order and the comparison instead
+    /// of passing two `bool`s.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query, SortMode, SortOrder};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Mountain {
+    ///         indices { range:String = "".to_string(); };
+    ///         name:String = "".to_string();
+    ///         height:u16 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Mountain:indices:range:Alps").unwrap();
+    /// create!(Mountain { range: "Alps".to_string(), name: "Eiger".to_string(), height: 3967, }, &client).unwrap();
+    /// create!(Mountain { range: "Alps".to_string(), name: "Matterhorn".to_string(), height: 4478, }, &client).unwrap();
+    /// create!(Mountain { range: "Alps".to_string(), name: "Mont Blanc".to_string(), height: 4808, }, &client).unwrap();
+    ///
+    /// let alps = Query::<Mountain>::find("range", "Alps", &client);
+    /// let names = alps.sort_by("name", SortOrder::Asc, SortMode::Alpha).unwrap()
+    ///     .map(|m| m.name).collect::<Vec<_>>();
+    /// assert_eq!(names, vec!["Eiger", "Matterhorn", "Mont Blanc"]);
+    ///
+    /// let highest = alps.sort_by_limit("height", SortOrder::Desc, SortMode::Numeric, 0, 1).unwrap()
+    ///     .map(|m| m.name).collect::<Vec<_>>();
+    /// assert_eq!(highest, vec!["Mont Blanc"]);
+    /// # }
+    /// ```
+    pub fn sort_by(&self, by: &str, order: SortOrder, mode: SortMode) -> Result<Iter<'a, T>, OhmerError> {
+        self.sort(by, None, order == SortOrder::Asc, mode == SortMode::Alpha)
+    }
+
+    /// Like `sort_by`, returning only `count` objects starting at
+    /// `offset`.
+    pub fn sort_by_limit(&self, by: &str, order: SortOrder, mode: SortMode, offset: usize, count: usize) -> Result<Iter<'a, T>, OhmerError> {
+        self.sort(by, Some((offset, count)), order == SortOrder::Asc, mode == SortMode::Alpha)
+    }
+
+    /// Sorts the set like `sort`, but instead of loading the objects it
+    /// returns only the id and the given `fields` of each one. Fields
+    /// without a value are left out of the row.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create, incr)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Counter, Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Planet {
+    ///         indices { rocky:bool = false; };
+    ///         name:String = "".to_string();
+    ///         moons:u32 = 0;
+    ///         probes:Counter = Counter;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Planet:indices:rocky:1").unwrap();
+    /// let earth = create!(Planet { name: "Earth".to_string(), rocky: true, moons: 1, }, &client).unwrap();
+    /// let mars = create!(Planet { name: "Mars".to_string(), rocky: true, moons: 2, }, &client).unwrap();
+    /// create!(Planet { name: "Jupiter".to_string(), moons: 79, }, &client).unwrap();
+    /// incr!(mars.probes, 3, client).unwrap();
+    ///
+    /// let rows = Query::<Planet>::find("rocky", "1", &client)
+    ///     .sort_get("name", &["name", "probes"], None, true, true).unwrap();
+    /// assert_eq!(rows.len(), 2);
+    /// assert_eq!(rows[0]["id"], format!("{}", earth.id));
+    /// assert_eq!(rows[0]["name"], "Earth");
+    /// assert!(!rows[0].contains_key("probes"));
+    /// assert_eq!(rows[1]["name"], "Mars");
+    /// assert_eq!(rows[1]["probes"], "3");
+    /// assert!(!rows[1].contains_key("moons"));
+    /// # }
+    /// ```
+    pub fn sort_get(&self, by: &str, fields: &[&str], limit: Option<(usize, usize)>, asc: bool, alpha: bool) -> Result<Vec<HashMap<String, String>>, OhmerError> {
+        let mut template = Query::<T>::sort_template(by, limit, asc, alpha);
+        template.push(b"GET".to_vec());
+        template.push(b"#".to_vec());
+        for field in fields {
+            template.push(b"GET".to_vec());
+            template.push(Query::<T>::sort_pattern(field));
+        }
+
+        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
+        let values: Vec<Option<String>> = try!(run_ops(self.wrap_ops(stal.solve()), self.r));
+        Ok(values.chunks(fields.len() + 1).map(|chunk| {
+            let mut row = HashMap::new();
+            if let Some(ref id) = chunk[0] {
+                row.insert("id".to_string(), id.clone());
+            }
+            for (field, value) in fields.iter().zip(chunk[1..].iter()) {
+                if let Some(ref value) = *value {
+                    row.insert(field.to_string(), value.clone());
+                }
+            }
+            row
+        }).collect())
+    }
+
+    /// Creates an iterator for all objects in the set sorted by several
+    /// fields: by the first one, then by the second one among the objects
+    /// with the same first one, and so on. Each key is a field name,
+    /// whether to sort in ascending order, and whether to compare as
+    /// strings (like `alpha` in `sort`) instead of numbers.
+    ///
+    /// Redis sorts by the first field and returns the fields of every
+    /// object, which are then sorted in the client by all the keys. Every
+    /// field of every object in the set is sent over the network and
+    /// sorted in memory before `limit` is applied, so prefer `sort` for
+    /// large sets or when one field is enough.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[macro_use(model, create)] extern crate ohmers;
+    /// # extern crate rustc_serialize;
+    /// # extern crate redis;
+    /// # use ohmers::{Ohmer, Query};
+    /// # use redis::Commands;
+    /// model!(
+    ///     Puppy {
+    ///         indices { kennel:u8 = 0; };
+    ///         name:String = "".to_string();
+    ///         age:u8 = 0;
+    ///     });
+    /// # fn main() {
+    /// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// # let _:bool = client.del("Puppy:indices:kennel:1").unwrap();
+    /// for &(name, age) in [("Ace", 1), ("Rex", 3), ("Bo", 2), ("Max", 3), ("Zed", 2)].iter() {
+    ///     create!(Puppy { kennel: 1, name: name.to_string(), age: age, }, &client).unwrap();
+    /// }
+    /// let names = |limit| Query::<Puppy>::find("kennel", "1", &client)
+    ///     .sort_multi(&[("age", false, false), ("name", true, true)], limit).unwrap()
+    ///     .map(|p| p.name).collect::<Vec<_>>();
+    /// assert_eq!(names(None), vec!["Max", "Rex", "Bo", "Zed", "Ace"]);
+    /// assert_eq!(names(Some((1, 2))), vec!["Rex", "Bo"]);
+    /// # }
+    /// ```
+    pub fn sort_multi(&self, keys: &[(&str, bool, bool)], limit: Option<(usize, usize)>) -> Result<Iter<'a, T>, OhmerError> {
+        let (by, asc, alpha) = match keys.first() {
+            Some(&key) => key,
+            None => return self.try_iter(),
+        };
+        let fields = keys.iter().map(|key| key.0).collect::<Vec<_>>();
+        let mut rows = try!(self.sort_get(by, &*fields, None, asc, alpha));
+        // sort_by is stable, so rows equal on every key keep the order
+        // given by Redis.
+        rows.sort_by(|a, b| {
+            for &(field, asc, alpha) in keys.iter() {
+                let ordering = Query::<T>::compare_sort_values(a.get(field), b.get(field), alpha);
+                let ordering = if asc { ordering } else { ordering.reverse() };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            Ordering::Equal
+        });
+        let (offset, count) = limit.unwrap_or((0, rows.len()));
+        let ids = rows.iter()
+                .filter_map(|row| row.get("id").and_then(|id| id.parse().ok()))
+                .skip(offset).take(count)
+                .collect::<Vec<usize>>();
+        Ok(Iter::new(ids.into_iter(), self.r))
+    }
+
+    /// Compares two values like `SORT` does: as strings if `alpha` is set,
+    /// and otherwise as numbers, with missing values as 0.
+    fn compare_sort_values(a: Option<&String>, b: Option<&String>, alpha: bool) -> Ordering {
+        if alpha {
+            a.map(|v| &**v).unwrap_or("").cmp(b.map(|v| &**v).unwrap_or(""))
+        } else {
+            let a = a.and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+            let b = b.and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+    }
+
+    /// The pattern `SORT` uses to read `field` of each element, either
+    /// from the object hash or from its counter.
+    fn sort_pattern(field: &str) -> Vec<u8> {
         let default = T::default();
         let class_name = default.get_class_name();
-        let key = if default.counters().contains(by) {
-            format!("{}:*:{}", class_name, by)
+        if default.counters().contains(field) {
+            format!("{}:*:{}", class_name, field)
         } else {
-            format!("{}:*->{}", class_name, by)
-        }.as_bytes().to_vec();
+            format!("{}:*->{}", class_name, stored_name(&default, field))
+        }.as_bytes().to_vec()
+    }
 
-        let mut template = vec![b"SORT".to_vec(), vec![], b"BY".to_vec(), key];
+    /// Builds a `SORT` template for the set, with the set key left empty.
+    fn sort_template(by: &str, limit: Option<(usize, usize)>, asc: bool, alpha: bool) -> Vec<Vec<u8>> {
+        let mut template = vec![b"SORT".to_vec(), vec![], b"BY".to_vec(), Query::<T>::sort_pattern(by)];
         if let Some(l) = limit {
             template.push(b"LIMIT".to_vec());
             template.push(format!("{}", l.0).as_bytes().to_vec());
@@ -1396,22 +6369,51 @@ impl<'a, T: Ohmer> Query<'a, T> {
         if alpha {
             template.push(b"ALPHA".to_vec());
         }
-
-        let stal = stal::Stal::from_template(template, vec![(self.set.clone(), 1)]);
-        Iter::from_ops(stal.solve(), self.r)
+        template
     }
 }
 
-/// Iterator for query results
+/// Iterator for query results. The ids are fetched when it is created, and
+/// each object is loaded when the iterator reaches it, from either end.
+/// If an object fails to load, for example because it was deleted in the
+/// meantime, the iterator ends there and the remaining ids are dropped.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// # use ohmers::{Ohmer, Query};
+/// # use redis::Commands;
+/// model!(
+///     Runner {
+///         indices { race:String = "".to_string(); };
+///         time:u32 = 0;
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// # let _:bool = client.del("Runner:indices:race:Boston").unwrap();
+/// for time in &[7_800, 7_500, 8_100] {
+///     create!(Runner { race: "Boston".to_string(), time: *time, }, &client).unwrap();
+/// }
+/// let mut runners = Query::<Runner>::find("race", "Boston", &client)
+///     .sort("time", None, true, false).unwrap();
+/// assert_eq!(runners.len(), 3);
+/// assert_eq!(runners.next_back().unwrap().time, 8_100);
+/// assert_eq!(runners.len(), 2);
+/// assert_eq!(runners.rev().map(|r| r.time).collect::<Vec<_>>(), vec![7_800, 7_500]);
+/// # }
+/// ```
 pub struct Iter<'a, T> {
-    r: &'a redis::Client,
+    r: &'a OhmerConnection,
     iter: std::vec::IntoIter<usize>,
     phantom: PhantomData<T>,
 }
 
 impl<'a, T: Ohmer> Iter<'a, T> {
     /// Creates a new iterator from a list of ids
-    fn new(iter: std::vec::IntoIter<usize>, r: &'a redis::Client) -> Self {
+    fn new(iter: std::vec::IntoIter<usize>, r: &'a OhmerConnection) -> Self {
         Iter {
             iter: iter,
             r: r,
@@ -1422,35 +6424,65 @@ impl<'a, T: Ohmer> Iter<'a, T> {
     /// Creates an iterator from a list of operations. The operations must
     /// be wrapped in a MULTI/EXEC, and it is required to provide which
     /// operation returns the list of ids.
-    fn from_ops(ops: (Vec<Vec<Vec<u8>>>, usize), r: &'a redis::Client) -> Result<Self, OhmerError> {
-        let mut q = redis::pipe();
-        q.atomic();
-        let mut i = 0;
-        let len = ops.0.len();
-
-        for op in ops.0.into_iter() {
-            if i == 0 || i == len - 1 {
-                i += 1;
-                // skip MULTI and EXEC
-                continue;
+    fn from_ops(ops: (Vec<Vec<Vec<u8>>>, usize), r: &'a OhmerConnection) -> Result<Self, OhmerError> {
+        let ids: Vec<usize> = try!(run_ops(ops, r));
+        Ok(Iter { iter: ids.into_iter(), r: r, phantom: PhantomData })
+    }
+
+    /// Loads the object `id`. On error the remaining ids are dropped, so
+    /// the iterator stays finished and its length stays exact.
+    fn load(&mut self, id: usize) -> Option<T> {
+        match get(id, self.r) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.iter = Vec::new().into_iter();
+                None
+            },
+        }
+    }
+}
+
+/// Runs a command on `key`, which should hold a value of kind `expected`,
+/// failing with `WrongType` if it holds something else.
+fn query_key<V: redis::FromRedisValue>(cmd: &redis::Cmd, key: &str, expected: &'static str, r: &OhmerConnection) -> Result<V, OhmerError> {
+    cmd.query(r.conn()).map_err(|e| OhmerError::from_key_error(e, key, expected))
+}
+
+/// Runs a list of operations wrapped in a MULTI/EXEC, returning the result
+/// of the operation in position `ops.1`.
+///
+/// The commands creating temporary keys and the `DEL` removing them are
+/// part of the same transaction. Redis runs every queued command even if
+/// one of them fails, and discards the transaction if the connection is
+/// lost before `EXEC`, so temporary keys never outlive the query.
+fn run_ops<V: redis::FromRedisValue>(ops: (Vec<Vec<Vec<u8>>>, usize), r: &OhmerConnection) -> Result<V, OhmerError> {
+    let mut q = redis::pipe();
+    q.atomic();
+    let mut i = 0;
+    let len = ops.0.len();
+
+    for op in ops.0.into_iter() {
+        if i == 0 || i == len - 1 {
+            i += 1;
+            // skip MULTI and EXEC
+            continue;
+        }
+        let mut first = true;
+        for arg in op {
+            if first {
+                q.cmd(&*try!(String::from_utf8(arg)));
+                first = false;
+            } else {
+                q.arg(arg);
             }
-            let mut first = true;
-            for arg in op {
-                if first {
-                    q.cmd(&*try!(String::from_utf8(arg)));
-                    first = false;
-                } else {
-                    q.arg(arg);
-                }
-                if i != ops.1 {
-                    q.ignore();
-                }
+            if i != ops.1 {
+                q.ignore();
             }
-            i += 1;
         }
-        let mut result:Vec<Vec<usize>> = try!(q.query(r));
-        Ok(Iter { iter: result.pop().unwrap().into_iter(), r: r, phantom: PhantomData })
+        i += 1;
     }
+    let mut result:Vec<V> = try!(timed(r, "query", || with_retry(r, || Ok(try!(q.query(r.conn()))))));
+    Ok(result.pop().unwrap())
 }
 
 impl<'a, T: Ohmer> Iterator for Iter<'a, T> {
@@ -1458,10 +6490,7 @@ impl<'a, T: Ohmer> Iterator for Iter<'a, T> {
 
     fn next(&mut self) -> Option<T> {
         match self.iter.next() {
-            Some(id) => match get(id, self.r) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            },
+            Some(id) => self.load(id),
             None => None,
         }
     }
@@ -1470,3 +6499,58 @@ impl<'a, T: Ohmer> Iterator for Iter<'a, T> {
         (self.iter.len(), Some(self.iter.len()))
     }
 }
+
+impl<'a, T: Ohmer> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<T> {
+        match self.iter.next_back() {
+            Some(id) => self.load(id),
+            None => None,
+        }
+    }
+}
+
+impl<'a, T: Ohmer> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T: Ohmer> std::iter::FusedIterator for Iter<'a, T> {}
+
+/// Iterator returned by `scan`.
+pub struct Scan<'a, T> {
+    r: &'a OhmerConnection,
+    key: String,
+    cursor: Option<u64>,
+    batch: usize,
+    buffer: std::vec::IntoIter<T>,
+}
+
+impl<'a, T: Ohmer> Iterator for Scan<'a, T> {
+    type Item = Result<T, OhmerError>;
+
+    fn next(&mut self) -> Option<Result<T, OhmerError>> {
+        loop {
+            if let Some(obj) = self.buffer.next() {
+                return Some(Ok(obj));
+            }
+            let cursor = match self.cursor {
+                Some(cursor) => cursor,
+                None => return None,
+            };
+            let result:Result<(u64, Vec<usize>), _> = redis::cmd("SSCAN").arg(&*self.key).arg(cursor)
+                .arg("COUNT").arg(self.batch).query(self.r.conn());
+            let (next, ids) = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    self.cursor = None;
+                    return Some(Err(OhmerError::RedisError(e)));
+                },
+            };
+            self.cursor = if next == 0 { None } else { Some(next) };
+            match load_many(&ids, self.r) {
+                Ok(objs) => self.buffer = objs.into_iter().filter_map(|o| o).collect::<Vec<_>>().into_iter(),
+                Err(e) => {
+                    self.cursor = None;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
@@ -6,12 +6,23 @@ pub const SAVE:&'static str = "
 -- updating zero or more sets (indices) and zero or more hashes
 -- (unique indices).
 --
+-- ohmers may send the parameters encoded with JSON instead, replacing
+-- every cmsgpack.unpack below with cjson.decode (see ScriptEncoding).
+--
 -- # model
 --
 -- Table with one or two attributes:
 --    name (model name)
 --    id (model instance id, optional)
 --
+-- ohmers adds optional features: new (the id was generated by the
+-- client), untracked (the id is not added to the all set),
+-- preserve (fields not in attrs are kept, see below), json (a
+-- RedisJSON document stored instead of the hash), shared (a class
+-- name where the indices are written too), only (absent or
+-- present, whether the object must not or must exist already) and
+-- inverses (one-to-one references, see link_inverses).
+--
 -- If the id is not provided, it is treated as a new record.
 --
 -- # attrs
@@ -32,6 +43,12 @@ pub const SAVE:&'static str = "
 -- value), an error is returned with the UniqueIndexViolation
 -- message and the field that triggered the error.
 --
+-- # removed (ohmers, only with preserve)
+--
+-- Fields to delete from the hash. Instead of replacing the whole
+-- hash, only these fields are removed, and fields written by other
+-- clients survive.
+--
 local model   = cmsgpack.unpack(ARGV[1])
 local attrs   = cmsgpack.unpack(ARGV[2])
 local indices = cmsgpack.unpack(ARGV[3])
@@ -44,8 +61,26 @@ local function save(model, attrs)
 
 	model.key = model.name .. \":\" .. model.id
 
-	redis.call(\"SADD\", model.name .. \":all\", model.id)
-	redis.call(\"DEL\", model.key)
+	-- ohmers: models may opt out of the set with every id.
+	if not model.untracked then
+		redis.call(\"SADD\", model.name .. \":all\", model.id)
+	end
+	-- ohmers: models may be stored as a RedisJSON document.
+	if model.json then
+		redis.call(\"JSON.SET\", model.key, \".\", model.json)
+		return
+	end
+
+	-- ohmers: models may keep the fields they do not declare.
+	if model.preserve then
+		local removed = cmsgpack.unpack(ARGV[5])
+
+		if #removed > 0 then
+			redis.call(\"HDEL\", model.key, unpack(removed))
+		end
+	else
+		redis.call(\"DEL\", model.key)
+	end
 
 	if math.mod(#attrs, 2) == 1 then
 		error(\"Wrong number of attribute/value pairs\")
@@ -77,6 +112,29 @@ local function remove_indices(model)
 	end
 end
 
+-- ohmers: models may also write their indices under the name of a class
+-- shared with other models (the shared flag), adding <name>:<id> to them.
+local function index_shared(model, indices)
+	local memo = model.key .. \":_shared\"
+	local member = model.name .. \":\" .. model.id
+
+	for _, key in ipairs(redis.call(\"SMEMBERS\", memo)) do
+		redis.call(\"SREM\", key, member)
+	end
+	redis.call(\"DEL\", memo)
+
+	if model.shared then
+		for field, enum in pairs(indices) do
+			for _, val in ipairs(enum) do
+				local key = model.shared .. \":indices:\" .. field .. \":\" .. tostring(val)
+
+				redis.call(\"SADD\", memo, key)
+				redis.call(\"SADD\", key, member)
+			end
+		end
+	end
+end
+
 local function unique(model, uniques)
 	for field, value in pairs(uniques) do
 		local key = model.name .. \":uniques:\" .. field
@@ -95,6 +153,66 @@ local function remove_uniques(model)
 	end
 end
 
+-- ohmers: one-to-one references keep the reference back from their
+-- target up to date. The inverses flag holds \"<field> <Class> <inverse>\"
+-- triples separated by commas. The targets of the current values are
+-- read before saving.
+local function inverse_targets(model)
+	local targets = {}
+
+	if model.inverses and model.id then
+		for field in string.gmatch(model.inverses, \"([^ ,]+) [^ ,]+ [^ ,]+\") do
+			targets[field] = redis.call(\"HGET\", model.name .. \":\" .. model.id, field)
+		end
+	end
+
+	return targets
+end
+
+local function link_inverses(model, attrs, targets)
+	if not model.inverses then
+		return
+	end
+
+	local values = {}
+	for i = 1, #attrs, 2 do
+		values[attrs[i]] = attrs[i + 1]
+	end
+
+	local id = tostring(model.id)
+	for field, class, inverse in string.gmatch(model.inverses, \"([^ ,]+) ([^ ,]+) ([^ ,]+)\") do
+		local old = targets[field]
+		local new = values[field]
+
+		-- The previous target no longer points back.
+		if old and old ~= \"0\" and old ~= new then
+			local key = class .. \":\" .. old
+
+			if redis.call(\"HGET\", key, inverse) == id then
+				redis.call(\"HSET\", key, inverse, \"0\")
+			end
+		end
+
+		if new and new ~= \"0\" then
+			local key = class .. \":\" .. new
+
+			if redis.call(\"EXISTS\", key) == 1 then
+				-- The object the target pointed back to loses it.
+				local previous = redis.call(\"HGET\", key, inverse)
+
+				if previous and previous ~= \"0\" and previous ~= id then
+					local other = model.name .. \":\" .. previous
+
+					if redis.call(\"HGET\", other, field) == new then
+						redis.call(\"HSET\", other, field, \"0\")
+					end
+				end
+				redis.call(\"HSET\", key, inverse, id)
+			end
+		end
+	end
+end
+
 local function verify(model, uniques)
 	local duplicates = {}
 
@@ -110,16 +228,38 @@ local function verify(model, uniques)
 	return duplicates, #duplicates ~= 0
 end
 
+-- ohmers: saving may be limited to objects that do not exist yet, or
+-- to objects that do.
+if model.id and model.only then
+	local exists = redis.call(\"EXISTS\", model.name .. \":\" .. model.id) == 1
+
+	if model.only == \"absent\" and exists then
+		error(\"AlreadyExists: \" .. model.id)
+	elseif model.only == \"present\" and not exists then
+		error(\"NotFound: \" .. model.id)
+	end
+end
+
+-- ohmers: an id generated by the client (flagged as new) must not be
+-- in use already.
+if model.new and redis.call(\"SISMEMBER\", model.name .. \":all\", model.id) == 1 then
+	error(\"IdInUse: \" .. model.id)
+end
+
 local duplicates, err = verify(model, uniques)
 
 if err then
 	error(\"UniqueIndexViolation: \" .. duplicates[1])
 end
 
+local targets = inverse_targets(model)
+
 save(model, attrs)
+link_inverses(model, attrs, targets)
 
 remove_indices(model)
 index(model, indices)
+index_shared(model, indices)
 
 remove_uniques(model, uniques)
 unique(model, uniques)
@@ -141,6 +281,9 @@ pub const DELETE:&'static str = "
 --    key (hash where the attributes will be saved)
 --    name (model name)
 --
+-- ohmers adds an optional inverses flag, like the one of the save
+-- script, to clear the references back to the object.
+--
 -- # uniques
 --
 -- Fields and values to be removed from the unique indices.
@@ -150,9 +293,15 @@ pub const DELETE:&'static str = "
 -- Keys that share the lifecycle of this model instance, that
 -- should be removed as this object is deleted.
 --
+-- # keys (ohmers, optional)
+--
+-- Full names of more keys to be removed, for the ones that are
+-- not named after the key of the instance.
+--
 local model   = cmsgpack.unpack(ARGV[1])
 local uniques = cmsgpack.unpack(ARGV[2])
 local tracked = cmsgpack.unpack(ARGV[3])
+local keys    = ARGV[4] and cmsgpack.unpack(ARGV[4]) or {}
 
 local function remove_indices(model)
 	local memo = model.key .. \":_indices\"
@@ -183,6 +332,37 @@ local function remove_tracked(model, tracked)
 	end
 end
 
+-- ohmers: removes the object from the indices it shares with other
+-- models.
+local function remove_shared(model)
+	local memo = model.key .. \":_shared\"
+
+	for _, key in ipairs(redis.call(\"SMEMBERS\", memo)) do
+		redis.call(\"SREM\", key, model.name .. \":\" .. model.id)
+	end
+	redis.call(\"DEL\", memo)
+end
+
+-- ohmers: one-to-one targets that point back to the object are left
+-- pointing to nothing (see inverses in the save script).
+local function unlink_inverses(model)
+	if not model.inverses then
+		return
+	end
+
+	for field, class, inverse in string.gmatch(model.inverses, \"([^ ,]+) ([^ ,]+) ([^ ,]+)\") do
+		local target = redis.call(\"HGET\", model.key, field)
+
+		if target and target ~= \"0\" then
+			local key = class .. \":\" .. target
+
+			if redis.call(\"HGET\", key, inverse) == model.id then
+				redis.call(\"HSET\", key, inverse, \"0\")
+			end
+		end
+	end
+end
+
 local function delete(model)
 	local keys = {
 		model.key .. \":counters\",
@@ -198,7 +378,446 @@ end
 remove_indices(model)
 remove_uniques(model, uniques)
 remove_tracked(model, tracked)
+remove_shared(model)
+unlink_inverses(model)
+if #keys > 0 then
+	redis.call(\"DEL\", unpack(keys))
+end
 delete(model)
 
 return model.id
 ";
+
+// Copies the ids in a list (KEYS[1]) to a set (KEYS[2]), so the list can be
+// used in set operations.
+pub const LIST_TO_SET:&'static str = "
+local ids = redis.call(\"LRANGE\", KEYS[1], 0, -1)
+
+for i = 1, #ids, 1000 do
+	redis.call(\"SADD\", KEYS[2], unpack(ids, i, math.min(i + 999, #ids)))
+end
+";
+
+// Moves the keys in KEYS from the class ARGV[1] to the class ARGV[2]. The
+// names of index and unique keys stored in the `_indices` and `_uniques`
+// memos are rewritten too.
+pub const RENAME_CLASS:&'static str = "
+local old = ARGV[1] .. \":\"
+local new = ARGV[2] .. \":\"
+
+local function rename(key)
+	if string.sub(key, 1, #old) == old then
+		return new .. string.sub(key, #old + 1)
+	end
+	return key
+end
+
+local function ends_with(key, suffix)
+	return string.sub(key, -#suffix) == suffix
+end
+
+for _, key in ipairs(KEYS) do
+	local renamed = rename(key)
+
+	if ends_with(key, \":_indices\") then
+		for _, member in ipairs(redis.call(\"SMEMBERS\", key)) do
+			redis.call(\"SADD\", renamed, rename(member))
+		end
+		redis.call(\"DEL\", key)
+	elseif ends_with(key, \":_uniques\") then
+		local memo = redis.call(\"HGETALL\", key)
+		for i = 1, #memo, 2 do
+			redis.call(\"HSET\", renamed, rename(memo[i]), memo[i + 1])
+		end
+		redis.call(\"DEL\", key)
+	else
+		redis.call(\"RENAME\", key, renamed)
+	end
+end
+";
+
+// Increments the counter KEYS[1] by ARGV[1], stopping at the largest or
+// smallest 64 bits integer instead of failing. The limits are returned as
+// strings, since Lua numbers are doubles.
+pub const INCR_SATURATING:&'static str = "
+local value = redis.pcall(\"INCRBY\", KEYS[1], ARGV[1])
+
+if type(value) == \"table\" and value.err then
+	if not string.find(value.err, \"overflow\") then
+		return value
+	end
+
+	local limit = \"9223372036854775807\"
+	if string.sub(ARGV[1], 1, 1) == \"-\" then
+		limit = \"-9223372036854775808\"
+	end
+	redis.call(\"SET\", KEYS[1], limit)
+	return limit
+end
+
+return value
+";
+
+// Creates many objects of a class at once. Every object takes four
+// arguments, encoded with MessagePack like in SAVE: its model, attributes,
+// indices and uniques. The model has the name of the class and the flags of
+// SAVE that apply to new objects: untracked, json, shared and inverses. The
+// ids are allocated as a single block, and the first one is returned.
+// Nothing is written if any unique value is taken, either by an existing
+// object or by another one in the batch.
+pub const CREATE_MANY:&'static str = "
+local objects = {}
+
+for i = 1, #ARGV, 4 do
+	objects[#objects + 1] = {
+		model = cmsgpack.unpack(ARGV[i]),
+		attrs = cmsgpack.unpack(ARGV[i + 1]),
+		indices = cmsgpack.unpack(ARGV[i + 2]),
+		uniques = cmsgpack.unpack(ARGV[i + 3]),
+	}
+end
+
+local name = objects[1].model.name
+local seen = {}
+
+-- The targets of the one-to-one references of a new object point back to
+-- it, and the object they pointed to before loses its reference.
+local function link_inverses(model, id, attrs)
+	if not model.inverses then
+		return
+	end
+
+	local values = {}
+	for i = 1, #attrs, 2 do
+		values[attrs[i]] = attrs[i + 1]
+	end
+
+	for field, class, inverse in string.gmatch(model.inverses, \"([^ ,]+) ([^ ,]+) ([^ ,]+)\") do
+		local target = values[field]
+
+		if target and target ~= \"0\" then
+			local key = class .. \":\" .. target
+
+			if redis.call(\"EXISTS\", key) == 1 then
+				local previous = redis.call(\"HGET\", key, inverse)
+
+				if previous and previous ~= \"0\" and previous ~= id then
+					local other = name .. \":\" .. previous
+
+					if redis.call(\"HGET\", other, field) == target then
+						redis.call(\"HSET\", other, field, \"0\")
+					end
+				end
+				redis.call(\"HSET\", key, inverse, id)
+			end
+		end
+	end
+end
+
+for _, object in ipairs(objects) do
+	for field, value in pairs(object.uniques) do
+		local key = name .. \":uniques:\" .. field
+		local value = tostring(value)
+
+		seen[key] = seen[key] or {}
+		if seen[key][value] or redis.call(\"HEXISTS\", key, value) == 1 then
+			error(\"UniqueIndexViolation: \" .. field)
+		end
+		seen[key][value] = true
+	end
+end
+
+local first = redis.call(\"INCRBY\", name .. \":id\", #objects) - #objects + 1
+
+for i, object in ipairs(objects) do
+	local model = object.model
+	local id = first + i - 1
+	local key = name .. \":\" .. id
+
+	if not model.untracked then
+		redis.call(\"SADD\", name .. \":all\", id)
+	end
+
+	if model.json then
+		redis.call(\"JSON.SET\", key, \".\", model.json)
+	elseif #object.attrs > 0 then
+		redis.call(\"HMSET\", key, unpack(object.attrs))
+	end
+	link_inverses(model, tostring(id), object.attrs)
+
+	for field, enum in pairs(object.indices) do
+		for _, val in ipairs(enum) do
+			local index = name .. \":indices:\" .. field .. \":\" .. tostring(val)
+
+			redis.call(\"SADD\", key .. \":_indices\", index)
+			redis.call(\"SADD\", index, id)
+
+			if model.shared then
+				index = model.shared .. \":indices:\" .. field .. \":\" .. tostring(val)
+				redis.call(\"SADD\", key .. \":_shared\", index)
+				redis.call(\"SADD\", index, key)
+			end
+		end
+	end
+
+	for field, value in pairs(object.uniques) do
+		local unique = name .. \":uniques:\" .. field
+
+		redis.call(\"HSET\", key .. \":_uniques\", unique, value)
+		redis.call(\"HSET\", unique, value, id)
+	end
+end
+
+return tostring(first)
+";
+
+// Saves many objects that already have an id at once. Every object takes
+// five arguments, encoded with MessagePack like in SAVE: its model, its
+// attributes, indices and uniques, and the fields to remove when it has the
+// preserve flag. One-to-one references are linked back like in SAVE.
+// Nothing is written if any unique value is taken, either by an object
+// outside the batch or by another one in it; values given up by an object
+// of the batch may be taken by another. The error names the id of the
+// object and the field. Returns the number of objects saved.
+pub const SAVE_MANY:&'static str = "
+local objects = {}
+local batch = {}
+
+for i = 1, #ARGV, 5 do
+	local object = {
+		model = cmsgpack.unpack(ARGV[i]),
+		attrs = cmsgpack.unpack(ARGV[i + 1]),
+		indices = cmsgpack.unpack(ARGV[i + 2]),
+		uniques = cmsgpack.unpack(ARGV[i + 3]),
+		removed = cmsgpack.unpack(ARGV[i + 4]),
+	}
+	object.model.key = object.model.name .. \":\" .. object.model.id
+	objects[#objects + 1] = object
+	batch[tostring(object.model.id)] = object
+end
+
+local seen = {}
+
+for _, object in ipairs(objects) do
+	local model = object.model
+
+	for field, value in pairs(object.uniques) do
+		local key = model.name .. \":uniques:\" .. field
+		local value = tostring(value)
+		local owner = redis.call(\"HGET\", key, value)
+		local released = owner and batch[owner] and tostring(batch[owner].uniques[field]) ~= value
+
+		seen[key] = seen[key] or {}
+		if seen[key][value] or (owner and owner ~= tostring(model.id) and not released) then
+			error(\"UniqueIndexViolationAt: \" .. model.id .. \" \" .. field)
+		end
+		seen[key][value] = true
+	end
+end
+
+-- The targets of the one-to-one references are read before any object
+-- is written, as in SAVE.
+for _, object in ipairs(objects) do
+	local model = object.model
+
+	object.targets = {}
+	if model.inverses then
+		for field in string.gmatch(model.inverses, \"([^ ,]+) [^ ,]+ [^ ,]+\") do
+			object.targets[field] = redis.call(\"HGET\", model.key, field)
+		end
+	end
+end
+
+local function link_inverses(model, attrs, targets)
+	if not model.inverses then
+		return
+	end
+
+	local values = {}
+	for i = 1, #attrs, 2 do
+		values[attrs[i]] = attrs[i + 1]
+	end
+
+	local id = tostring(model.id)
+	for field, class, inverse in string.gmatch(model.inverses, \"([^ ,]+) ([^ ,]+) ([^ ,]+)\") do
+		local old = targets[field]
+		local new = values[field]
+
+		if old and old ~= \"0\" and old ~= new then
+			local key = class .. \":\" .. old
+
+			if redis.call(\"HGET\", key, inverse) == id then
+				redis.call(\"HSET\", key, inverse, \"0\")
+			end
+		end
+
+		if new and new ~= \"0\" then
+			local key = class .. \":\" .. new
+
+			if redis.call(\"EXISTS\", key) == 1 then
+				local previous = redis.call(\"HGET\", key, inverse)
+
+				if previous and previous ~= \"0\" and previous ~= id then
+					local other = model.name .. \":\" .. previous
+
+					if redis.call(\"HGET\", other, field) == new then
+						redis.call(\"HSET\", other, field, \"0\")
+					end
+				end
+				redis.call(\"HSET\", key, inverse, id)
+			end
+		end
+	end
+end
+
+-- Every old index is removed before writing the new ones, so a value
+-- passed from one object to another is not removed after it is taken.
+for _, object in ipairs(objects) do
+	local model = object.model
+	local memo = model.key .. \":_indices\"
+
+	for _, key in ipairs(redis.call(\"SMEMBERS\", memo)) do
+		redis.call(\"SREM\", key, model.id)
+		redis.call(\"SREM\", memo, key)
+	end
+
+	memo = model.key .. \":_uniques\"
+	for _, key in pairs(redis.call(\"HKEYS\", memo)) do
+		redis.call(\"HDEL\", key, redis.call(\"HGET\", memo, key))
+		redis.call(\"HDEL\", memo, key)
+	end
+
+	memo = model.key .. \":_shared\"
+	for _, key in ipairs(redis.call(\"SMEMBERS\", memo)) do
+		redis.call(\"SREM\", key, model.name .. \":\" .. model.id)
+	end
+	redis.call(\"DEL\", memo)
+end
+
+for _, object in ipairs(objects) do
+	local model = object.model
+
+	if not model.untracked then
+		redis.call(\"SADD\", model.name .. \":all\", model.id)
+	end
+
+	if model.json then
+		redis.call(\"JSON.SET\", model.key, \".\", model.json)
+	else
+		if not model.preserve then
+			redis.call(\"DEL\", model.key)
+		elseif #object.removed > 0 then
+			redis.call(\"HDEL\", model.key, unpack(object.removed))
+		end
+
+		if #object.attrs > 0 then
+			redis.call(\"HMSET\", model.key, unpack(object.attrs))
+		end
+	end
+	link_inverses(model, object.attrs, object.targets)
+
+	for field, enum in pairs(object.indices) do
+		for _, val in ipairs(enum) do
+			local key = model.name .. \":indices:\" .. field .. \":\" .. tostring(val)
+
+			redis.call(\"SADD\", model.key .. \":_indices\", key)
+			redis.call(\"SADD\", key, model.id)
+
+			if model.shared then
+				key = model.shared .. \":indices:\" .. field .. \":\" .. tostring(val)
+				redis.call(\"SADD\", model.key .. \":_shared\", key)
+				redis.call(\"SADD\", key, model.name .. \":\" .. model.id)
+			end
+		end
+	end
+
+	for field, value in pairs(object.uniques) do
+		local key = model.name .. \":uniques:\" .. field
+
+		redis.call(\"HSET\", model.key .. \":_uniques\", key, value)
+		redis.call(\"HSET\", key, value, model.id)
+	end
+end
+
+return #objects
+";
+
+// Increments the counter KEYS[1] by ARGV[1] and reads the field ARGV[2] of
+// the hash KEYS[2], returning both. Nothing is incremented if the field is
+// missing.
+pub const INCR_AND_GET_FIELD:&'static str = "
+local value = redis.call(\"HGET\", KEYS[2], ARGV[2])
+
+if not value then
+	return redis.error_reply(\"ERR missing field \" .. ARGV[2])
+end
+
+return {redis.call(\"INCRBY\", KEYS[1], ARGV[1]), value}
+";
+
+// Deletes a lock only if it still holds the token of the client releasing
+// it, so a client whose lock expired cannot release the lock of another.
+pub const UNLOCK:&'static str = "
+if redis.call(\"GET\", KEYS[1]) == ARGV[1] then
+	return redis.call(\"DEL\", KEYS[1])
+end
+return 0
+";
+
+// ohmers: sets a single field of an object without rewriting the rest of
+// the hash, keeping its index and unique index up to date.
+//
+// KEYS[1] is the object hash and KEYS[2] the set with every id. ARGV has
+// the id, the field, the value, the prefix of the index keys of the field,
+// the index key for the new value, and the unique index of the field. The
+// last three are empty if the field has no such index. An optional seventh
+// argument is the value the field must have for it to be set; the script
+// returns 0 without changing anything if it has another one, and 1 once
+// the field is set.
+pub const SET_FIELD:&'static str = "
+local key, all = KEYS[1], KEYS[2]
+local id, field, value = ARGV[1], ARGV[2], ARGV[3]
+local prefix, index, unique = ARGV[4], ARGV[5], ARGV[6]
+local expected = ARGV[7]
+
+if redis.call(\"SISMEMBER\", all, id) == 0 and redis.call(\"EXISTS\", key) == 0 then
+	error(\"NotFound: \" .. id)
+end
+
+if expected and redis.call(\"HGET\", key, field) ~= expected then
+	return 0
+end
+
+if unique ~= \"\" then
+	local owner = redis.call(\"HGET\", unique, value)
+
+	if owner and owner ~= id then
+		error(\"UniqueIndexViolation: \" .. field)
+	end
+
+	local memo = key .. \":_uniques\"
+	local old = redis.call(\"HGET\", memo, unique)
+
+	if old then
+		redis.call(\"HDEL\", unique, old)
+	end
+	redis.call(\"HSET\", unique, value, id)
+	redis.call(\"HSET\", memo, unique, value)
+end
+
+if prefix ~= \"\" then
+	local memo = key .. \":_indices\"
+
+	for _, existing in ipairs(redis.call(\"SMEMBERS\", memo)) do
+		if string.sub(existing, 1, #prefix) == prefix then
+			redis.call(\"SREM\", existing, id)
+			redis.call(\"SREM\", memo, existing)
+		end
+	end
+	redis.call(\"SADD\", index, id)
+	redis.call(\"SADD\", memo, index)
+end
+
+redis.call(\"HSET\", key, field, value)
+return 1
+";
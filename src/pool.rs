@@ -0,0 +1,88 @@
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate redis;
+
+use std::io;
+
+use self::r2d2_redis::RedisConnectionManager;
+use redis::{ConnectionLike, RedisResult, Value};
+
+use connection::OhmerConnection;
+
+/// A pool of Redis connections, backed by `r2d2`.
+/// Every command checks out a connection from the pool and returns it when
+/// it is done, so a single `Pool` can be shared by many threads.
+///
+/// With the default `r2d2` configuration, connections are checked with a
+/// `PING` when they are taken, and broken ones, like after the server
+/// restarts, are replaced with new connections. See `ping`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// extern crate r2d2;
+/// extern crate r2d2_redis;
+/// # use ohmers::Ohmer;
+/// use std::thread;
+///
+/// model!(
+///     Visit {
+///         path:String = "/".to_string();
+///     });
+///
+/// fn handle(path: &str, pool: &ohmers::Pool) -> usize {
+///     let visit = create!(Visit { path: path.to_string(), }, pool).unwrap();
+///     ohmers::get::<Visit>(visit.id, pool).unwrap().id
+/// }
+///
+/// # fn main() {
+/// let manager = r2d2_redis::RedisConnectionManager::new("redis://127.0.0.1/").unwrap();
+/// let pool = ohmers::Pool::new(r2d2::Pool::new(Default::default(), manager).unwrap());
+///
+/// let handles = (0..4).map(|_| {
+///     let pool = pool.clone();
+///     thread::spawn(move || handle("/index.html", &pool))
+/// }).collect::<Vec<_>>();
+/// for h in handles {
+///     assert!(h.join().unwrap() > 0);
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Pool {
+    pool: r2d2::Pool<RedisConnectionManager>,
+}
+
+impl Pool {
+    /// Wraps an `r2d2` pool of Redis connections.
+    pub fn new(pool: r2d2::Pool<RedisConnectionManager>) -> Self {
+        Pool { pool: pool }
+    }
+
+    /// Takes a connection from the pool. Failing to get one in time is
+    /// reported as an I/O timeout.
+    fn checkout(&self) -> RedisResult<r2d2::PooledConnection<RedisConnectionManager>> {
+        self.pool.get().map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e).into())
+    }
+}
+
+impl ConnectionLike for Pool {
+    fn req_packed_command(&self, cmd: &[u8]) -> RedisResult<Value> {
+        try!(self.checkout()).req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(&self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        try!(self.checkout()).req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.checkout().map(|c| c.get_db()).unwrap_or(0)
+    }
+}
+
+impl OhmerConnection for Pool {
+    fn conn(&self) -> &ConnectionLike { self }
+}
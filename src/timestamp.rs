@@ -0,0 +1,107 @@
+extern crate chrono;
+
+use std::ops::Deref;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use self::chrono::{DateTime, TimeZone, Utc};
+
+/// A point in time, stored as the number of milliseconds since the Unix
+/// epoch. Being a number, it can be used with `Query::sort` without
+/// `alpha`, and dates before 1970 are negative.
+///
+/// The precision is one millisecond: anything smaller is dropped when the
+/// timestamp is created, so a saved timestamp is loaded back unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[macro_use(model, create)] extern crate ohmers;
+/// # extern crate rustc_serialize;
+/// # extern crate redis;
+/// extern crate chrono;
+/// # use ohmers::{Ohmer, Timestamp};
+/// use chrono::{FixedOffset, NaiveDate, TimeZone};
+///
+/// model!(
+///     Meeting {
+///         title:String = "".to_string();
+///         starts:Timestamp = Timestamp::now();
+///     });
+/// # fn main() {
+/// # let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let buenos_aires = FixedOffset::west_opt(3 * 3600).unwrap();
+/// let local = NaiveDate::from_ymd_opt(2016, 3, 1).unwrap().and_hms_milli_opt(9, 30, 0, 250).unwrap();
+/// let starts = buenos_aires.from_local_datetime(&local).single().unwrap();
+/// let meeting = create!(Meeting { title: "Standup".to_string(), starts: starts.into(), }, &client).unwrap();
+///
+/// let loaded = ohmers::get::<Meeting>(meeting.id, &client).unwrap();
+/// assert_eq!(loaded.starts, meeting.starts);
+/// assert_eq!(*loaded.starts, starts);
+/// assert_eq!(loaded.starts.millis(), 1456835400250);
+/// # }
+/// ```
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct Timestamp(DateTime<Utc>);
+
+impl Timestamp {
+    /// The current time.
+    pub fn now() -> Self {
+        Timestamp::from(Utc::now())
+    }
+
+    /// The time `millis` milliseconds after the Unix epoch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the time is out of the range of `chrono`, about 262,000
+    /// years from the epoch; see `try_from_millis`.
+    pub fn from_millis(millis: i64) -> Self {
+        Timestamp::try_from_millis(millis).expect("timestamp out of range")
+    }
+
+    /// The time `millis` milliseconds after the Unix epoch, or `None` if it
+    /// is out of the range of `chrono`.
+    pub fn try_from_millis(millis: i64) -> Option<Self> {
+        let secs = if millis < 0 && millis % 1000 != 0 { millis / 1000 - 1 } else { millis / 1000 };
+        let nanos = (millis - secs * 1000) as u32 * 1_000_000;
+        Utc.timestamp_opt(secs, nanos).single().map(Timestamp)
+    }
+
+    /// Milliseconds since the Unix epoch.
+    pub fn millis(&self) -> i64 {
+        self.0.timestamp() * 1000 + self.0.timestamp_subsec_millis() as i64
+    }
+
+    /// The timestamp as a `chrono` date.
+    pub fn into_inner(self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for Timestamp {
+    fn from(date: DateTime<Tz>) -> Timestamp {
+        let date = date.with_timezone(&Utc);
+        Timestamp::from_millis(date.timestamp() * 1000 + date.timestamp_subsec_millis() as i64)
+    }
+}
+
+impl Deref for Timestamp {
+    type Target = DateTime<Utc>;
+
+    fn deref(&self) -> &DateTime<Utc> {
+        &self.0
+    }
+}
+
+impl Encodable for Timestamp {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_i64(self.millis())
+    }
+}
+
+impl Decodable for Timestamp {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Timestamp, D::Error> {
+        let millis = try!(d.read_i64());
+        Timestamp::try_from_millis(millis).ok_or_else(|| d.error(&*format!("timestamp out of range: {}", millis)))
+    }
+}
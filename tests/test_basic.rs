@@ -2,7 +2,10 @@ extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
+use std::collections::HashMap;
+
 use ohmers::{get, Ohmer};
+use redis::Commands;
 use rustc_serialize::Encodable;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -38,3 +41,60 @@ fn test_save_load() {
     let person2 = get(person.id, &client).unwrap();
     assert_eq!(person, person2);
 }
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+struct Contact {
+    id: usize,
+    name: String,
+    phone: Option<String>,
+}
+impl Ohmer for Contact {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn preserve_unknown_fields(&self) -> bool { true }
+}
+
+#[test]
+fn test_save_unknown_fields() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut person = Person { id: 0, name: "Joe".to_string() };
+    person.save(&client).unwrap();
+    let _:bool = client.hset(format!("Person:{}", person.id), "nickname", "Jo").unwrap();
+    person.save(&client).unwrap();
+    let nickname:Option<String> = client.hget(format!("Person:{}", person.id), "nickname").unwrap();
+    assert_eq!(nickname, None);
+
+    let mut contact = Contact { id: 0, name: "Joe".to_string(), phone: Some("555".to_string()) };
+    contact.save(&client).unwrap();
+    let _:bool = client.hset(format!("Contact:{}", contact.id), "nickname", "Jo").unwrap();
+    contact.name = "Joseph".to_string();
+    contact.phone = None;
+    contact.save(&client).unwrap();
+
+    let nickname:Option<String> = client.hget(format!("Contact:{}", contact.id), "nickname").unwrap();
+    assert_eq!(nickname, Some("Jo".to_string()));
+    let phone:Option<String> = client.hget(format!("Contact:{}", contact.id), "phone").unwrap();
+    assert_eq!(phone, None);
+    assert_eq!(get::<Contact>(contact.id, &client).unwrap(), contact);
+}
+
+#[test]
+fn test_map_round_trip() {
+    let mut map = HashMap::new();
+    map.insert("id".to_string(), "12".to_string());
+    map.insert("name".to_string(), "Jim".to_string());
+    map.insert("phone".to_string(), "555-0100".to_string());
+
+    let contact = Contact::from_map(map.clone()).unwrap();
+    assert_eq!(contact, Contact { id: 12, name: "Jim".to_string(), phone: Some("555-0100".to_string()) });
+    assert_eq!(contact.to_map().unwrap(), map);
+
+    map.remove("id");
+    map.remove("phone");
+    let contact = Contact::from_map(map.clone()).unwrap();
+    assert_eq!(contact, Contact { id: 0, name: "Jim".to_string(), phone: None });
+
+    map.insert("id".to_string(), "twelve".to_string());
+    assert!(Contact::from_map(map).is_err());
+}
@@ -0,0 +1,59 @@
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, Binary, Ohmer};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Document {
+    id: usize,
+    name: String,
+    payload: Binary,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Document {
+            id: 0,
+            name: "".to_string(),
+            payload: Binary::default(),
+        }
+    }
+}
+impl Ohmer for Document {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[test]
+fn test_binary() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let bytes = vec![0xc3, 0x28, 0xa0, 0xa1, 0xe2, 0x28, 0xa1, 0x00, 0xff, 0xfe];
+    assert!(String::from_utf8(bytes.clone()).is_err());
+
+    let mut doc = Document::default();
+    doc.name = "blob".to_string();
+    doc.payload = Binary(bytes.clone());
+    doc.save(&client).unwrap();
+
+    let doc2:Document = get(doc.id, &client).unwrap();
+    assert_eq!(doc2, doc);
+    assert_eq!(doc2.payload.0, bytes);
+
+    let mut empty = Document::default();
+    empty.save(&client).unwrap();
+    assert_eq!(get::<Document>(empty.id, &client).unwrap().payload, Binary(vec![]));
+}
+
+#[test]
+fn test_binary_invalid() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut doc = Document::default();
+    doc.save(&client).unwrap();
+    let _:() = client.hset(format!("Document:{}", doc.id), "payload", "not base64!").unwrap();
+    assert!(get::<Document>(doc.id, &client).is_err());
+}
@@ -2,6 +2,9 @@
 extern crate redis;
 extern crate rustc_serialize;
 
+use std::collections::HashSet;
+use std::iter::FromIterator;
+
 use ohmers::{Ohmer, Reference, Collection};
 use rustc_serialize::Encodable;
 
@@ -60,3 +63,60 @@ fn test_movie_year() {
             vec![bttf.clone(), cocoon.clone(), rocky_iv.clone()]
             );
 }
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Studio {
+    id: usize,
+    name: String,
+    films: Collection<Film>,
+}
+
+impl Default for Studio {
+    fn default() -> Self {
+        Studio { id: 0, name: "".to_string(), films: Collection::new() }
+    }
+}
+impl Ohmer for Studio {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn property_key(&self, property: &str) -> String {
+        match property {
+            "Studio" => "producer".to_string(),
+            _ => property.to_string(),
+        }
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Film {
+    id: usize,
+    producer: Reference<Studio>,
+}
+
+impl Default for Film {
+    fn default() -> Self {
+        Film { id: 0, producer: Reference::new() }
+    }
+}
+impl Ohmer for Film {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn index_fields<'a>(&self) -> HashSet<&'a str> {
+        HashSet::from_iter(vec!["producer"])
+    }
+}
+
+#[test]
+fn test_collection_property_key() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut studio = Studio::default();
+    studio.name = "Ghibli".to_string();
+    studio.save(&client).unwrap();
+    let mut film = Film::default();
+    film.producer.set(&studio);
+    film.save(&client).unwrap();
+
+    let films = collection!(studio.films, &client).try_iter().unwrap().collect::<Vec<_>>();
+    assert_eq!(films, vec![film]);
+}
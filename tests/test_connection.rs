@@ -0,0 +1,81 @@
+#[macro_use(model, create, incr, counter, insert, len)] extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, Counter, Ohmer, OhmerError, Query, ReadOnly, Set};
+
+model!(Book {
+        title:String = "".to_owned();
+        });
+
+model!(Archive {
+        indices { year:u32 = 0; };
+        });
+
+model!(Shelf {
+        books:Set<Book> = Set::new();
+        visits:Counter = Counter;
+        });
+
+#[test]
+fn test_connection() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let connection = client.get_connection().unwrap();
+
+    let mut book = create!(Book { title: "Dune".to_owned(), }, &connection).unwrap();
+    assert_eq!(get::<Book>(book.id, &connection).unwrap().title, "Dune".to_owned());
+
+    book.title = "Emma".to_owned();
+    book.save(&connection).unwrap();
+    assert_eq!(get::<Book>(book.id, &client).unwrap().title, "Emma".to_owned());
+}
+
+#[test]
+fn test_connection_like() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let connection = client.get_connection().unwrap();
+    let conn_like: &redis::ConnectionLike = &connection;
+
+    let book = create!(Book { title: "Ulysses".to_owned(), }, conn_like).unwrap();
+    assert_eq!(get::<Book>(book.id, &conn_like).unwrap().title, "Ulysses".to_owned());
+}
+
+#[test]
+fn test_read_only() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let book = create!(Book { title: "Walden".to_owned(), }, &client).unwrap();
+    let shelf = create!(Shelf {}, &client).unwrap();
+    insert!(shelf.books, book, client).unwrap();
+
+    let read_only = ReadOnly::new(&client);
+    assert_eq!(create!(Book { title: "Beloved".to_owned(), }, read_only).unwrap_err(), OhmerError::ReadOnly);
+    assert_eq!(incr!(shelf.visits, read_only).unwrap_err(), OhmerError::ReadOnly);
+    assert_eq!(shelf.books.remove("books", &shelf, &book, &read_only).unwrap_err(), OhmerError::ReadOnly);
+    assert_eq!(get::<Book>(book.id, &read_only).unwrap().delete(&read_only).unwrap_err(), OhmerError::ReadOnly);
+    assert_eq!(book.eval_script("return redis.call('HSET', KEYS[1], 'title', ARGV[1])", &[], &["Beloved"], &read_only).unwrap_err(),
+            OhmerError::ReadOnly);
+
+    // reads still work, and nothing was written
+    assert_eq!(len!(shelf.books, read_only).unwrap(), 1);
+    assert_eq!(counter!(shelf.visits, &read_only).unwrap(), 0);
+    assert_eq!(get::<Book>(book.id, &read_only).unwrap().title, "Walden".to_owned());
+}
+
+#[test]
+fn test_select_db() {
+    let client = redis::Client::open("redis://127.0.0.1/3").unwrap();
+    let connection = client.get_connection().unwrap();
+    let default = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    // saving runs a script, and loading many objects a pipeline
+    let archive = create!(Archive { year: 1999, }, &client).unwrap();
+    create!(Archive { year: 1999, }, &connection).unwrap();
+    assert_eq!(get::<Archive>(archive.id, &connection).unwrap(), archive);
+    let found = Query::<Archive>::find("year", "1999", &client).into_vec().unwrap();
+    assert!(found.contains(&archive));
+
+    let exists:bool = redis::cmd("EXISTS").arg("Archive:all").query(&default).unwrap();
+    assert!(!exists);
+    let exists:bool = redis::cmd("EXISTS").arg("Archive:indices:year:1999").query(&default).unwrap();
+    assert!(!exists);
+}
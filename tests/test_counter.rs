@@ -2,7 +2,10 @@
 extern crate redis;
 extern crate rustc_serialize;
 
- use ohmers::{Ohmer, Counter};
+use std::i64;
+use std::thread;
+
+ use ohmers::{Ohmer, OhmerError, Counter};
 use rustc_serialize::Encodable;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -43,3 +46,50 @@ fn test_counter() {
     assert_eq!(incr!(candidate.positive_votes, &client).unwrap(), 3);
     assert_eq!(decr!(candidate.positive_votes, &client).unwrap(), 2);
 }
+
+#[test]
+fn test_counter_overflow() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut candidate = Candidate::default();
+    candidate.save(&client).unwrap();
+
+    assert_eq!(incr!(candidate.positive_votes, i64::MAX, &client).unwrap(), i64::MAX);
+    assert_eq!(incr!(candidate.positive_votes, &client), Err(OhmerError::CounterOverflow));
+    assert_eq!(candidate.positive_votes.get(&candidate, "positive_votes", &client).unwrap(), i64::MAX);
+
+    assert_eq!(incr!(candidate.negative_votes, i64::MIN, &client).unwrap(), i64::MIN);
+    assert_eq!(decr!(candidate.negative_votes, &client), Err(OhmerError::CounterOverflow));
+    assert_eq!(candidate.negative_votes.incr_saturating(&candidate, "negative_votes", -10, &client).unwrap(), i64::MIN);
+    assert_eq!(candidate.negative_votes.incr_saturating(&candidate, "negative_votes", 10, &client).unwrap(), i64::MIN + 10);
+    assert_eq!(candidate.negative_votes.incr_saturating(&candidate, "negative_votes", i64::MAX, &client).unwrap(), -1);
+}
+
+#[test]
+fn test_counter_get_and_reset() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut candidate = Candidate::default();
+    candidate.save(&client).unwrap();
+
+    candidate.positive_votes.set(&candidate, "positive_votes", 10, &client).unwrap();
+    assert_eq!(candidate.positive_votes.get(&candidate, "positive_votes", &client).unwrap(), 10);
+
+    let id = candidate.id;
+    let handles = (0..4).map(|_| thread::spawn(move || {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let candidate = Candidate { id: id, ..Candidate::default() };
+        for _ in 0..100 {
+            incr!(candidate.positive_votes, &client).unwrap();
+        }
+    })).collect::<Vec<_>>();
+
+    let mut total = 0;
+    for _ in 0..20 {
+        total += candidate.positive_votes.get_and_reset(&candidate, "positive_votes", &client).unwrap();
+    }
+    for h in handles {
+        h.join().unwrap();
+    }
+    total += candidate.positive_votes.get_and_reset(&candidate, "positive_votes", &client).unwrap();
+    assert_eq!(total, 410);
+    assert_eq!(candidate.positive_votes.get(&candidate, "positive_votes", &client).unwrap(), 0);
+}
@@ -0,0 +1,111 @@
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, get_by_string_id, Ohmer, OhmerConnection, OhmerError};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Coupon {
+    id: usize,
+    code: String,
+}
+
+impl Default for Coupon {
+    fn default() -> Self {
+        Coupon {
+            id: 0,
+            code: "".to_string(),
+        }
+    }
+}
+impl Ohmer for Coupon {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn generate_id(&self, _: &OhmerConnection) -> Result<Option<usize>, OhmerError> {
+        Ok(Some(900_000_000 + self.code.len()))
+    }
+}
+
+#[test]
+fn test_generate_id() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Coupon:all").unwrap();
+
+    let mut coupon = Coupon::default();
+    coupon.code = "FREE".to_string();
+    coupon.save(&client).unwrap();
+    assert_eq!(coupon.id, 900_000_004);
+    assert_eq!(get::<Coupon>(coupon.id, &client).unwrap(), coupon);
+
+    // updates keep the id
+    coupon.code = "GIFT".to_string();
+    coupon.save(&client).unwrap();
+    assert_eq!(coupon.id, 900_000_004);
+
+    let mut other = Coupon::default();
+    other.code = "HALF".to_string();
+    assert_eq!(other.save(&client), Err(OhmerError::IdInUse(900_000_004)));
+    assert_eq!(other.id, 0);
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default, Clone)]
+struct Boarding {
+    id: usize,
+    uid: String,
+    seat: String,
+}
+
+impl Ohmer for Boarding {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn string_id_field(&self) -> Option<&'static str> { Some("uid") }
+    fn generate_string_id(&self, r: &OhmerConnection) -> Result<Option<String>, OhmerError> {
+        let n:u64 = try!(redis::cmd("INCR").arg("Boarding:uid").query(r.conn()));
+        Ok(Some(format!("brd-{}", n)))
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+struct Badge {
+    id: usize,
+    uid: String,
+}
+
+impl Ohmer for Badge {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn string_id_field(&self) -> Option<&'static str> { Some("uid") }
+}
+
+#[test]
+fn test_string_id() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut boarding = Boarding { seat: "12A".to_string(), ..Default::default() };
+    boarding.save(&client).unwrap();
+    assert!(boarding.uid.starts_with("brd-"));
+    assert_eq!(get_by_string_id::<Boarding>(&*boarding.uid, &client).unwrap(), Some(boarding.clone()));
+
+    // updates keep the string id
+    let uid = boarding.uid.clone();
+    boarding.seat = "12B".to_string();
+    boarding.save(&client).unwrap();
+    assert_eq!(boarding.uid, uid);
+    assert_eq!(&*get_by_string_id::<Boarding>(&*uid, &client).unwrap().unwrap().seat, "12B");
+
+    // string ids set by the caller are kept, and must be unused
+    let mut copy = Boarding { uid: uid.clone(), ..Default::default() };
+    assert_eq!(copy.save(&client), Err(OhmerError::UniqueIndexViolation("uid".to_string())));
+    assert_eq!(get_by_string_id::<Boarding>("brd-missing", &client).unwrap(), None);
+
+    let id = boarding.id;
+    boarding.delete(&client).unwrap();
+    assert_eq!(get_by_string_id::<Boarding>(&*uid, &client).unwrap(), None);
+    assert!(get::<Boarding>(id, &client).is_err());
+
+    let mut badge = Badge::default();
+    assert_eq!(badge.save(&client), Err(OhmerError::MissingStringId));
+    assert_eq!(get_by_string_id::<Coupon>("FREE", &client), Err(OhmerError::UnknownIndex("string id".to_string())));
+}
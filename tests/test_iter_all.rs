@@ -5,7 +5,7 @@ extern crate rustc_serialize;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
-use ohmers::{all, Ohmer};
+use ohmers::{all, get, reindex, Ohmer, OhmerError, Reference};
 use redis::Commands;
 use rustc_serialize::Encodable;
 
@@ -51,3 +51,75 @@ fn test_iter_all() {
             HashSet::from_iter(vec![toyota, mercedes])
             );
 }
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Hash, Clone)]
+struct LogEntry {
+    id: usize,
+    message: String,
+}
+impl Default for LogEntry {
+    fn default() -> Self {
+        LogEntry {
+            id: 0,
+            message: "".to_string(),
+        }
+    }
+}
+impl Ohmer for LogEntry {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn track_all(&self) -> bool { false }
+}
+
+#[test]
+fn test_iter_all_untracked() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut entry = LogEntry::default();
+    entry.message = "started".to_string();
+    entry.save(&client).unwrap();
+    assert!(entry.id > 0);
+
+    let exists:bool = client.exists("LogEntry:all").unwrap();
+    assert!(!exists);
+    assert_eq!(all::<LogEntry>(&client).unwrap().count(), 0);
+    assert_eq!(get::<LogEntry>(entry.id, &client).unwrap(), entry);
+    assert_eq!(Reference::with_value(&entry).try_get(&client).unwrap(), Some(entry.clone()));
+
+    entry.message = "stopped".to_string();
+    entry.save(&client).unwrap();
+    assert_eq!(&*get::<LogEntry>(entry.id, &client).unwrap().message, "stopped");
+    assert_eq!(reindex::<LogEntry>(&client), Err(OhmerError::Untracked("LogEntry".to_string())));
+    entry.delete(&client).unwrap();
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+struct Lap {
+    id: usize,
+    time: u32,
+}
+impl Ohmer for Lap {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[test]
+fn test_iter_stops_at_load_error() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Lap:all").unwrap();
+
+    let laps = (0..3).map(|time| {
+        let mut lap = Lap { id: 0, time: time };
+        lap.save(&client).unwrap();
+        lap
+    }).collect::<Vec<_>>();
+    let _:bool = client.hset(format!("Lap:{}", laps[1].id), "time", "fast").unwrap();
+
+    let mut iter = all::<Lap>(&client).unwrap();
+    assert_eq!(iter.len(), 3);
+    assert_eq!(iter.next(), Some(Lap { id: laps[0].id, time: 0 }));
+    assert_eq!(iter.next(), None);
+    assert_eq!(iter.len(), 0);
+    assert_eq!(iter.next(), None);
+    assert_eq!(iter.next_back(), None);
+}
@@ -5,7 +5,7 @@ extern crate rustc_serialize;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
-use ohmers::{all, Ohmer, Query};
+use ohmers::{all, Ohmer, Query, StalSet};
 use redis::Commands;
 use rustc_serialize::Encodable;
 
@@ -94,5 +94,44 @@ fn test_iter_find() {
     let mut query = Query::<Cat>::find("age", "2", &client);
     query.diff("is_male", "0");
     let cats = query.try_into_iter().unwrap().collect::<HashSet<_>>();
-    assert_eq!(HashSet::from_iter(vec![mozart].into_iter()), cats);
+    assert_eq!(HashSet::from_iter(vec![mozart.clone()].into_iter()), cats);
+
+    let young = Query::<Cat>::find("age", "2", &client).combine_union(Query::<Cat>::find("age", "3", &client));
+    let cats = young.combine_inter(Query::<Cat>::find("is_male", "0", &client))
+        .try_iter().unwrap().collect::<HashSet<_>>();
+    assert_eq!(HashSet::from_iter(vec![merry.clone(), dorothy.clone()].into_iter()), cats);
+
+    let cats = Query::<Cat>::find("age", "2", &client).combine_diff(Query::<Cat>::find("is_male", "1", &client))
+        .try_into_iter().unwrap().collect::<HashSet<_>>();
+    assert_eq!(HashSet::from_iter(vec![dorothy].into_iter()), cats);
+}
+
+#[test]
+fn test_query_temporary_keys() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.sadd("TemporaryKeys:a", vec![1, 2, 3]).unwrap();
+    let _:() = client.sadd("TemporaryKeys:b", vec![2, 3, 4]).unwrap();
+    let _:() = client.sadd("TemporaryKeys:c", vec![3, 5]).unwrap();
+    let _:() = client.set("TemporaryKeys:string", "not a set").unwrap();
+    let key = |k: &str| StalSet::Key(format!("TemporaryKeys:{}", k).into_bytes());
+
+    for _ in 0..100 {
+        let query = Query::<Cat>::new(StalSet::Union(vec![
+                StalSet::Inter(vec![key("a"), key("b")]),
+                StalSet::Diff(vec![key("c"), key("a")]),
+                ]), &client);
+        assert_eq!(query.try_iter().unwrap().len(), 3);
+        assert_eq!(query.sort("name", None, true, true).unwrap().len(), 3);
+        assert_eq!(query.combine_inter(Query::new(key("c"), &client)).try_into_iter().unwrap().len(), 2);
+
+        // the failing command is in the middle of the transaction
+        let query = Query::<Cat>::new(StalSet::Inter(vec![
+                StalSet::Union(vec![key("a"), key("string")]),
+                StalSet::Union(vec![key("b"), key("c")]),
+                ]), &client);
+        assert!(query.try_iter().is_err());
+    }
+
+    let leaked:Vec<String> = client.keys("stal:*").unwrap();
+    assert_eq!(leaked, Vec::<String>::new());
 }
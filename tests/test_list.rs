@@ -1,9 +1,11 @@
 #[macro_use(model, create, len, push_back, push_front, pop_back, pop_front,
-        first, last, try_range, try_iter, contains, remove)] extern crate ohmers;
+        first, last, try_range, try_iter, contains, remove, list_get, list_set,
+        insert_before, insert_after, move_to, clear)] extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
-use ohmers::{Ohmer, List};
+use ohmers::{Ohmer, OhmerError, List};
+use redis::Commands;
 use rustc_serialize::Encodable;
 
 model!(derive { Clone } Task {
@@ -176,3 +178,253 @@ fn test_list_macros() {
     assert_eq!(len!(q2.tasks, &client).unwrap(), 0);
     assert_eq!(remove!(q2.tasks, t3, &client).unwrap(), 0);
 }
+
+#[test]
+fn test_list_index() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue {
+            name: "q".to_string(),
+            }, &client).unwrap();
+
+    let t1 = create!(Task {
+            payload: "t1".to_string(),
+            }, &client).unwrap();
+    let t2 = create!(Task {
+            payload: "t2".to_string(),
+            }, &client).unwrap();
+    let t3 = create!(Task {
+            payload: "t3".to_string(),
+            }, &client).unwrap();
+
+    assert_eq!(q.tasks.get("tasks", &q, 0, &client).unwrap(), None);
+    assert!(q.tasks.set("tasks", &q, 0, &t1, &client).is_err());
+
+    push_back!(q.tasks, t1, client).unwrap();
+    push_back!(q.tasks, t2, client).unwrap();
+
+    assert_eq!(q.tasks.get("tasks", &q, 0, &client).unwrap(), Some(t1.clone()));
+    assert_eq!(q.tasks.get("tasks", &q, -1, &client).unwrap(), Some(t2.clone()));
+    assert_eq!(q.tasks.get("tasks", &q, 2, &client).unwrap(), None);
+
+    q.tasks.set("tasks", &q, 1, &t3, &client).unwrap();
+    assert_eq!(list_get!(q.tasks[1], client).unwrap(), Some(t3.clone()));
+    list_set!(q.tasks[-2], t2, client).unwrap();
+    assert_eq!(list_get!(q.tasks[0], client).unwrap(), Some(t2.clone()));
+    assert!(list_set!(q.tasks[5], t1, client).is_err());
+    assert_eq!(len!(q.tasks, &client).unwrap(), 2);
+}
+
+#[test]
+fn test_list_insert_pivot() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue {
+            name: "q".to_string(),
+            }, &client).unwrap();
+
+    let t1 = create!(Task {
+            payload: "t1".to_string(),
+            }, &client).unwrap();
+    let t2 = create!(Task {
+            payload: "t2".to_string(),
+            }, &client).unwrap();
+    let t3 = create!(Task {
+            payload: "t3".to_string(),
+            }, &client).unwrap();
+    let t4 = create!(Task {
+            payload: "t4".to_string(),
+            }, &client).unwrap();
+    let t5 = create!(Task {
+            payload: "t5".to_string(),
+            }, &client).unwrap();
+
+    assert_eq!(q.tasks.insert_before("tasks", &q, &t1, &t2, &client).unwrap(), None);
+
+    push_back!(q.tasks, t1, client).unwrap();
+    push_back!(q.tasks, t3, client).unwrap();
+
+    assert_eq!(q.tasks.insert_before("tasks", &q, &t3, &t2, &client).unwrap(), Some(3));
+    assert_eq!(insert_after!(q.tasks, t3, t4, client).unwrap(), Some(4));
+    assert_eq!(insert_before!(q.tasks, t4, t1, client).unwrap(), Some(5));
+    assert_eq!(q.tasks.insert_after("tasks", &q, &t4, &t4, &client).unwrap(), Some(6));
+    assert_eq!(q.tasks.insert_after("tasks", &q, &t5, &t1, &client).unwrap(), None);
+
+    let payloads = try_iter!(q.tasks, client).unwrap().map(|t| t.payload).collect::<Vec<_>>();
+    assert_eq!(payloads, vec!["t1", "t2", "t3", "t1", "t4", "t4"]);
+}
+
+#[test]
+fn test_list_trim() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue {
+            name: "q".to_string(),
+            }, &client).unwrap();
+
+    let t1 = create!(Task {
+            payload: "t1".to_string(),
+            }, &client).unwrap();
+    let t2 = create!(Task {
+            payload: "t2".to_string(),
+            }, &client).unwrap();
+
+    for i in 0..150 {
+        let t = if i % 2 == 0 { &t1 } else { &t2 };
+        q.tasks.push_back_capped("tasks", &q, t, 100, &client).unwrap();
+    }
+    assert_eq!(len!(q.tasks, &client).unwrap(), 100);
+    assert_eq!(last!(q.tasks, client).unwrap(), Some(t2.clone()));
+
+    q.tasks.trim("tasks", &q, 0, 9, &client).unwrap();
+    assert_eq!(len!(q.tasks, &client).unwrap(), 10);
+    assert_eq!(first!(q.tasks, client).unwrap(), Some(t1.clone()));
+
+    q.tasks.push_back_capped("tasks", &q, &t1, 0, &client).unwrap();
+    assert_eq!(len!(q.tasks, &client).unwrap(), 0);
+}
+
+#[test]
+fn test_list_move_to() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let pending = create!(Queue {
+            name: "pending".to_string(),
+            }, &client).unwrap();
+    let processing = create!(Queue {
+            name: "processing".to_string(),
+            }, &client).unwrap();
+
+    let t1 = create!(Task {
+            payload: "t1".to_string(),
+            }, &client).unwrap();
+    let t2 = create!(Task {
+            payload: "t2".to_string(),
+            }, &client).unwrap();
+
+    push_back!(pending.tasks, t1, client).unwrap();
+    push_back!(pending.tasks, t2, client).unwrap();
+
+    assert_eq!(pending.tasks.move_to("tasks", &pending, &processing.tasks, "tasks", &processing, &client).unwrap(), Some(t2.clone()));
+    assert_eq!(len!(pending.tasks, &client).unwrap(), 1);
+    assert_eq!(first!(processing.tasks, client).unwrap(), Some(t2.clone()));
+
+    assert_eq!(move_to!(pending.tasks => processing.tasks, client).unwrap(), Some(t1.clone()));
+    assert_eq!(len!(pending.tasks, &client).unwrap(), 0);
+    assert_eq!(first!(processing.tasks, client).unwrap(), Some(t1.clone()));
+    assert_eq!(len!(processing.tasks, &client).unwrap(), 2);
+
+    assert_eq!(move_to!(pending.tasks => processing.tasks, client).unwrap(), None);
+}
+
+#[test]
+fn test_list_ids() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue { name: "ids".to_string(), }, &client).unwrap();
+    let t1 = create!(Task { payload: "t1".to_string(), }, &client).unwrap();
+    let t2 = create!(Task { payload: "t2".to_string(), }, &client).unwrap();
+
+    assert!(!q.tasks.contains_id("tasks", &q, t1.id, &client).unwrap());
+    q.tasks.push_back_id("tasks", &q, t1.id, &client).unwrap();
+    q.tasks.push_front_id("tasks", &q, t2.id, &client).unwrap();
+    q.tasks.push_back_id("tasks", &q, t2.id, &client).unwrap();
+    assert!(q.tasks.contains_id("tasks", &q, t1.id, &client).unwrap());
+    assert_eq!(try_iter!(q.tasks, client).unwrap().map(|t| t.id).collect::<Vec<_>>(), vec![t2.id, t1.id, t2.id]);
+
+    assert_eq!(q.tasks.remove_id("tasks", &q, t2.id, &client).unwrap(), 2);
+    assert!(!q.tasks.contains_id("tasks", &q, t2.id, &client).unwrap());
+    assert_eq!(len!(q.tasks, client).unwrap(), 1);
+}
+
+model!(PTask {
+        indices { priority: String = "low".to_string(); };
+        payload: String = "".to_string();
+        });
+
+model!(PQueue {
+        tasks: List<PTask> = List::new();
+        });
+
+#[test]
+fn test_list_query() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(PQueue {}, &client).unwrap();
+    assert_eq!(q.tasks.query("tasks", &q, &client).unwrap().try_iter().unwrap().count(), 0);
+
+    let t1 = create!(PTask { payload: "t1".to_string(), priority: "high".to_string(), }, &client).unwrap();
+    let t2 = create!(PTask { payload: "t2".to_string(), }, &client).unwrap();
+    let t3 = create!(PTask { payload: "t3".to_string(), priority: "high".to_string(), }, &client).unwrap();
+    // high priority, but not in the list
+    create!(PTask { payload: "t4".to_string(), priority: "high".to_string(), }, &client).unwrap();
+    push_back!(q.tasks, t1, client).unwrap();
+    push_back!(q.tasks, t2, client).unwrap();
+    push_back!(q.tasks, t3, client).unwrap();
+
+    let mut query = q.tasks.query("tasks", &q, &client).unwrap();
+    query.inter("priority", "high");
+    let mut payloads = query.sort("payload", None, true, true).unwrap().map(|t| t.payload).collect::<Vec<_>>();
+    assert_eq!(payloads, vec!["t1".to_string(), "t3".to_string()]);
+    payloads = query.try_into_iter().unwrap().map(|t| t.payload).collect::<Vec<_>>();
+    payloads.sort();
+    assert_eq!(payloads, vec!["t1".to_string(), "t3".to_string()]);
+
+    // the temporary set does not outlive the query
+    let exists:bool = redis::cmd("EXISTS").arg(format!("PQueue:tasks:{}:query", q.id)).query(&client).unwrap();
+    assert!(!exists);
+}
+
+#[test]
+fn test_list_wrong_type() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q1 = create!(Queue { name: "legacy".to_string(), }, &client).unwrap();
+    let q2 = create!(Queue { name: "new".to_string(), }, &client).unwrap();
+    let t1 = create!(Task { payload: "t1".to_string(), }, &client).unwrap();
+    let key = format!("Queue:tasks:{}", q1.id);
+    let _:() = client.set(&*key, "t1,t2").unwrap();
+
+    let wrong_type = || OhmerError::WrongType { key: key.clone(), expected: "list" };
+    assert_eq!(q1.tasks.len("tasks", &q1, &client), Err(wrong_type()));
+    assert_eq!(q1.tasks.push_back("tasks", &q1, &t1, &client), Err(wrong_type()));
+    assert_eq!(q1.tasks.push_back_capped("tasks", &q1, &t1, 5, &client), Err(wrong_type()));
+    assert_eq!(q2.tasks.move_to("tasks", &q2, &q1.tasks, "tasks", &q1, &client), Ok(None));
+    q2.tasks.push_back("tasks", &q2, &t1, &client).unwrap();
+    assert_eq!(q2.tasks.move_to("tasks", &q2, &q1.tasks, "tasks", &q1, &client), Err(wrong_type()));
+    assert_eq!(q1.tasks.move_to("tasks", &q1, &q2.tasks, "tasks", &q2, &client), Err(wrong_type()));
+}
+
+#[test]
+fn test_list_clear() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue { name: "reset".to_string(), }, &client).unwrap();
+    let t1 = create!(Task { payload: "t1".to_string(), }, &client).unwrap();
+    push_back!(q.tasks, t1, client).unwrap();
+    push_back!(q.tasks, t1, client).unwrap();
+
+    assert!(clear!(q.tasks, client).unwrap());
+    assert_eq!(len!(q.tasks, client).unwrap(), 0);
+    assert!(!q.tasks.clear("tasks", &q, &client).unwrap());
+    assert_eq!(ohmers::get::<Queue>(q.id, &client).unwrap().name, "reset");
+}
+
+#[test]
+fn test_list_to_vec() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let q = create!(Queue { name: "to_vec".to_string(), }, &client).unwrap();
+    assert_eq!(q.tasks.to_vec("tasks", &q, &client).unwrap(), vec![]);
+
+    let t1 = create!(Task { payload: "t1".to_string(), }, &client).unwrap();
+    let t2 = create!(Task { payload: "t2".to_string(), }, &client).unwrap();
+    push_back!(q.tasks, t2, client).unwrap();
+    push_back!(q.tasks, t1, client).unwrap();
+    push_back!(q.tasks, t2, client).unwrap();
+    assert_eq!(q.tasks.to_vec("tasks", &q, &client).unwrap(), vec![t2.clone(), t1.clone(), t2.clone()]);
+
+    let id = t1.id;
+    t1.delete(&client).unwrap();
+    assert_eq!(q.tasks.to_vec("tasks", &q, &client), Err(OhmerError::NotFound(id)));
+}
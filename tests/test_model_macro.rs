@@ -1,11 +1,11 @@
-#[macro_use(model)] extern crate ohmers;
+#[macro_use(model, field, find)] extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
-use ohmers::{get, Ohmer};
+use ohmers::{get, Counter, Ohmer, Query};
 use redis::Commands;
 use rustc_serialize::Encodable;
 
@@ -94,3 +94,167 @@ fn test_model_unique_index_macro() {
 
     assert_eq!(get::<UIPerson>(person.id, &client).unwrap(), person);
 }
+
+model!(CPerson {
+        name:String = "".to_owned();
+        visits:Counter = Counter;
+        likes:Counter = Counter;
+        });
+
+#[test]
+fn test_model_counters_macro() {
+    let person = CPerson::default();
+    assert_eq!(person.counters(), HashSet::from_iter(vec!["visits".to_owned(), "likes".to_owned()]));
+    // cached after the first call
+    assert_eq!(person.counters(), HashSet::from_iter(vec!["visits".to_owned(), "likes".to_owned()]));
+    assert_eq!(Person::default().counters().len(), 0);
+}
+
+#[test]
+fn test_model_builder() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("UIPerson:uniques:name").unwrap();
+
+    let person = UIPersonBuilder::default().name("Jane".to_owned()).maybe_age(None).build();
+    assert_eq!(person.id, 0);
+    assert_eq!(&*person.name, "Jane");
+    assert_eq!(person.age, 18);
+
+    let person = UIPersonBuilder::default().name("Jane".to_owned()).maybe_age(Some(40))
+        .create(&client).unwrap();
+    assert!(person.id > 0);
+    assert_eq!(get::<UIPerson>(person.id, &client).unwrap().age, 40);
+}
+
+model!(Vehicle {
+        indices { r#type:String = "car".to_owned(); };
+        r#match:String = "".to_owned();
+        });
+
+#[test]
+fn test_model_raw_identifiers() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("Vehicle:indices:type:truck").unwrap();
+
+    let vehicle = VehicleBuilder::default().r#type("truck".to_owned()).maybe_match(Some("yes".to_owned())).build();
+    assert_eq!(vehicle.index_fields(), HashSet::from_iter(vec!["type"]));
+    let vehicle = vehicle.create(&client).unwrap();
+
+    let stored:Option<String> = client.hget(format!("Vehicle:{}", vehicle.id), "type").unwrap();
+    assert_eq!(stored, Some("truck".to_owned()));
+
+    let found = Query::<Vehicle>::find("type", "truck", &client).try_iter().unwrap().collect::<Vec<_>>();
+    assert_eq!(found.len(), 1);
+    assert_eq!(&*found[0].r#match, "yes");
+}
+
+#[test]
+fn test_model_field_names() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("Vehicle:indices:type:bus").unwrap();
+
+    assert_eq!(field!(Vehicle.r#type), "type");
+    assert_eq!(field!(Vehicle.r#match), "match");
+    assert_eq!(field!(Vehicle.id), "id");
+    assert!(Vehicle::default().index_fields().contains(field!(Vehicle.r#type)));
+
+    VehicleBuilder::default().r#type("bus".to_owned()).create(&client).unwrap();
+    let mut query = Query::<Vehicle>::find(field!(Vehicle.r#type), "bus", &client);
+    assert_eq!(query.try_iter().unwrap().count(), 1);
+    query.inter(field!(Vehicle.r#type), "car");
+    assert_eq!(query.try_iter().unwrap().count(), 0);
+    assert_eq!(find!(Vehicle { r#type: "bus", }, &client).try_iter().unwrap().count(), 1);
+}
+
+model!(
+        derive { Clone, Hash, Eq }
+        Tag {
+        name:String = "".to_owned();
+        });
+
+#[test]
+fn test_model_hash() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let rust = TagBuilder::default().name("rust".to_owned()).create(&client).unwrap();
+    let redis = TagBuilder::default().name("redis".to_owned()).create(&client).unwrap();
+    let mut renamed = rust.clone();
+    renamed.name = "rustlang".to_owned();
+
+    let mut tags = HashSet::new();
+    assert!(tags.insert(rust.clone()));
+    assert!(tags.insert(redis.clone()));
+    assert!(!tags.insert(renamed));
+    assert_eq!(tags.len(), 2);
+
+    let copy = TagBuilder::default().name("rust".to_owned()).create(&client).unwrap();
+    assert!(tags.insert(copy));
+    assert_eq!(tags.len(), 3);
+}
+
+fn trim(value: &str) -> String {
+    value.trim().to_owned()
+}
+
+model!(TPerson {
+        uniques { nick:String = "".to_owned(); };
+        indices {
+            email:String = "".to_owned() => str::to_lowercase;
+            city:String = "".to_owned() => trim;
+            age:u8 = 18;
+        };
+        });
+
+#[test]
+fn test_model_index_transform() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("TPerson:uniques:nick").unwrap();
+    let _:() = client.del("TPerson:indices:email:bob@example.com").unwrap();
+    let _:() = client.del("TPerson:indices:city:Lyon").unwrap();
+
+    let person = TPersonBuilder::default().nick("bob".to_owned())
+        .email("Bob@Example.com".to_owned()).city(" Lyon ".to_owned())
+        .create(&client).unwrap();
+    assert_eq!(person.index_value("email", "Bob@Example.com"), "bob@example.com");
+    assert_eq!(person.index_value("age", "18"), "18");
+    assert_eq!(&*get::<TPerson>(person.id, &client).unwrap().email, "Bob@Example.com");
+
+    let ids:HashSet<usize> = client.smembers("TPerson:indices:email:bob@example.com").unwrap();
+    assert_eq!(ids, HashSet::from_iter(vec![person.id]));
+    assert_eq!(find!(TPerson { email: "BOB@example.COM", city: "Lyon", }, &client)
+            .try_iter().unwrap().map(|p| p.id).collect::<Vec<_>>(), vec![person.id]);
+    assert_eq!(Query::<TPerson>::find("city", "Lyon  ", &client).try_iter().unwrap()
+            .map(|p| p.id).collect::<Vec<_>>(), vec![person.id]);
+}
+
+model!(
+    #[key_tag = "shop"]
+    KPerson {
+        uniques { nick:String = "".to_owned(); };
+        indices { city:String = "".to_owned(); };
+        visits:Counter = Counter;
+        });
+
+#[test]
+fn test_model_key_tag() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    ohmers::purge_class::<KPerson>(&client).unwrap();
+
+    let mut person = KPerson { nick: "kim".to_owned(), city: "Lima".to_owned(), ..Default::default() };
+    assert_eq!(person.key_tag(), Some("shop"));
+    assert_eq!(person.get_class_name(), "{shop}KPerson".to_owned());
+    assert_eq!(Person::default().key_tag(), None);
+    person.save(&client).unwrap();
+    person.visits.incr(&person, "visits", 1, &client).unwrap();
+    assert_eq!(person.key(), format!("{{shop}}KPerson:{}", person.id));
+    assert_eq!(person.key_for_index("city", "Lima"), "{shop}KPerson:indices:city:Lima".to_owned());
+    assert_eq!(person.key_for_unique("nick", "kim"), "{shop}KPerson:uniques:nick:kim".to_owned());
+
+    let keys:HashSet<String> = client.keys("*KPerson*").unwrap();
+    assert!(keys.contains(&*person.key()));
+    assert!(keys.contains("{shop}KPerson:all"));
+    assert!(keys.contains("{shop}KPerson:id"));
+    assert!(keys.contains("{shop}KPerson:indices:city:Lima"));
+    assert!(keys.iter().all(|key| key.starts_with("{shop}KPerson:")), "{:?}", keys);
+    assert_eq!(get::<KPerson>(person.id, &client).unwrap().nick, "kim".to_owned());
+}
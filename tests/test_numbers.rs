@@ -2,7 +2,8 @@ extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
-use ohmers::{get, Ohmer};
+use ohmers::{all_query, get, Ohmer};
+use redis::Commands;
 use rustc_serialize::Encodable;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -82,3 +83,68 @@ fn test_numbers_min() {
     let numbers2 = get(numbers.id, &client).unwrap();
     assert_eq!(numbers, numbers2);
 }
+
+#[test]
+fn test_numbers_negative() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut numbers = Numbers::default();
+    numbers.i8 = -1;
+    numbers.i16 = -300;
+    numbers.i32 = -70000;
+    numbers.i64 = -5000000000;
+    numbers.isize = -2;
+    numbers.save(&client).unwrap();
+
+    let numbers2 = get(numbers.id, &client).unwrap();
+    assert_eq!(numbers, numbers2);
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Measure {
+    id: usize,
+    size: u64,
+    delta: i64,
+}
+
+impl Default for Measure {
+    fn default() -> Self {
+        Measure {
+            id: 0,
+            size: 0,
+            delta: 0,
+        }
+    }
+}
+
+impl Ohmer for Measure {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[test]
+fn test_numbers_sort() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Measure:all").unwrap();
+
+    let values = vec![
+        (std::u64::MAX, -42),
+        (0, std::i64::MAX),
+        (1, std::i64::MIN),
+        (std::u64::MAX / 2, 0),
+        (10, -7),
+    ];
+    for &(size, delta) in values.iter() {
+        let mut measure = Measure::default();
+        measure.size = size;
+        measure.delta = delta;
+        measure.save(&client).unwrap();
+    }
+
+    let sizes = all_query::<Measure>(&client).unwrap().sort("size", None, true, false).unwrap()
+        .map(|m| m.size).collect::<Vec<_>>();
+    assert_eq!(sizes, vec![0, 1, 10, std::u64::MAX / 2, std::u64::MAX]);
+
+    let deltas = all_query::<Measure>(&client).unwrap().sort("delta", None, false, false).unwrap()
+        .map(|m| m.delta).collect::<Vec<_>>();
+    assert_eq!(deltas, vec![std::i64::MAX, 0, -7, -42, std::i64::MIN]);
+}
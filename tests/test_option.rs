@@ -2,7 +2,9 @@ extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
-use ohmers::{get, Ohmer};
+use std::collections::HashSet;
+
+use ohmers::{get, Ohmer, Query};
 use rustc_serialize::Encodable;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -48,3 +50,50 @@ fn test_option_none() {
     let person2 = get(person.id, &client).unwrap();
     assert_eq!(person, person2);
 }
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Subscriber {
+    id: usize,
+    email: Option<String>,
+}
+
+impl Default for Subscriber {
+    fn default() -> Self {
+        Subscriber {
+            id: 0,
+            email: None,
+        }
+    }
+}
+impl Ohmer for Subscriber {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn index_fields<'a>(&self) -> HashSet<&'a str> {
+        let mut hs = HashSet::new();
+        hs.insert("email");
+        hs
+    }
+}
+
+#[test]
+fn test_option_missing() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut with_email = Subscriber::default();
+    with_email.email = Some("dan@example.com".to_string());
+    with_email.save(&client).unwrap();
+
+    let mut without_email = Subscriber::default();
+    without_email.save(&client).unwrap();
+
+    let missing = || Query::<Subscriber>::missing("email", &client).unwrap()
+        .try_iter().unwrap().map(|s| s.id).collect::<Vec<_>>();
+    assert!(missing().contains(&without_email.id));
+    assert!(!missing().contains(&with_email.id));
+
+    with_email.email = None;
+    with_email.save(&client).unwrap();
+    without_email.email = Some("erin@example.com".to_string());
+    without_email.save(&client).unwrap();
+    assert!(missing().contains(&with_email.id));
+    assert!(!missing().contains(&without_email.id));
+}
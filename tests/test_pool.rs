@@ -0,0 +1,23 @@
+#![cfg(feature = "pool")]
+#[macro_use(model, create)] extern crate ohmers;
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, Ohmer, Pool};
+
+model!(Lamp {
+        color:String = "".to_owned();
+        });
+
+#[test]
+fn test_pool() {
+    let manager = r2d2_redis::RedisConnectionManager::new("redis://127.0.0.1/").unwrap();
+    let pool = Pool::new(r2d2::Pool::new(Default::default(), manager).unwrap());
+
+    let mut lamp = create!(Lamp { color: "red".to_owned(), }, &pool).unwrap();
+    lamp.color = "blue".to_owned();
+    lamp.save(&pool).unwrap();
+    assert_eq!(get::<Lamp>(lamp.id, &pool).unwrap().color, "blue".to_owned());
+}
@@ -0,0 +1,38 @@
+#![cfg(feature = "testing")]
+#[macro_use(model, create, insert, incr)] extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{purge_class, Counter, Ohmer, Set};
+use redis::Commands;
+
+model!(Crate {
+        uniques { label:String = "".to_owned(); };
+        indices { size:u8 = 0; };
+        opened:Counter = Counter;
+        items:Set<Item> = Set::new();
+        });
+
+model!(Item {
+        name:String = "".to_owned();
+        });
+
+#[test]
+fn test_purge_class() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let c = create!(Crate { label: "a".to_owned(), size: 3, }, &client).unwrap();
+    let item = create!(Item { name: "hammer".to_owned(), }, &client).unwrap();
+    insert!(c.items, item, client).unwrap();
+    incr!(c.opened, client).unwrap();
+
+    purge_class::<Crate>(&client).unwrap();
+
+    let keys:Vec<String> = client.keys("Crate:*").unwrap();
+    assert_eq!(keys, Vec::<String>::new());
+    // other classes are left alone
+    assert!(client.exists::<_, bool>(format!("Item:{}", item.id)).unwrap());
+
+    // uniques are released
+    create!(Crate { label: "a".to_owned(), }, &client).unwrap();
+}
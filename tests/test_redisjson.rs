@@ -0,0 +1,77 @@
+#![cfg(feature = "redisjson")]
+// Requires a server with the RedisJSON module, like Redis Stack.
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, Ohmer, Query};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default, Clone)]
+struct Cover {
+    color: String,
+    width: u16,
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default, Clone)]
+struct Booklet {
+    id: usize,
+    title: String,
+    pages: u32,
+    summary: Option<String>,
+    cover: Cover,
+}
+
+impl Ohmer for Booklet {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn store_as_json(&self) -> bool { true }
+    fn index_fields<'a>(&self) -> std::collections::HashSet<&'a str> {
+        vec!["title"].into_iter().collect()
+    }
+}
+
+#[test]
+fn test_redisjson_save_load() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Booklet:indices:title:Manual").unwrap();
+
+    let mut doc = Booklet { id: 0, title: "Manual".to_string(), pages: 12, summary: None, cover: Cover::default() };
+    doc.save(&client).unwrap();
+
+    let kind:String = redis::cmd("TYPE").arg(format!("Booklet:{}", doc.id)).query(&client).unwrap();
+    assert_eq!(&*kind, "ReJSON-RL");
+    assert_eq!(get::<Booklet>(doc.id, &client).unwrap(), doc);
+
+    doc.pages = 13;
+    doc.summary = Some("How to".to_string());
+    doc.cover = Cover { color: "red".to_string(), width: 210 };
+    doc.save(&client).unwrap();
+    assert_eq!(get::<Booklet>(doc.id, &client).unwrap(), doc);
+    let width:u16 = redis::cmd("JSON.GET").arg(format!("Booklet:{}", doc.id)).arg(".cover.width").query(&client).unwrap();
+    assert_eq!(width, 210);
+    assert_eq!(Query::<Booklet>::find("title", "Manual", &client).into_vec().unwrap(), vec![doc.clone()]);
+    assert_eq!(Query::<Booklet>::find("title", "Manual", &client).try_iter().unwrap()
+            .collect::<Vec<_>>(), vec![doc]);
+}
+
+#[test]
+fn test_redisjson_create_many() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Booklet:indices:title:Leaflet").unwrap();
+
+    let mut docs = (0..3).map(|pages| Booklet {
+        id: 0,
+        title: "Leaflet".to_string(),
+        pages: pages,
+        summary: None,
+        cover: Cover { color: "blue".to_string(), width: 100 + pages as u16 },
+    }).collect::<Vec<_>>();
+    ohmers::create_many(&mut docs, &client).unwrap();
+
+    let kind:String = redis::cmd("TYPE").arg(format!("Booklet:{}", docs[0].id)).query(&client).unwrap();
+    assert_eq!(&*kind, "ReJSON-RL");
+    assert_eq!(get::<Booklet>(docs[2].id, &client).unwrap(), docs[2]);
+    assert_eq!(Query::<Booklet>::find("title", "Leaflet", &client).into_vec().unwrap(), docs);
+}
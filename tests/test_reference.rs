@@ -2,7 +2,7 @@ extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
-use ohmers::{get, Ohmer, Reference};
+use ohmers::{get, preload, Ohmer, Reference};
 use rustc_serialize::Encodable;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -108,3 +108,38 @@ fn test_event_location() {
     assert_eq!(event2.name, "Birthday Party");
     assert_eq!(event2.location.get(&client).unwrap().name, "House");
 }
+
+#[test]
+fn test_preload() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut father = Person::default();
+    father.name = "Bob".to_string();
+    father.save(&client).unwrap();
+
+    let mut mother = Person::default();
+    mother.name = "Carol".to_string();
+    mother.save(&client).unwrap();
+
+    let mut children = vec![];
+    for name in &["Dan", "Erin", "Frank"] {
+        let mut child = Person::default();
+        child.name = name.to_string();
+        child.father.set(&father);
+        child.mother.set(&mother);
+        child.save(&client).unwrap();
+        children.push(child);
+    }
+
+    let fathers = preload(&children, |c| &c.father, &client).unwrap();
+    assert_eq!(fathers.len(), 1);
+    assert_eq!(fathers[&father.id], father);
+
+    let parents = Reference::get_many(&[&children[0].father, &children[1].mother, &father.father], &client).unwrap();
+    assert_eq!(parents.len(), 2);
+    assert_eq!(parents[&mother.id], mother);
+
+    mother.delete(&client).unwrap();
+    assert!(preload(&children, |c| &c.mother, &client).unwrap().is_empty());
+    assert!(preload(&[] as &[Person], |c| &c.father, &client).unwrap().is_empty());
+}
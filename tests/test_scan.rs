@@ -0,0 +1,66 @@
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use std::collections::HashSet;
+
+use ohmers::{scan, Ohmer};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Hash)]
+struct Ship {
+    id: usize,
+    name: String,
+}
+impl Default for Ship {
+    fn default() -> Self {
+        Ship {
+            id: 0,
+            name: "".to_string(),
+        }
+    }
+}
+impl Ohmer for Ship {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[test]
+fn test_scan() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let _:bool = client.del("Ship:all").unwrap();
+
+    assert_eq!(scan::<Ship>(&client, 10).count(), 0);
+
+    // more than fits in an intset, so SSCAN really pages
+    let mut ids = HashSet::new();
+    for i in 0..600 {
+        let mut ship = Ship::default();
+        ship.name = format!("ship {}", i);
+        ship.save(&client).unwrap();
+        ids.insert(ship.id);
+    }
+
+    let seen = scan::<Ship>(&client, 10).map(|s| s.unwrap().id).collect::<HashSet<_>>();
+    assert_eq!(seen, ids);
+
+    // a batch of 0 is not sent as COUNT 0, which Redis rejects
+    let seen = scan::<Ship>(&client, 0).map(|s| s.unwrap().id).collect::<HashSet<_>>();
+    assert_eq!(seen, ids);
+
+    // ids removed mid-scan are skipped instead of loaded as empty objects
+    let mut iter = scan::<Ship>(&client, 10);
+    let first = iter.next().unwrap().unwrap();
+    let removed = ids.iter().filter(|id| **id != first.id).take(300).cloned().collect::<HashSet<_>>();
+    for id in removed.iter() {
+        let _:bool = client.srem("Ship:all", *id).unwrap();
+        let _:bool = client.del(format!("Ship:{}", id)).unwrap();
+    }
+    for ship in iter {
+        let ship = ship.unwrap();
+        assert!(ids.contains(&ship.id));
+        assert!(ship.name.starts_with("ship "));
+    }
+}
@@ -1,4 +1,4 @@
-#[macro_use(insert)] extern crate ohmers;
+#[macro_use(clear, insert, move_member, set_iter)] extern crate ohmers;
 extern crate redis;
 extern crate rustc_serialize;
 
@@ -81,3 +81,130 @@ fn test_set() {
 
     assert_eq!(team.players.len("players", &team, &client).unwrap(), 1);
 }
+
+#[test]
+fn test_set_iter() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut p1 = Player::default();
+    p1.name = "Carol".to_string();
+    p1.save(&client).unwrap();
+
+    let mut p2 = Player::default();
+    p2.name = "Dave".to_string();
+    p2.save(&client).unwrap();
+
+    let mut team = Team::default();
+    team.name = "bar".to_string();
+    team.save(&client).unwrap();
+
+    assert_eq!(team.players.try_iter("players", &team, &client).unwrap().count(), 0);
+
+    insert!(team.players, &p1, &client).unwrap();
+    insert!(team.players, &p2, &client).unwrap();
+
+    let mut names = team.players.try_iter("players", &team, &client).unwrap()
+        .map(|p| p.name).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["Carol".to_string(), "Dave".to_string()]);
+
+    assert_eq!(set_iter!(team.players, &client).unwrap().count(), 2);
+}
+
+#[test]
+fn test_set_ids() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut p1 = Player::default();
+    p1.name = "Erin".to_string();
+    p1.save(&client).unwrap();
+
+    let mut team = Team::default();
+    team.name = "baz".to_string();
+    team.save(&client).unwrap();
+
+    assert!(!team.players.contains_id("players", &team, p1.id, &client).unwrap());
+    assert!(team.players.insert_id("players", &team, p1.id, &client).unwrap());
+    assert!(!team.players.insert_id("players", &team, p1.id, &client).unwrap());
+    assert!(team.players.contains_id("players", &team, p1.id, &client).unwrap());
+    assert!(team.players.contains("players", &team, &p1, &client).unwrap());
+    assert!(team.players.remove_id("players", &team, p1.id, &client).unwrap());
+    assert!(!team.players.contains_id("players", &team, p1.id, &client).unwrap());
+}
+
+#[test]
+fn test_set_move_to() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut p1 = Player::default();
+    p1.name = "Frank".to_string();
+    p1.save(&client).unwrap();
+
+    let mut team = Team::default();
+    team.name = "qux".to_string();
+    team.save(&client).unwrap();
+
+    let mut bench = Team::default();
+    bench.name = "quux".to_string();
+    bench.save(&client).unwrap();
+
+    insert!(team.players, &p1, &client).unwrap();
+    assert!(team.players.move_to("players", &team, &p1, &bench.players, "players", &bench, &client).unwrap());
+    assert!(!team.players.contains("players", &team, &p1, &client).unwrap());
+    assert!(bench.players.contains("players", &bench, &p1, &client).unwrap());
+
+    assert!(!team.players.move_to("players", &team, &p1, &bench.players, "players", &bench, &client).unwrap());
+    assert!(bench.players.contains("players", &bench, &p1, &client).unwrap());
+
+    assert!(move_member!(bench.players => team.players, &p1, client).unwrap());
+    assert!(team.players.contains("players", &team, &p1, &client).unwrap());
+    assert_eq!(bench.players.len("players", &bench, &client).unwrap(), 0);
+}
+
+#[test]
+fn test_set_clear() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut p1 = Player::default();
+    p1.name = "Grace".to_string();
+    p1.save(&client).unwrap();
+
+    let mut team = Team::default();
+    team.name = "corge".to_string();
+    team.save(&client).unwrap();
+
+    insert!(team.players, &p1, &client).unwrap();
+    assert!(team.players.clear("players", &team, &client).unwrap());
+    assert_eq!(team.players.len("players", &team, &client).unwrap(), 0);
+    assert!(!clear!(team.players, client).unwrap());
+    assert!(ohmers::get::<Team>(team.id, &client).is_ok());
+}
+
+#[test]
+fn test_set_to_vec() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut p1 = Player::default();
+    p1.name = "Heidi".to_string();
+    p1.save(&client).unwrap();
+
+    let mut p2 = Player::default();
+    p2.name = "Ivan".to_string();
+    p2.save(&client).unwrap();
+
+    let mut team = Team::default();
+    team.name = "grault".to_string();
+    team.save(&client).unwrap();
+
+    insert!(team.players, &p1, &client).unwrap();
+    insert!(team.players, &p2, &client).unwrap();
+    let mut names = team.players.to_vec("players", &team, &client).unwrap()
+        .into_iter().map(|p| p.name).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["Heidi".to_string(), "Ivan".to_string()]);
+
+    let id = p2.id;
+    p2.delete(&client).unwrap();
+    assert_eq!(team.players.to_vec("players", &team, &client).map(|v| v.len()),
+        Err(ohmers::OhmerError::NotFound(id)));
+}
@@ -2,7 +2,7 @@
 extern crate redis;
 extern crate rustc_serialize;
 
-use ohmers::{all, all_query, Ohmer, Counter};
+use ohmers::{all, all_query, Ohmer, Counter, SortMode, SortOrder};
 use redis::Commands;
 use rustc_serialize::Encodable;
 
@@ -33,6 +33,31 @@ impl PartialEq for TvShow {
     }
 }
 
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+struct Podcast {
+    id: usize,
+    listens: Counter,
+}
+
+impl Default for Podcast {
+    fn default() -> Self {
+        Podcast {
+            id: 0,
+            listens: Counter,
+        }
+    }
+}
+impl Ohmer for Podcast {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+impl PartialEq for Podcast {
+    fn eq(&self, other: &Podcast) -> bool {
+        self.id == other.id
+    }
+}
+
 macro_rules! create {
     ($name: ident, $votes: expr, $conn: expr) => {{
         let mut t = TvShow::default();
@@ -98,4 +123,55 @@ fn test_sort() {
             shows[3].clone(),
             shows[4].clone(),
             ]);
+    assert_eq!(
+            &*all_query::<TvShow>(&client).unwrap().sort_by(
+                "name", SortOrder::Asc, SortMode::Alpha,
+                ).unwrap().collect::<Vec<_>>(),
+            &shows);
+    assert_eq!(
+            &*all_query::<TvShow>(&client).unwrap().sort_by_limit(
+                "votes", SortOrder::Desc, SortMode::Numeric, 1, 2,
+                ).unwrap().collect::<Vec<_>>(),
+            &[
+            shows[3].clone(),
+            shows[7].clone(),
+            ]);
+}
+
+#[test]
+fn test_sort_missing_counter() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Podcast:all").unwrap();
+
+    let mut podcasts = vec![];
+    for listens in &[5, 0, 2, -3] {
+        let mut p = Podcast::default();
+        p.save(&client).unwrap();
+        // The second one never gets a key.
+        if *listens != 0 {
+            incr!(p.listens, *listens, &client).unwrap();
+        }
+        podcasts.push(p);
+    }
+
+    assert_eq!(
+            all_query::<Podcast>(&client).unwrap().sort_by(
+                "listens", SortOrder::Asc, SortMode::Numeric,
+                ).unwrap().collect::<Vec<_>>(),
+            vec![
+            podcasts[3].clone(),
+            podcasts[1].clone(),
+            podcasts[2].clone(),
+            podcasts[0].clone(),
+            ]);
+    assert_eq!(
+            all_query::<Podcast>(&client).unwrap().sort_by(
+                "listens", SortOrder::Asc, SortMode::Alpha,
+                ).unwrap().collect::<Vec<_>>(),
+            vec![
+            podcasts[1].clone(),
+            podcasts[3].clone(),
+            podcasts[2].clone(),
+            podcasts[0].clone(),
+            ]);
 }
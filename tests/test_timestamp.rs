@@ -0,0 +1,87 @@
+#![cfg(feature = "chrono")]
+extern crate chrono;
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use ohmers::{all_query, get, Ohmer, Timestamp};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Launch {
+    id: usize,
+    name: String,
+    at: Timestamp,
+}
+
+impl Default for Launch {
+    fn default() -> Self {
+        Launch {
+            id: 0,
+            name: "".to_string(),
+            at: Timestamp::now(),
+        }
+    }
+}
+impl Ohmer for Launch {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+fn launch(name: &str, at: Timestamp, client: &redis::Client) -> Launch {
+    let mut launch = Launch::default();
+    launch.name = name.to_string();
+    launch.at = at;
+    launch.save(client).unwrap();
+    launch
+}
+
+fn date<Tz: TimeZone>(tz: &Tz, (y, mo, d): (i32, u32, u32), (h, mi, s, ms): (u32, u32, u32, u32)) -> DateTime<Tz> {
+    let local = NaiveDate::from_ymd_opt(y, mo, d).unwrap().and_hms_milli_opt(h, mi, s, ms).unwrap();
+    tz.from_local_datetime(&local).single().unwrap()
+}
+
+#[test]
+fn test_timestamp() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:bool = client.del("Launch:all").unwrap();
+
+    let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+    let houston = FixedOffset::west_opt(6 * 3600).unwrap();
+    let apollo = launch("Apollo 11", date(&houston, (1969, 7, 16), (8, 32, 0, 0)).into(), &client);
+    let hayabusa = launch("Hayabusa", date(&tokyo, (2003, 5, 9), (13, 29, 25, 999)).into(), &client);
+    let sputnik = launch("Sputnik", date(&Utc, (1957, 10, 4), (19, 28, 34, 1)).into(), &client);
+    let epoch = launch("Epoch", Timestamp::from_millis(-1), &client);
+    let now = launch("Now", Timestamp::now(), &client);
+
+    for l in &[&apollo, &hayabusa, &sputnik, &epoch, &now] {
+        assert_eq!(&get::<Launch>(l.id, &client).unwrap(), *l);
+        assert_eq!(get::<Launch>(l.id, &client).unwrap().at, l.at);
+    }
+
+    assert_eq!(*hayabusa.at, date(&tokyo, (2003, 5, 9), (13, 29, 25, 999)));
+    assert_eq!(hayabusa.at.millis(), 1052454565999);
+    assert_eq!(*epoch.at, date(&Utc, (1969, 12, 31), (23, 59, 59, 999)));
+    assert!(sputnik.at.millis() < 0);
+    let stored:String = client.hget(format!("Launch:{}", apollo.id), "at").unwrap();
+    assert_eq!(stored, format!("{}", apollo.at.millis()));
+
+    let names = all_query::<Launch>(&client).unwrap().sort("at", None, true, false).unwrap()
+        .map(|l| l.name).collect::<Vec<_>>();
+    assert_eq!(names, vec!["Sputnik", "Apollo 11", "Epoch", "Hayabusa", "Now"]);
+}
+
+#[test]
+fn test_timestamp_out_of_range() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    assert_eq!(Timestamp::try_from_millis(i64::max_value()), None);
+    assert_eq!(Timestamp::try_from_millis(-1), Some(Timestamp::from_millis(-1)));
+
+    let corrupt = launch("Corrupt", Timestamp::now(), &client);
+    let _:bool = client.hset(format!("Launch:{}", corrupt.id), "at", i64::max_value()).unwrap();
+    assert!(get::<Launch>(corrupt.id, &client).is_err());
+    corrupt.delete(&client).unwrap();
+}
@@ -5,7 +5,7 @@ extern crate rustc_serialize;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
-use ohmers::{Ohmer, OhmerError, with};
+use ohmers::{create_many, Ohmer, OhmerError, with};
 use redis::Commands;
 use rustc_serialize::Encodable;
 
@@ -46,3 +46,105 @@ fn test_unique() {
 
     assert!(with::<Thing, _>("name", "Window", &client).unwrap().is_none());
 }
+
+#[test]
+fn test_unique_violation_from_script_error() {
+    let e = redis::RedisError::from((redis::ErrorKind::ResponseError, "An error was signalled by the server",
+            "Error running script (call to f_0123456789abcdef): @user_script:82: UniqueIndexViolation: email_address".to_string()));
+    assert_eq!(OhmerError::from_script_error(e), OhmerError::UniqueIndexViolation("email_address".to_string()));
+
+    let e = redis::RedisError::from((redis::ErrorKind::ResponseError, "An error was signalled by the server",
+            "ERR unknown command".to_string()));
+    match OhmerError::from_script_error(e) {
+        OhmerError::RedisError(e) => assert_eq!(e.kind(), redis::ErrorKind::ResponseError),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Part {
+    id: usize,
+    name: String,
+}
+
+impl Default for Part {
+    fn default() -> Self {
+        Part {
+            id: 0,
+            name: "".to_string(),
+        }
+    }
+}
+impl Ohmer for Part {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn unique_fields<'a>(&self) -> HashSet<&'a str> { HashSet::from_iter(vec!["name"]) }
+}
+
+#[test]
+fn test_unique_create_many() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("Part:uniques:name").unwrap();
+    let mut window = Part { id: 0, name: "Window".to_string() };
+    window.save(&client).unwrap();
+
+    let mut parts = vec![
+        Part { id: 0, name: "Roof".to_string() },
+        Part { id: 0, name: "Wall".to_string() },
+        Part { id: 0, name: "Roof".to_string() },
+    ];
+    assert_eq!(create_many(&mut parts, &client), Err(OhmerError::UniqueIndexViolation("name".to_string())));
+    assert!(parts.iter().all(|t| t.id == 0));
+
+    parts.pop();
+    parts.push(Part { id: 0, name: "Window".to_string() });
+    assert_eq!(create_many(&mut parts, &client), Err(OhmerError::UniqueIndexViolation("name".to_string())));
+    let len:usize = client.hlen("Part:uniques:name").unwrap();
+    assert_eq!(len, 1);
+
+    parts.pop();
+    create_many(&mut parts, &client).unwrap();
+    assert_eq!(parts[1].id, parts[0].id + 1);
+    assert_eq!(with::<Part, _>("name", "Wall", &client).unwrap().unwrap(), parts[1]);
+    assert_eq!(create_many(&mut parts, &client), Err(OhmerError::IdInUse(parts[0].id)));
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Account {
+    id: usize,
+    email: String,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Account {
+            id: 0,
+            email: "".to_string(),
+        }
+    }
+}
+impl Ohmer for Account {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+    fn unique_fields<'a>(&self) -> HashSet<&'a str> { HashSet::from_iter(vec!["email"]) }
+}
+
+#[test]
+fn test_unique_change() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let _:() = client.del("Account:uniques:email").unwrap();
+    let mut account = Account { id: 0, email: "old@example.com".to_string() };
+    account.save(&client).unwrap();
+
+    account.email = "new@example.com".to_string();
+    account.save(&client).unwrap();
+    assert_eq!(with::<Account, _>("email", "old@example.com", &client).unwrap(), None);
+    assert_eq!(with::<Account, _>("email", "new@example.com", &client).unwrap().unwrap(), account);
+    let len:usize = client.hlen("Account:uniques:email").unwrap();
+    assert_eq!(len, 1);
+
+    // the old value is free to be taken by someone else
+    let mut other = Account { id: 0, email: "old@example.com".to_string() };
+    other.save(&client).unwrap();
+    assert_eq!(with::<Account, _>("email", "old@example.com", &client).unwrap().unwrap(), other);
+}
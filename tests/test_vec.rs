@@ -0,0 +1,99 @@
+extern crate ohmers;
+extern crate redis;
+extern crate rustc_serialize;
+
+use ohmers::{get, Ohmer};
+use redis::Commands;
+use rustc_serialize::Encodable;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Recipe {
+    id: usize,
+    name: String,
+    tags: Vec<String>,
+    steps: Vec<u32>,
+    ratings: Vec<Option<i8>>,
+}
+
+impl Default for Recipe {
+    fn default() -> Self {
+        Recipe {
+            id: 0,
+            name: "".to_string(),
+            tags: vec![],
+            steps: vec![],
+            ratings: vec![],
+        }
+    }
+}
+impl Ohmer for Recipe {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug, Default)]
+struct Grade {
+    id: usize,
+    grade: char,
+    code: [u8; 4],
+    marks: Vec<char>,
+}
+impl Ohmer for Grade {
+    fn id(&self) -> usize { self.id }
+    fn set_id(&mut self, id: usize) { self.id = id; }
+}
+
+#[test]
+fn test_vec() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut recipe = Recipe::default();
+    recipe.name = "crêpes".to_string();
+    recipe.tags = vec!["dessert".to_string(), "français".to_string(), "🥞".to_string(), "007".to_string(), "".to_string()];
+    recipe.steps = vec![3, 1, 4, 1, 5];
+    recipe.ratings = vec![Some(5), None, Some(-1)];
+    recipe.save(&client).unwrap();
+    assert_eq!(get::<Recipe>(recipe.id, &client).unwrap(), recipe);
+
+    let steps:String = client.hget(format!("Recipe:{}", recipe.id), "steps").unwrap();
+    assert_eq!(&*steps, "[3,1,4,1,5]");
+
+    let mut empty = Recipe::default();
+    empty.save(&client).unwrap();
+    let tags:String = client.hget(format!("Recipe:{}", empty.id), "tags").unwrap();
+    assert_eq!(&*tags, "[]");
+    assert_eq!(get::<Recipe>(empty.id, &client).unwrap(), empty);
+}
+
+#[test]
+fn test_vec_invalid() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut recipe = Recipe::default();
+    recipe.save(&client).unwrap();
+    let _:() = client.hset(format!("Recipe:{}", recipe.id), "steps", "[\"one\"]").unwrap();
+    assert!(get::<Recipe>(recipe.id, &client).is_err());
+    let _:() = client.hset(format!("Recipe:{}", recipe.id), "steps", "not json").unwrap();
+    assert!(get::<Recipe>(recipe.id, &client).is_err());
+}
+
+#[test]
+fn test_char_array() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+
+    let mut grade = Grade { id: 0, grade: 'Ä', code: [0, 7, 255, 16], marks: vec!['a', 'ß', '🎓'] };
+    grade.save(&client).unwrap();
+    assert_eq!(get::<Grade>(grade.id, &client).unwrap(), grade);
+
+    let key = format!("Grade:{}", grade.id);
+    let stored:String = client.hget(&*key, "grade").unwrap();
+    assert_eq!(&*stored, "Ä");
+    let code:String = client.hget(&*key, "code").unwrap();
+    assert_eq!(&*code, "[0,7,255,16]");
+
+    let _:() = client.hset(&*key, "code", "[0,7,255]").unwrap();
+    assert!(get::<Grade>(grade.id, &client).is_err());
+    let _:() = client.hset(&*key, "code", "[0,7,255,16]").unwrap();
+    let _:() = client.hset(&*key, "grade", "AB").unwrap();
+    assert!(get::<Grade>(grade.id, &client).is_err());
+}